@@ -0,0 +1,63 @@
+// cargo bench --bench pipeline, throughput of MTU sized packets through a pipeline against one core doing it all
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use shyvana::{
+    cipher::{Decrypted, Encryptor},
+    pipeline::Pipeline,
+};
+use std::thread::available_parallelism;
+
+const PACKET: usize = 0x0578;
+const PACKETS: usize = 0x0400;
+const CHUNK: usize = 0x08;
+
+fn buffers(count: usize) -> Vec<(Vec<u8>, usize)> {
+    (0x00..count)
+        .map(|_| (vec![0x45; 0x20 + PACKET.next_multiple_of(0x10)], PACKET))
+        .collect()
+}
+
+fn encrypt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encrypt");
+    group.throughput(Throughput::Bytes((PACKET * PACKETS) as u64));
+
+    group.bench_function("inline", |b| {
+        let mut encryptor = Encryptor::new(0x01, [0x07; 0x20]);
+        b.iter_batched(
+            || buffers(PACKETS),
+            |buffers| {
+                for (mut buffer, length) in buffers {
+                    let mut decrypted = Decrypted::new(&mut buffer).unwrap();
+                    decrypted.resize(length);
+                    encryptor.encrypt(decrypted).unwrap();
+                }
+            },
+            criterion::BatchSize::LargeInput,
+        )
+    });
+
+    let cores = available_parallelism().map_or(0x01, |n| n.get());
+    let mut workers = vec![0x01, 0x02, 0x04, 0x08, 0x10];
+    workers.retain(|&n| n <= cores.max(0x02));
+    for n in workers {
+        let pipeline = Pipeline::new(n);
+        let mut lane = pipeline.lane();
+        let mut encryptor = Encryptor::new(0x01, [0x07; 0x20]);
+        group.bench_with_input(BenchmarkId::new("pipeline", n), &n, |b, _| {
+            b.iter_batched(
+                || buffers(PACKETS),
+                |mut buffers| {
+                    while !buffers.is_empty() {
+                        let chunk = buffers.drain(0x00..CHUNK.min(buffers.len())).collect();
+                        lane.encrypt(&mut encryptor, chunk);
+                    }
+                    while lane.recv().is_some() {}
+                },
+                criterion::BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encrypt);
+criterion_main!(benches);
@@ -0,0 +1,107 @@
+#!/bin/sh
+# TCP throughput through two `shyvana tun` instances joined by a veth pair, each in a network namespace of its own
+# needs root, ip and python3: benches/veth.sh [seconds, 10 by default [crypto workers, one per core by default]]
+# prints the plain veth figure first for comparison
+set -eu
+
+SECONDS_=${1:-10}
+WORKERS=${2:-}
+BIN=$(pwd)/target/release/shyvana
+DIR=$(mktemp -d)
+
+# throwaway keys, good for nothing but this
+A_PRIVATE=MAGihn3kNT2JbFKKqR5pDhasuRiWXU2xTEhTQD/Hh10=
+A_PUBLIC=ok3ZulTXbFyFtEhDmTvdr37Jkn5LvJtu3PHMGRf7qSg=
+B_PRIVATE=YEq9u5QCTDmX1L/4hzkWkRA3FhPWc1z69T4dvkOj7Vk=
+B_PUBLIC=WiexIFk8b8ck5vPG68L4JSHEEWvOCoHkdZD3Kj3YWgw=
+
+cargo build --release --features proxy --bin shyvana
+
+cleanup() {
+    ip netns pids shyvana-a 2>/dev/null | xargs -r kill 2>/dev/null || true
+    ip netns pids shyvana-b 2>/dev/null | xargs -r kill 2>/dev/null || true
+    ip netns del shyvana-a 2>/dev/null || true
+    ip netns del shyvana-b 2>/dev/null || true
+    rm -rf "$DIR"
+}
+trap cleanup EXIT
+cleanup
+DIR=$(mktemp -d)
+
+ip netns add shyvana-a
+ip netns add shyvana-b
+ip link add shyvana-va type veth peer name shyvana-vb
+ip link set shyvana-va netns shyvana-a
+ip link set shyvana-vb netns shyvana-b
+ip -n shyvana-a addr add 10.9.0.1/24 dev shyvana-va
+ip -n shyvana-b addr add 10.9.0.2/24 dev shyvana-vb
+for ns in a b; do
+    ip -n shyvana-$ns link set lo up
+    ip -n shyvana-$ns link set shyvana-v$ns up
+done
+
+cat > "$DIR/a.conf" <<CONF
+[Interface]
+PrivateKey = $A_PRIVATE
+ListenPort = 51820
+[Peer]
+PublicKey = $B_PUBLIC
+Endpoint = 10.9.0.2:51820
+CONF
+cat > "$DIR/b.conf" <<CONF
+[Interface]
+PrivateKey = $B_PRIVATE
+ListenPort = 51820
+[Peer]
+PublicKey = $A_PUBLIC
+Endpoint = 10.9.0.1:51820
+CONF
+
+cat > "$DIR/sink.py" <<'PY'
+import socket, sys, time
+s = socket.socket()
+s.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
+s.bind((sys.argv[1], 5201))
+s.listen(1)
+c, _ = s.accept()
+n, start = 0, time.time()
+while True:
+    d = c.recv(1 << 20)
+    if not d:
+        break
+    n += len(d)
+print(f"{sys.argv[2]}: {n * 8 / (time.time() - start) / 1e6:.0f} Mbit/s")
+PY
+cat > "$DIR/source.py" <<'PY'
+import socket, sys, time
+s = socket.create_connection((sys.argv[1], 5201), timeout=5)
+buffer, end = bytes(1 << 16), time.time() + float(sys.argv[2])
+while time.time() < end:
+    s.sendall(buffer)
+s.close()
+PY
+
+measure() {
+    ip netns exec shyvana-b python3 "$DIR/sink.py" "$1" "$2" &
+    sink=$!
+    sleep 0.5
+    ip netns exec shyvana-a python3 "$DIR/source.py" "$1" "$SECONDS_"
+    wait $sink
+}
+
+measure 10.9.0.2 veth
+
+for ns in a b; do
+    ip netns exec shyvana-$ns "$BIN" tun "$DIR/$ns.conf" shyvana0 $WORKERS 2>"$DIR/$ns.log" &
+done
+sleep 0.5
+ip -n shyvana-a addr add 10.10.0.1/24 dev shyvana0
+ip -n shyvana-b addr add 10.10.0.2/24 dev shyvana0
+for ns in a b; do
+    ip -n shyvana-$ns link set shyvana0 up mtu 1420
+done
+# the first packet starts the handshake
+ip netns exec shyvana-a python3 -c "import socket; socket.socket(socket.AF_INET, socket.SOCK_DGRAM).sendto(b'', ('10.10.0.2', 9))"
+sleep 0.5
+
+measure 10.10.0.2 "shyvana tun, $(sed -n 's/.* with \([0-9]*\) crypto workers.*/\1/p' "$DIR/a.log") workers"
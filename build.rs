@@ -0,0 +1,14 @@
+fn main() {
+    // the C header goes to OUT_DIR, tests/ffi.rs checks include/shyvana.h is still the same
+    // a build never writes into the source tree, copy it over by hand after changing src/ffi.rs
+    #[cfg(feature = "ffi")]
+    {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let out = std::env::var("OUT_DIR").unwrap();
+        cbindgen::generate(&dir)
+            .expect("src/ffi.rs should be parseable by cbindgen")
+            .write_to_file(format!("{out}/shyvana.h"));
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+    }
+}
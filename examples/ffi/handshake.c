@@ -0,0 +1,119 @@
+/* two tunnels talking to each other in memory: a handshake, a packet each way and the stats */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "shyvana.h"
+
+#define CHECK(condition)                                                      \
+    do {                                                                      \
+        if (!(condition)) {                                                   \
+            fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #condition);  \
+            return 1;                                                         \
+        }                                                                     \
+    } while (0)
+
+/* RFC 7748 section 6.1 */
+static const uint8_t ALICE_PRIVATE[32] = {
+    0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2, 0x66, 0x45,
+    0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5, 0x1d, 0xb9, 0x2c, 0x2a,
+};
+static const uint8_t ALICE_PUBLIC[32] = {
+    0x85, 0x20, 0xf0, 0x09, 0x89, 0x30, 0xa7, 0x54, 0x74, 0x8b, 0x7d, 0xdc, 0xb4, 0x3e, 0xf7, 0x5a,
+    0x0d, 0xbf, 0x3a, 0x0d, 0x26, 0x38, 0x1a, 0xf4, 0xeb, 0xa4, 0xa9, 0x8e, 0xaa, 0x9b, 0x4e, 0x6a,
+};
+static const uint8_t BOB_PRIVATE[32] = {
+    0x5d, 0xab, 0x08, 0x7e, 0x62, 0x4a, 0x8a, 0x4b, 0x79, 0xe1, 0x7f, 0x8b, 0x83, 0x80, 0x0e, 0xe6,
+    0x6f, 0x3b, 0xb1, 0x29, 0x26, 0x18, 0xb6, 0xfd, 0x1c, 0x2f, 0x8b, 0x27, 0xff, 0x88, 0xe0, 0xeb,
+};
+static const uint8_t BOB_PUBLIC[32] = {
+    0xde, 0x9e, 0xdb, 0x7d, 0x7b, 0x7d, 0xc1, 0xb4, 0xd3, 0x5b, 0x61, 0xc2, 0xec, 0xe4, 0x35, 0x37,
+    0x3f, 0x83, 0x43, 0xc8, 0x5b, 0x78, 0x67, 0x4d, 0xad, 0xfc, 0x7e, 0x14, 0x6f, 0x88, 0x2b, 0x4f,
+};
+
+static void count(ShyvanaLogLevel level, const char *line, void *user_data) {
+    (void)level;
+    (void)line;
+    ++*(int *)user_data;
+}
+
+int main(void) {
+    ShyvanaEndpoint alice_at = {.family = 4, .address = {127, 0, 0, 1}, .port = 51820};
+    ShyvanaEndpoint bob_at = {.family = 4, .address = {127, 0, 0, 1}, .port = 51821};
+    uint8_t datagram[2048], sent[2048], packet[2048];
+    ShyvanaResult result;
+    ShyvanaStats stats;
+    int lines = 0;
+    /* any clock that keeps going forward will do, this one is fixed for the whole exchange */
+    uint64_t now = 1700000000000;
+
+    shyvana_set_log_callback(count, &lines);
+    CHECK(shyvana_tunnel_new(NULL, BOB_PUBLIC, NULL, NULL, 0) == NULL);
+    ShyvanaTunnel *alice = shyvana_tunnel_new(ALICE_PRIVATE, BOB_PUBLIC, NULL, &bob_at, 25);
+    ShyvanaTunnel *bob = shyvana_tunnel_new(BOB_PRIVATE, ALICE_PUBLIC, NULL, NULL, 0);
+    CHECK(alice != NULL && bob != NULL);
+
+    /* nothing to seal with yet, and bob has nowhere to send an initiation */
+    CHECK(shyvana_encapsulate(alice, now, (const uint8_t *)"", 0, datagram, sizeof datagram).op == SHYVANA_OP_ERROR);
+    CHECK(shyvana_tick(bob, now, datagram, sizeof datagram).op == SHYVANA_OP_IDLE);
+
+    /* initiation, response */
+    result = shyvana_tick(alice, now, datagram, sizeof datagram);
+    CHECK(result.op == SHYVANA_OP_WRITE_TO_NETWORK && result.size == 148);
+    CHECK(shyvana_tick(alice, now, datagram + result.size, sizeof datagram - result.size).op == SHYVANA_OP_IDLE);
+    result = shyvana_decapsulate(bob, now, &alice_at, datagram, result.size, datagram, sizeof datagram);
+    CHECK(result.op == SHYVANA_OP_WRITE_TO_NETWORK && result.size == 92);
+    result = shyvana_decapsulate(alice, now, &bob_at, datagram, result.size, packet, sizeof packet);
+    CHECK(result.op == SHYVANA_OP_IDLE);
+
+    /* bob learned where alice is from her initiation */
+    ShyvanaEndpoint endpoint;
+    CHECK(shyvana_endpoint(bob, &endpoint));
+    CHECK(endpoint.family == 4 && endpoint.port == 51820);
+
+    /* an IPv4 header and a little payload, alice to bob */
+    uint8_t ip[28] = {0x45, 0x00, 0x00, 28, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2};
+    memcpy(ip + 20, "shyvana!", 8);
+    CHECK(shyvana_encapsulate(alice, now, ip, sizeof ip, sent, 16).op == SHYVANA_OP_ERROR);
+    result = shyvana_encapsulate(alice, now, ip, sizeof ip, sent, sizeof sent);
+    CHECK(result.op == SHYVANA_OP_WRITE_TO_NETWORK);
+    size_t sent_size = result.size;
+    result = shyvana_decapsulate(bob, now, &alice_at, sent, sent_size, packet, sizeof packet);
+    CHECK(result.op == SHYVANA_OP_WRITE_TO_TUNNEL && result.size == sizeof ip);
+    CHECK(memcmp(packet, ip, sizeof ip) == 0);
+
+    /* garbage is turned away */
+    sent[sent_size - 1] ^= 0x01;
+    CHECK(shyvana_decapsulate(bob, now, &alice_at, sent, sent_size, packet, sizeof packet).op == SHYVANA_OP_ERROR);
+
+    /* and back, bob to alice */
+    result = shyvana_encapsulate(bob, now, ip, sizeof ip, datagram, sizeof datagram);
+    CHECK(result.op == SHYVANA_OP_WRITE_TO_NETWORK);
+    result = shyvana_decapsulate(alice, now, &bob_at, datagram, result.size, packet, sizeof packet);
+    CHECK(result.op == SHYVANA_OP_WRITE_TO_TUNNEL && memcmp(packet, ip, sizeof ip) == 0);
+
+    CHECK(shyvana_stats(alice, now, &stats));
+    CHECK(stats.handshakes_initiated == 1 && stats.handshakes_completed == 1);
+    CHECK(stats.tx_packets == 1 && stats.rx_packets == 1);
+    CHECK(stats.session == 1 && stats.last_handshake_ms == now);
+    CHECK(shyvana_stats(bob, now, &stats));
+    CHECK(stats.handshakes_initiated == 0 && stats.handshakes_completed == 1);
+    CHECK(stats.rx_packets == 1 && stats.tx_packets == 1);
+
+    /* a null tunnel fails instead of crashing */
+    CHECK(shyvana_encapsulate(NULL, now, ip, sizeof ip, sent, sizeof sent).op == SHYVANA_OP_ERROR);
+    CHECK(shyvana_decapsulate(NULL, now, &alice_at, sent, sent_size, packet, sizeof packet).op == SHYVANA_OP_ERROR);
+    CHECK(shyvana_tick(NULL, now, datagram, sizeof datagram).op == SHYVANA_OP_ERROR);
+    CHECK(!shyvana_endpoint(NULL, &endpoint));
+    CHECK(!shyvana_stats(NULL, now, &stats));
+
+    /* every failure above was logged */
+    CHECK(lines > 0);
+    shyvana_set_log_callback(NULL, NULL);
+
+    shyvana_tunnel_free(alice);
+    shyvana_tunnel_free(bob);
+    shyvana_tunnel_free(NULL);
+    printf("ok, %d log lines\n", lines);
+    return 0;
+}
@@ -0,0 +1,103 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use core::time::Duration;
+use libfuzzer_sys::fuzz_target;
+use shyvana::{
+    device::{Device, LoadMonitor},
+    driver::{Action, Driver},
+    packet::HandshakeInit,
+    ratelimiter::RateLimiter,
+    tunnel::Tunnel,
+};
+use shyvana_fuzz::{apply, remac_init, Flip, SeedRng};
+use std::{net::SocketAddr, sync::Arc};
+use x25519::{PublicKey, StaticSecret};
+
+#[derive(Arbitrary, Debug)]
+enum Step {
+    // arbitrary bytes straight into the local driver
+    Raw(Vec<u8>),
+    // whatever the remote driver sends next, tampered with, and re-MACed if it is an initiation
+    Remote { flips: Vec<Flip> },
+    // transport data from the remote side's current session, tampered with
+    Data { payload: Vec<u8>, flips: Vec<Flip> },
+    // time passing on both sides
+    Wait { secs: u16 },
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    l_s: [u8; 0x20],
+    r_s: [u8; 0x20],
+    seed: [u8; 0x20],
+    steps: Vec<Step>,
+}
+
+fn driver(secret: StaticSecret, peer: PublicKey, endpoint: Option<SocketAddr>) -> Driver {
+    let device = Arc::new(Device::new(
+        secret,
+        LoadMonitor::default(),
+        RateLimiter::default(),
+    ));
+    Driver::new(
+        Arc::new(Tunnel::new(device, peer, [0x00; 0x20])),
+        endpoint,
+        Some(Duration::from_secs(0x19)),
+    )
+}
+
+fuzz_target!(|input: Input| {
+    let l_s = StaticSecret::from(input.l_s);
+    let r_s = StaticSecret::from(input.r_s);
+    let l_p = PublicKey::from(&l_s);
+    let r_p = PublicKey::from(&r_s);
+    let src: SocketAddr = ([0x0a, 0x00, 0x00, 0x02], 0xca6c).into();
+    // the local side only answers, the remote side starts handshakes
+    let local = driver(l_s, r_p, None);
+    let remote = driver(r_s, l_p, Some(([0x0a, 0x00, 0x00, 0x01], 0xca6c).into()));
+    let mut rng = SeedRng(input.seed);
+    let mut now = Duration::from_secs(0x6553f100);
+    let mut out = vec![0x00; 0x10000];
+    let mut back = vec![0x00; 0x10000];
+    for step in input.steps {
+        match step {
+            Step::Raw(mut data) => {
+                let _ = local.decapsulate(&mut data, src, now, &mut rng, &mut out);
+            }
+            Step::Remote { flips } => {
+                let Ok(Action::Network(length)) = remote.tick(now, &mut rng, &mut out) else {
+                    continue;
+                };
+                let mut datagram = out[0x00..length].to_vec();
+                apply(&flips, &mut datagram);
+                if let Ok(msg) = HandshakeInit::wrap_mut(&mut datagram) {
+                    if msg.m_t == 0x01 {
+                        remac_init(msg, &l_p, None);
+                    }
+                }
+                // answers go back so the remote side gets a session
+                if let Ok(Action::Network(length)) =
+                    local.decapsulate(&mut datagram, src, now, &mut rng, &mut out)
+                {
+                    let _ =
+                        remote.decapsulate(&mut out[0x00..length], src, now, &mut rng, &mut back);
+                }
+            }
+            Step::Data { payload, flips } => {
+                let Ok(Action::Network(length)) =
+                    remote.encapsulate(&payload, now, &mut rng, &mut out)
+                else {
+                    continue;
+                };
+                let mut datagram = out[0x00..length].to_vec();
+                apply(&flips, &mut datagram);
+                let _ = local.decapsulate(&mut datagram, src, now, &mut rng, &mut back);
+            }
+            Step::Wait { secs } => {
+                now += Duration::from_secs(secs as u64);
+                while let Ok(Action::Network(_)) = local.tick(now, &mut rng, &mut out) {}
+            }
+        }
+    }
+});
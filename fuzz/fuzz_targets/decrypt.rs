@@ -0,0 +1,56 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use shyvana::cipher::{Decrypted, Decryptor, Encryptor};
+use shyvana_fuzz::{apply, Flip};
+
+#[derive(Arbitrary, Debug)]
+enum Input {
+    // arbitrary bytes, mostly rejected by the length checks or the tag
+    Raw {
+        key: [u8; 0x20],
+        data: Vec<u8>,
+    },
+    // a genuine transport message, optionally tampered with
+    Sealed {
+        r_i: u32,
+        key: [u8; 0x20],
+        cnt: u64,
+        payload: Vec<u8>,
+        flips: Vec<Flip>,
+    },
+}
+
+fuzz_target!(|input: Input| {
+    match input {
+        Input::Raw { key, mut data } => {
+            let _ = Decryptor::new(0x00, key).decrypt(&mut data);
+        }
+        Input::Sealed {
+            r_i,
+            key,
+            cnt,
+            payload,
+            flips,
+        } => {
+            let mut encryptor = Encryptor::new(r_i, key);
+            encryptor.reserve(cnt);
+            let mut buffer = vec![0x00; payload.len().next_multiple_of(0x10) + 0x20];
+            let mut decrypted = Decrypted::new(&mut buffer).unwrap();
+            decrypted.resize(payload.len());
+            decrypted.copy_from_slice(&payload);
+            let mut encrypted = match encryptor.encrypt(decrypted) {
+                Ok(encrypted) => encrypted,
+                // counter exhausted
+                Err(_) => return,
+            };
+            apply(&flips, &mut encrypted);
+            let result = Decryptor::new(r_i, key).decrypt(&mut encrypted);
+            if flips.iter().all(|flip| flip.xor == 0x00) {
+                assert!(result.is_ok());
+                assert_eq!(&encrypted[0x10..0x10 + payload.len()], &payload[..]);
+            }
+        }
+    }
+});
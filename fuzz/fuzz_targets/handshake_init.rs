@@ -0,0 +1,72 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use core::{mem::size_of, time::Duration};
+use libfuzzer_sys::fuzz_target;
+use shyvana::{
+    handshake::{Initiator, PeerKeys, Responder},
+    packet::HandshakeInit,
+};
+use shyvana_fuzz::{apply, ephemeral, remac_init, Flip};
+use x25519::{PublicKey, StaticSecret};
+
+#[derive(Arbitrary, Debug)]
+enum Input {
+    // arbitrary bytes, mostly rejected early
+    Raw {
+        r_s: [u8; 0x20],
+        i_s: [u8; 0x20],
+        data: Vec<u8>,
+    },
+    // a genuine initiation, tampered with and then given valid macs
+    Sealed {
+        i_s: [u8; 0x20],
+        r_s: [u8; 0x20],
+        e_s: [u8; 0x20],
+        i_i: u32,
+        secs: u64,
+        nanos: u32,
+        l_c: Option<[u8; 0x10]>,
+        flips: Vec<Flip>,
+    },
+}
+
+fuzz_target!(|input: Input| {
+    match input {
+        Input::Raw { r_s, i_s, data } => {
+            let r_s = StaticSecret::from(r_s);
+            let r_p = PublicKey::from(&r_s);
+            let i_k = PeerKeys::new(&r_s, &PublicKey::from(&StaticSecret::from(i_s)));
+            if let Ok(msg) = HandshakeInit::wrap_ref(&data) {
+                let _ = Responder::recv_handshake_init(&r_s, &r_p, &i_k, msg);
+            }
+        }
+        Input::Sealed {
+            i_s,
+            r_s,
+            e_s,
+            i_i,
+            secs,
+            nanos,
+            l_c,
+            flips,
+        } => {
+            let i_s = StaticSecret::from(i_s);
+            let i_p = PublicKey::from(&i_s);
+            let r_s = StaticSecret::from(r_s);
+            let r_p = PublicKey::from(&r_s);
+            let r_k = PeerKeys::new(&i_s, &r_p);
+            let i_k = PeerKeys::new(&r_s, &i_p);
+            let now = Duration::new(secs, nanos % 1_000_000_000);
+            let mut buffer = [0x00; size_of::<HandshakeInit>()];
+            let msg = HandshakeInit::wrap_mut(&mut buffer).unwrap();
+            Initiator::send_handshake_init(i_i, &i_p, &r_k, ephemeral(e_s), now, l_c, msg).unwrap();
+            apply(&flips, &mut msg[0x00..0x74]);
+            remac_init(msg, &r_p, l_c);
+            let result = Responder::recv_handshake_init(&r_s, &r_p, &i_k, msg);
+            if flips.iter().all(|flip| flip.xor == 0x00) {
+                assert!(result.is_ok());
+            }
+        }
+    }
+});
@@ -0,0 +1,78 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use core::{mem::size_of, time::Duration};
+use libfuzzer_sys::fuzz_target;
+use shyvana::{
+    handshake::{Initiator, PeerKeys, Responder},
+    packet::{HandshakeInit, HandshakeResp},
+};
+use shyvana_fuzz::{apply, ephemeral, remac_resp, Flip};
+use x25519::{PublicKey, StaticSecret};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    i_s: [u8; 0x20],
+    r_s: [u8; 0x20],
+    i_e: [u8; 0x20],
+    r_e: [u8; 0x20],
+    i_i: u32,
+    r_i: u32,
+    p_k: Option<[u8; 0x20]>,
+    l_c: Option<[u8; 0x10]>,
+    // replaces the response wholesale instead of tampering with a genuine one
+    raw: Option<Vec<u8>>,
+    flips: Vec<Flip>,
+}
+
+fuzz_target!(|input: Input| {
+    let i_s = StaticSecret::from(input.i_s);
+    let i_p = PublicKey::from(&i_s);
+    let r_s = StaticSecret::from(input.r_s);
+    let r_p = PublicKey::from(&r_s);
+    let r_k = PeerKeys::new(&i_s, &r_p);
+    let i_k = PeerKeys::new(&r_s, &i_p);
+
+    let mut init = [0x00; size_of::<HandshakeInit>()];
+    let init = HandshakeInit::wrap_mut(&mut init).unwrap();
+    let initiator = Initiator::send_handshake_init(
+        input.i_i,
+        &i_p,
+        &r_k,
+        ephemeral(input.i_e),
+        Duration::ZERO,
+        None,
+        init,
+    )
+    .unwrap();
+    let responder = Responder::recv_handshake_init(&r_s, &r_p, &i_k, init).unwrap();
+
+    if let Some(data) = input.raw {
+        if let Ok(msg) = HandshakeResp::wrap_ref(&data) {
+            let _ = initiator.recv_handshake_resp(&i_s, input.p_k, msg);
+        }
+        return;
+    }
+
+    let mut resp = [0x00; size_of::<HandshakeResp>()];
+    let resp = HandshakeResp::wrap_mut(&mut resp).unwrap();
+    let (r_s_k, r_r_k) = responder
+        .send_handshake_resp(
+            input.i_i,
+            &i_k,
+            input.r_i,
+            ephemeral(input.r_e),
+            input.p_k,
+            input.l_c,
+            resp,
+        )
+        .unwrap();
+    apply(&input.flips, &mut resp[0x00..0x3c]);
+    remac_resp(resp, &i_p, input.l_c);
+    let result = initiator.recv_handshake_resp(&i_s, input.p_k, resp);
+    if input.flips.iter().all(|flip| flip.xor == 0x00) {
+        let (i_s_k, i_r_k) = result.unwrap();
+        assert_eq!(i_s_k, r_r_k);
+        assert_eq!(i_r_k, r_s_k);
+    }
+});
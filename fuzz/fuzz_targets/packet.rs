@@ -0,0 +1,59 @@
+#![no_main]
+
+use core::mem::size_of;
+use libfuzzer_sys::fuzz_target;
+use shyvana::packet::{CookieReply, HandshakeInit, HandshakeResp, TransportData};
+
+fuzz_target!(|data: &[u8]| {
+    let mut buffer = data.to_vec();
+    if let Ok(msg) = HandshakeInit::wrap_ref(data) {
+        assert_eq!(msg.len(), size_of::<HandshakeInit>());
+        let _ = (
+            msg.m_t,
+            msg.r_0,
+            msg.s_i.get(),
+            msg.u_e,
+            msg.e_s,
+            msg.e_t,
+            msg.m_1,
+            msg.m_2,
+        );
+    }
+    if let Ok(msg) = HandshakeInit::wrap_mut(&mut buffer) {
+        msg.m_t = 0x01;
+        assert_eq!(msg[0x00], 0x01);
+    }
+    if let Ok(msg) = HandshakeResp::wrap_ref(data) {
+        assert_eq!(msg.len(), size_of::<HandshakeResp>());
+        let _ = (
+            msg.m_t,
+            msg.r_0,
+            msg.s_i.get(),
+            msg.r_i.get(),
+            msg.u_e,
+            msg.e_n,
+            msg.m_1,
+            msg.m_2,
+        );
+    }
+    if let Ok(msg) = HandshakeResp::wrap_mut(&mut buffer) {
+        msg.m_t = 0x02;
+        assert_eq!(msg[0x00], 0x02);
+    }
+    if let Ok(msg) = CookieReply::wrap_ref(data) {
+        assert_eq!(msg.len(), size_of::<CookieReply>());
+        let _ = (msg.m_t, msg.r_0, msg.r_i.get(), msg.n_c, msg.e_c);
+    }
+    if let Ok(msg) = CookieReply::wrap_mut(&mut buffer) {
+        msg.m_t = 0x03;
+        assert_eq!(msg[0x00], 0x03);
+    }
+    if let Ok(msg) = TransportData::wrap_ref(data) {
+        assert_eq!(msg.len(), size_of::<TransportData>());
+        let _ = (msg.m_t, msg.r_0, msg.r_i.get(), msg.cnt.get());
+    }
+    if let Ok(msg) = TransportData::wrap_mut(&mut buffer) {
+        msg.m_t = 0x04;
+        assert_eq!(msg[0x00], 0x04);
+    }
+});
@@ -0,0 +1,70 @@
+use arbitrary::Arbitrary;
+use rand_core::{CryptoRng, RngCore};
+use shyvana::{
+    crypto::{hash, mac},
+    packet::{HandshakeInit, HandshakeResp},
+};
+use x25519::{PublicKey, ReusableSecret};
+
+// hands out the same seed over and over so that ephemeral secrets are reproducible from the input
+pub struct SeedRng(pub [u8; 0x20]);
+
+impl RngCore for SeedRng {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(0x20) {
+            chunk.copy_from_slice(&self.0[0x00..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for SeedRng {}
+
+pub fn ephemeral(seed: [u8; 0x20]) -> ReusableSecret {
+    ReusableSecret::random_from_rng(SeedRng(seed))
+}
+
+#[derive(Arbitrary, Debug)]
+pub struct Flip {
+    pub at: u16,
+    pub xor: u8,
+}
+
+pub fn apply(flips: &[Flip], buffer: &mut [u8]) {
+    if buffer.is_empty() {
+        return;
+    }
+    for flip in flips {
+        buffer[flip.at as usize % buffer.len()] ^= flip.xor;
+    }
+}
+
+// recomputes mac1 and mac2 after the body has been tampered with
+pub fn remac_init(msg: &mut HandshakeInit, r_p: &PublicKey, l_c: Option<[u8; 0x10]>) {
+    msg.m_1 = mac(hash("mac1----", r_p), &msg[0x00..0x74]);
+    msg.m_2 = match l_c {
+        Some(latest_cookie) => mac(latest_cookie, &msg[0x00..0x84]),
+        None => [0x00; 0x10],
+    };
+}
+
+// recomputes mac1 and mac2 after the body has been tampered with
+pub fn remac_resp(msg: &mut HandshakeResp, i_p: &PublicKey, l_c: Option<[u8; 0x10]>) {
+    msg.m_1 = mac(hash("mac1----", i_p), &msg[0x00..0x3c]);
+    msg.m_2 = match l_c {
+        Some(latest_cookie) => mac(latest_cookie, &msg[0x00..0x4c]),
+        None => [0x00; 0x10],
+    };
+}
@@ -0,0 +1,195 @@
+#ifndef SHYVANA_H
+#define SHYVANA_H
+
+/* generated by cbindgen from src/ffi.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * What a call left in the destination buffer.
+ */
+typedef enum ShyvanaOp {
+  /**
+   * Nothing to do.
+   */
+  SHYVANA_OP_IDLE,
+  /**
+   * Send the first `size` bytes to the peer.
+   */
+  SHYVANA_OP_WRITE_TO_NETWORK,
+  /**
+   * The first `size` bytes are a decrypted IP packet for the local stack.
+   */
+  SHYVANA_OP_WRITE_TO_TUNNEL,
+  /**
+   * The call failed, the reason went to the log callback.
+   */
+  SHYVANA_OP_ERROR,
+} ShyvanaOp;
+
+typedef enum ShyvanaLogLevel {
+  SHYVANA_LOG_LEVEL_ERROR,
+  SHYVANA_LOG_LEVEL_WARN,
+  SHYVANA_LOG_LEVEL_INFO,
+  SHYVANA_LOG_LEVEL_DEBUG,
+  SHYVANA_LOG_LEVEL_TRACE,
+} ShyvanaLogLevel;
+
+/**
+ * A tunnel to one peer, created with `shyvana_tunnel_new` and freed with `shyvana_tunnel_free`.
+ *
+ * Calls that need the time take `now_ms`, milliseconds since the UNIX epoch on the caller's
+ * clock. It goes into handshake timestamps, so it has to keep going forward across restarts.
+ */
+typedef struct ShyvanaTunnel ShyvanaTunnel;
+
+/**
+ * An IPv4 or IPv6 address and port, IPv4 addresses take up the first four bytes.
+ */
+typedef struct ShyvanaEndpoint {
+  /**
+   * 4 or 6.
+   */
+  uint8_t family;
+  uint8_t address[16];
+  uint16_t port;
+} ShyvanaEndpoint;
+
+typedef struct ShyvanaResult {
+  enum ShyvanaOp op;
+  size_t size;
+} ShyvanaResult;
+
+typedef struct ShyvanaStats {
+  uint64_t rx_bytes;
+  uint64_t rx_packets;
+  uint64_t tx_bytes;
+  uint64_t tx_packets;
+  uint64_t handshakes_initiated;
+  uint64_t handshakes_completed;
+  uint64_t replay_drops;
+  /**
+   * Milliseconds since the UNIX epoch of the last completed handshake, 0 if there was none.
+   */
+  uint64_t last_handshake_ms;
+  /**
+   * 1 if there is a session and 0 if not, and how old it is.
+   */
+  uint8_t session;
+  uint64_t session_age_ms;
+} ShyvanaStats;
+
+/**
+ * Gets one log line at a time, the line is only valid for the duration of the call.
+ */
+typedef void (*ShyvanaLogCallback)(enum ShyvanaLogLevel level, const char *line, void *user_data);
+
+/**
+ * Creates a tunnel to one peer, or returns null if a key is missing.
+ *
+ * Without an endpoint the tunnel only answers handshakes, it starts its own once the peer has
+ * been heard from. A keepalive of 0 turns persistent keepalives off.
+ *
+ * # Safety
+ *
+ * `private_key` and `peer_public` must point to 32 bytes each, `preshared_key` to 32 bytes or be
+ * null, and `endpoint` to an endpoint or be null.
+ */
+struct ShyvanaTunnel *shyvana_tunnel_new(const uint8_t *private_key,
+                                         const uint8_t *peer_public,
+                                         const uint8_t *preshared_key,
+                                         const struct ShyvanaEndpoint *endpoint,
+                                         uint16_t keepalive);
+
+/**
+ * Frees a tunnel, null is ignored.
+ *
+ * # Safety
+ *
+ * `tunnel` must come from `shyvana_tunnel_new` and not be used again.
+ */
+void shyvana_tunnel_free(struct ShyvanaTunnel *tunnel);
+
+/**
+ * Seals an IP packet for the peer. Fails until `shyvana_tick` has set up a session.
+ *
+ * # Safety
+ *
+ * `tunnel` must be null or live, from `shyvana_tunnel_new` and not freed yet. `src` must be
+ * readable for `src_size` bytes and `dst` writable for `dst_size` bytes.
+ */
+struct ShyvanaResult shyvana_encapsulate(const struct ShyvanaTunnel *tunnel,
+                                         uint64_t now_ms,
+                                         const uint8_t *src,
+                                         size_t src_size,
+                                         uint8_t *dst,
+                                         size_t dst_size);
+
+/**
+ * Handles one datagram from the peer, `from` is where it came from or null if unknown.
+ *
+ * # Safety
+ *
+ * `tunnel` must be null or live, from `shyvana_tunnel_new` and not freed yet. `src` must be
+ * readable for `src_size` bytes, `dst` writable for `dst_size` bytes and `from` point to an
+ * endpoint or be null.
+ */
+struct ShyvanaResult shyvana_decapsulate(const struct ShyvanaTunnel *tunnel,
+                                         uint64_t now_ms,
+                                         const struct ShyvanaEndpoint *from,
+                                         const uint8_t *src,
+                                         size_t src_size,
+                                         uint8_t *dst,
+                                         size_t dst_size);
+
+/**
+ * Starts and retries handshakes and sends keepalives. Call it every 100ms or so, and again
+ * straight away for as long as it asks for a network write.
+ *
+ * # Safety
+ *
+ * `tunnel` must be null or live, from `shyvana_tunnel_new` and not freed yet. `dst` must be
+ * writable for `dst_size` bytes.
+ */
+struct ShyvanaResult shyvana_tick(const struct ShyvanaTunnel *tunnel,
+                                  uint64_t now_ms,
+                                  uint8_t *dst,
+                                  size_t dst_size);
+
+/**
+ * Where network writes go, false if the peer has no endpoint yet or a pointer is null.
+ *
+ * # Safety
+ *
+ * `tunnel` must be null or live, from `shyvana_tunnel_new` and not freed yet. `endpoint` must be
+ * null or writable.
+ */
+bool shyvana_endpoint(const struct ShyvanaTunnel *tunnel, struct ShyvanaEndpoint *endpoint);
+
+/**
+ * Counters for the peer, false if a pointer is null and nothing was written.
+ *
+ * # Safety
+ *
+ * `tunnel` must be null or live, from `shyvana_tunnel_new` and not freed yet. `stats` must be
+ * null or writable.
+ */
+bool shyvana_stats(const struct ShyvanaTunnel *tunnel, uint64_t now_ms, struct ShyvanaStats *stats);
+
+/**
+ * Sends log lines to `callback` from now on, null turns logging off. Lines only ever carry
+ * indices, endpoints, key fingerprints and reasons, never key material.
+ *
+ * Returns false if the process already has a tracing subscriber and the lines go there instead.
+ *
+ * # Safety
+ *
+ * `callback` must stay callable with `user_data`, from any thread, until it is replaced.
+ */
+bool shyvana_set_log_callback(ShyvanaLogCallback callback, void *user_data);
+
+#endif  /* SHYVANA_H */
@@ -0,0 +1,159 @@
+import pytest
+
+import shyvana
+
+# an IPv4 header and eight bytes of payload, so the tunnel can tell the packet from its padding
+PACKET = bytes([0x45, 0x00, 0x00, 28, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]) + b"shyvana!"
+
+
+@pytest.fixture
+def keys():
+    alice_private, alice_public = shyvana.generate_keypair()
+    bob_private, bob_public = shyvana.generate_keypair()
+    return alice_private, alice_public, bob_private, bob_public
+
+
+def handshake(keys, preshared_key=None):
+    alice_private, alice_public, bob_private, bob_public = keys
+    alice_peer = shyvana.PeerKeys(alice_private, bob_public)
+    bob_peer = shyvana.PeerKeys(bob_private, alice_public)
+    initiator, init = shyvana.Initiator.send_handshake_init(7, alice_public, alice_peer)
+    responder = shyvana.Responder.recv_handshake_init(bob_private, bob_peer, init)
+    resp, bob_send, bob_recv = responder.send_handshake_resp(
+        init.sender_index, bob_peer, 9, preshared_key=preshared_key
+    )
+    alice_send, alice_recv = initiator.recv_handshake_resp(alice_private, resp, preshared_key)
+    return (alice_send, alice_recv), (bob_send, bob_recv)
+
+
+def test_public_key(keys):
+    alice_private, alice_public, _, _ = keys
+    assert shyvana.public_key(alice_private) == alice_public
+    assert len(alice_public) == 32
+
+
+def test_handshake_keys_match(keys):
+    (alice_send, alice_recv), (bob_send, bob_recv) = handshake(keys)
+    assert alice_send == bob_recv
+    assert alice_recv == bob_send
+    assert alice_send != alice_recv
+
+
+def test_preshared_key_mismatch(keys):
+    alice_private, alice_public, bob_private, bob_public = keys
+    alice_peer = shyvana.PeerKeys(alice_private, bob_public)
+    bob_peer = shyvana.PeerKeys(bob_private, alice_public)
+    initiator, init = shyvana.Initiator.send_handshake_init(7, alice_public, alice_peer)
+    responder = shyvana.Responder.recv_handshake_init(bob_private, bob_peer, init)
+    resp, _, _ = responder.send_handshake_resp(7, bob_peer, 9, preshared_key=b"\x01" * 32)
+    with pytest.raises(shyvana.MaliciousError) as error:
+        initiator.recv_handshake_resp(alice_private, resp, b"\x02" * 32)
+    assert error.value.reason == "aead_error"
+
+
+def test_pinned_ephemeral(keys):
+    alice_private, alice_public, _, bob_public = keys
+    ephemeral, ephemeral_public = shyvana.generate_keypair()
+    peer = shyvana.PeerKeys(alice_private, bob_public)
+    _, init = shyvana.Initiator.send_handshake_init(
+        1, alice_public, peer, ephemeral=ephemeral, now=1_700_000_000_000_000_000
+    )
+    assert init.unencrypted_ephemeral == ephemeral_public
+
+
+def test_tampered_initiation(keys):
+    alice_private, alice_public, bob_private, bob_public = keys
+    _, init = shyvana.Initiator.send_handshake_init(
+        1, alice_public, shyvana.PeerKeys(alice_private, bob_public)
+    )
+    static = bytearray(init.encrypted_static)
+    static[0] ^= 0x01
+    init.encrypted_static = bytes(static)
+    with pytest.raises(shyvana.WireGuardError) as error:
+        shyvana.Responder.recv_handshake_init(
+            bob_private, shyvana.PeerKeys(bob_private, alice_public), init
+        )
+    assert isinstance(error.value, shyvana.MaliciousError)
+    assert error.value.reason == "aead_error"
+
+
+def test_state_is_single_use(keys):
+    alice_private, alice_public, bob_private, bob_public = keys
+    alice_peer = shyvana.PeerKeys(alice_private, bob_public)
+    bob_peer = shyvana.PeerKeys(bob_private, alice_public)
+    initiator, init = shyvana.Initiator.send_handshake_init(7, alice_public, alice_peer)
+    responder = shyvana.Responder.recv_handshake_init(bob_private, bob_peer, init)
+    resp, _, _ = responder.send_handshake_resp(7, bob_peer, 9)
+    initiator.recv_handshake_resp(alice_private, resp)
+    with pytest.raises(RuntimeError):
+        initiator.recv_handshake_resp(alice_private, resp)
+    with pytest.raises(RuntimeError):
+        responder.send_handshake_resp(7, bob_peer, 9)
+
+
+def test_ciphers(keys):
+    (alice_send, _), (_, bob_recv) = handshake(keys)
+    encryptor = shyvana.Encryptor(9, alice_send)
+    decryptor = shyvana.Decryptor(9, bob_recv)
+    message = encryptor.encrypt(b"hello")
+    assert encryptor.counter == 1
+    data = shyvana.TransportData(message)
+    assert data.message_type == 4
+    assert data.receiver_index == 9
+    assert data.counter == 0
+    assert len(message) == 16 + 16 + 16
+    # padded to a multiple of 16
+    assert decryptor.decrypt(message) == b"hello" + bytes(11)
+    with pytest.raises(shyvana.MaliciousError):
+        shyvana.Decryptor(9, bytes(32)).decrypt(message)
+
+
+def test_packet_fields():
+    init = shyvana.HandshakeInit()
+    assert init.message_type == 1
+    assert len(init) == 148
+    init.sender_index = 0xDEADBEEF
+    init.mac2 = b"\xff" * 16
+    raw = bytes(init)
+    assert raw[4:8] == bytes([0xEF, 0xBE, 0xAD, 0xDE])
+    assert raw[-16:] == b"\xff" * 16
+    copy = shyvana.HandshakeInit(raw)
+    assert copy.sender_index == 0xDEADBEEF
+    with pytest.raises(ValueError):
+        init.mac1 = b"short"
+    with pytest.raises(shyvana.BenignDropError) as error:
+        shyvana.HandshakeResp(raw[:10])
+    assert error.value.reason == "buffer_length_too_short"
+    assert shyvana.CookieReply().message_type == 3
+    assert len(shyvana.CookieReply()) == 64
+
+
+def test_tunnel(keys):
+    alice_private, alice_public, bob_private, bob_public = keys
+    alice = shyvana.Tunnel(alice_private, bob_public)
+    bob = shyvana.Tunnel(bob_private, alice_public)
+    assert alice.public_key == alice_public
+    assert alice.peer_public == bob_public
+    with pytest.raises(shyvana.NeedsRekeyError) as error:
+        alice.encrypt(PACKET)
+    assert error.value.reason == "session_expired"
+
+    init = alice.send_handshake_init()
+    resp = bob.recv_handshake_init(init)
+    alice.recv_handshake_resp(resp)
+
+    assert bob.decrypt(alice.encrypt(PACKET)) == PACKET
+    assert alice.decrypt(bob.encrypt(PACKET)) == PACKET
+    # keepalive
+    assert bob.decrypt(alice.encrypt(b"")) == b""
+
+
+def test_tunnel_rejects_unknown_response(keys):
+    alice_private, alice_public, bob_private, bob_public = keys
+    alice = shyvana.Tunnel(alice_private, bob_public)
+    bob = shyvana.Tunnel(bob_private, alice_public)
+    resp = bob.recv_handshake_init(alice.send_handshake_init())
+    resp.receiver_index = resp.receiver_index ^ 1
+    with pytest.raises(shyvana.WireGuardError) as error:
+        alice.recv_handshake_resp(resp)
+    assert error.value.reason == "unknown_receiver"
@@ -0,0 +1,274 @@
+use std::{
+    fs::{rename, File},
+    io::{self, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::checksum::{fold, sum};
+
+// pcapng block types
+const SECTION_HEADER: u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const ENHANCED_PACKET: u32 = 0x00000006;
+
+// pcapng option codes
+const OPT_ENDOFOPT: u16 = 0x0000;
+const IF_NAME: u16 = 0x0002;
+const IF_TSRESOL: u16 = 0x0009;
+
+// LINKTYPE_RAW, bare IPv4 or IPv6 packets
+const LINKTYPE_RAW: u16 = 0x0065;
+
+// interface ids, in the order they are described in every file
+const OUTER: u32 = 0x00;
+const INNER: u32 = 0x01;
+
+struct State {
+    out: BufWriter<File>,
+    written: u64, // bytes in the current file
+}
+
+// writes encrypted datagrams and decrypted packets to pcapng files that rotate by size
+pub struct Capture {
+    path: PathBuf,
+    limit: u64,  // bytes a file may grow to before it is rotated
+    keep: usize, // rotated files kept as path.1, path.2, ...
+    state: Mutex<State>,
+}
+
+impl Capture {
+    pub fn create(path: impl Into<PathBuf>, limit: u64, keep: usize) -> io::Result<Self> {
+        let path = path.into();
+        let state = Mutex::new(start(&path)?);
+        Ok(Self {
+            path,
+            limit,
+            keep,
+            state,
+        })
+    }
+
+    // a datagram as it went over UDP, wrapped in made up IP and UDP headers so Wireshark can dissect it
+    pub fn outer(
+        &self,
+        src: SocketAddr, // where it came from
+        dst: SocketAddr, // where it went
+        payload: &[u8],  // the WireGuard message
+        now: Duration,   // duration since UNIX epoch
+    ) -> io::Result<()> {
+        self.write(OUTER, &datagram(src, dst, payload), now)
+    }
+
+    // a packet as it went in or out of the tunnel
+    pub fn inner(&self, packet: &[u8], now: Duration) -> io::Result<()> {
+        self.write(INNER, packet, now)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.state.lock().unwrap().out.flush()
+    }
+
+    fn write(&self, interface: u32, packet: &[u8], now: Duration) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.written >= self.limit {
+            state.out.flush()?;
+            self.rotate()?;
+            *state = start(&self.path)?;
+        }
+        let nanos = now.as_nanos() as u64;
+        let mut body = Vec::with_capacity(0x14 + packet.len());
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((nanos >> 0x20) as u32).to_le_bytes());
+        body.extend_from_slice(&(nanos as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(packet);
+        state.written += block(&mut state.out, ENHANCED_PACKET, &body)?;
+        Ok(())
+    }
+
+    // path.n becomes path.n+1, the oldest falls off the end
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0x00 {
+            return Ok(());
+        }
+        for n in (0x01..self.keep).rev() {
+            let _ = rename(self.rotated(n), self.rotated(n + 0x01));
+        }
+        rename(&self.path, self.rotated(0x01))
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+}
+
+// truncates path and writes the section header and both interfaces
+fn start(path: &Path) -> io::Result<State> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut written = 0x00;
+    let mut shb = Vec::new();
+    shb.extend_from_slice(&0x1a2b3c4du32.to_le_bytes());
+    shb.extend_from_slice(&0x0001u16.to_le_bytes());
+    shb.extend_from_slice(&0x0000u16.to_le_bytes());
+    shb.extend_from_slice(&(-0x01i64).to_le_bytes());
+    written += block(&mut out, SECTION_HEADER, &shb)?;
+    for name in ["outer", "inner"] {
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0x0000u16.to_le_bytes());
+        idb.extend_from_slice(&0x00000000u32.to_le_bytes());
+        option(&mut idb, IF_NAME, name.as_bytes());
+        // timestamps are in nanoseconds
+        option(&mut idb, IF_TSRESOL, &[0x09]);
+        option(&mut idb, OPT_ENDOFOPT, &[]);
+        written += block(&mut out, INTERFACE_DESCRIPTION, &idb)?;
+    }
+    Ok(State { out, written })
+}
+
+// block type || block length || body padded to 32 bits || block length
+fn block(out: &mut impl Write, kind: u32, body: &[u8]) -> io::Result<u64> {
+    let padded = body.len().next_multiple_of(0x04);
+    let length = (0x0c + padded) as u32;
+    out.write_all(&kind.to_le_bytes())?;
+    out.write_all(&length.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&[0x00; 0x03][0x00..padded - body.len()])?;
+    out.write_all(&length.to_le_bytes())?;
+    Ok(length as u64)
+}
+
+// option code || option length || value padded to 32 bits
+fn option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    out.extend_from_slice(&code.to_le_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    out.extend_from_slice(value);
+    out.resize(out.len().next_multiple_of(0x04), 0x00);
+}
+
+// IPv4 when both ends are, IPv6 with mapped addresses otherwise
+fn datagram(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let length = 0x08 + payload.len();
+    let mut udp = Vec::with_capacity(length);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&(length as u16).to_be_bytes());
+    udp.extend_from_slice(&[0x00; 0x02]);
+    udp.extend_from_slice(payload);
+    let mut out = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let mut ip = vec![0x45, 0x00];
+            ip.extend_from_slice(&((0x14 + length) as u16).to_be_bytes());
+            ip.extend_from_slice(&[0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00]);
+            ip.extend_from_slice(&s.octets());
+            ip.extend_from_slice(&d.octets());
+            let csum = !fold(sum(0x00, &ip));
+            ip[0x0a..0x0c].copy_from_slice(&csum.to_be_bytes());
+            ip
+        }
+        (s, d) => {
+            let s = match s {
+                IpAddr::V4(s) => s.to_ipv6_mapped(),
+                IpAddr::V6(s) => s,
+            };
+            let d = match d {
+                IpAddr::V4(d) => d.to_ipv6_mapped(),
+                IpAddr::V6(d) => d,
+            };
+            let mut ip = vec![0x60, 0x00, 0x00, 0x00];
+            ip.extend_from_slice(&(length as u16).to_be_bytes());
+            ip.extend_from_slice(&[0x11, 0x40]);
+            ip.extend_from_slice(&s.octets());
+            ip.extend_from_slice(&d.octets());
+            ip
+        }
+    };
+    // pseudo header is the addresses, the protocol and the UDP length
+    let addresses = match out[0x00] >> 0x04 {
+        0x04 => &out[0x0c..0x14],
+        _ => &out[0x08..0x28],
+    };
+    let csum = match !fold(sum(sum(0x11 + length as u32, addresses), &udp)) {
+        // zero means no checksum for UDP
+        0x0000 => 0xffff,
+        csum => csum,
+    };
+    udp[0x06..0x08].copy_from_slice(&csum.to_be_bytes());
+    out.extend_from_slice(&udp);
+    out
+}
+
+// the interface and data of every packet in a file this module wrote
+#[cfg(test)]
+pub(crate) fn read(path: &Path) -> Vec<(u32, Vec<u8>)> {
+    let file = std::fs::read(path).unwrap();
+    let mut rest = &file[..];
+    let mut packets = Vec::new();
+    let u32_at =
+        |bytes: &[u8], at: usize| u32::from_le_bytes(bytes[at..at + 0x04].try_into().unwrap());
+    while !rest.is_empty() {
+        let (kind, length) = (u32_at(rest, 0x00), u32_at(rest, 0x04) as usize);
+        // the length is repeated at the end so readers can walk backwards too
+        assert_eq!(u32_at(rest, length - 0x04) as usize, length);
+        let body = &rest[0x08..length - 0x04];
+        match kind {
+            SECTION_HEADER => assert_eq!(u32_at(body, 0x00), 0x1a2b3c4d),
+            INTERFACE_DESCRIPTION => assert_eq!(body[0x00..0x02], LINKTYPE_RAW.to_le_bytes()),
+            ENHANCED_PACKET => {
+                let captured = u32_at(body, 0x0c) as usize;
+                assert_eq!(u32_at(body, 0x10) as usize, captured);
+                packets.push((u32_at(body, 0x00), body[0x14..0x14 + captured].to_vec()));
+            }
+            kind => panic!("unexpected block {kind:#x}"),
+        }
+        rest = &rest[length..];
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readback() {
+        let path =
+            std::env::temp_dir().join(format!("shyvana-capture-{}.pcapng", std::process::id()));
+        // small enough that the second packet starts a new file
+        let capture = Capture::create(&path, 0xa0, 0x01).unwrap();
+        let src: SocketAddr = ([0xc0, 0x00, 0x02, 0x01], 0xca6c).into();
+        let dst: SocketAddr = ([0xc0, 0x00, 0x02, 0x02], 0xca6c).into();
+        let now = Duration::from_secs(0x6553f100);
+        capture.outer(src, dst, &[0x04; 0x21], now).unwrap();
+        capture.inner(&[0x45; 0x14], now).unwrap();
+        capture.flush().unwrap();
+
+        let rotated = capture.rotated(0x01);
+        let packets = read(&rotated);
+        assert_eq!(packets.len(), 0x01);
+        let (interface, datagram) = &packets[0x00];
+        assert_eq!(*interface, OUTER);
+        assert_eq!(datagram.len(), 0x14 + 0x08 + 0x21);
+        // a header that checks out sums to all ones, and so does UDP with its pseudo header
+        assert_eq!(fold(sum(0x00, &datagram[0x00..0x14])), 0xffff);
+        let udp = &datagram[0x14..];
+        assert_eq!(
+            fold(sum(
+                sum(0x11 + udp.len() as u32, &datagram[0x0c..0x14]),
+                udp
+            )),
+            0xffff
+        );
+        assert_eq!(udp[0x08..], [0x04; 0x21]);
+
+        assert_eq!(read(&path), [(INNER, vec![0x45; 0x14])]);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&rotated);
+    }
+}
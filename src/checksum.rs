@@ -0,0 +1,18 @@
+// ones complement sum of big endian 16 bit words, for IP and UDP checksums
+pub fn sum(mut acc: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(0x02);
+    for chunk in &mut chunks {
+        acc = fold(acc + u16::from_be_bytes([chunk[0x00], chunk[0x01]]) as u32) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        acc = fold(acc + ((*last as u32) << 0x08)) as u32;
+    }
+    acc
+}
+
+pub fn fold(mut acc: u32) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 0x10);
+    }
+    acc as u16
+}
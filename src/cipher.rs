@@ -1,9 +1,16 @@
-use std::ops::{Deref, DerefMut};
+#![forbid(unsafe_code)]
+
+use chacha20poly1305::ChaCha20Poly1305;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
 
 use crate::{
-    crypto::{open, seal},
-    error::{Error, Result},
+    crypto::{aead, open_with, seal_with},
+    error::{Error, Peer, Result},
     packet::TransportData,
+    trace::{debug, trace},
 };
 
 pub struct Encrypted<'a> {
@@ -49,11 +56,18 @@ pub struct Decrypted<'a> {
 }
 
 impl<'a> Decrypted<'a> {
-    pub fn new(buffer: &'a mut [u8]) -> Self {
-        Self {
+    // buffer has room for the header in front of the packet and the tag behind it
+    pub fn new(buffer: &'a mut [u8]) -> Result<Self> {
+        if buffer.len() < 0x20 {
+            Err(Error::BufferLengthTooShort {
+                expected: 0x20,
+                got: buffer.len(),
+            })?
+        }
+        Ok(Self {
             length: buffer.len() - 0x20,
             buffer,
-        }
+        })
     }
 
     pub fn resize(&mut self, length: usize) -> bool {
@@ -80,16 +94,116 @@ impl<'a> DerefMut for Decrypted<'a> {
     }
 }
 
+// room for the header, the padded packet and the tag
+fn check_room(buffer: &[u8], length: usize) -> Result<()> {
+    let padded = length.next_multiple_of(0x10);
+    if buffer.len() < padded + 0x20 {
+        Err(Error::BufferLengthTooShort {
+            expected: padded + 0x20,
+            got: buffer.len(),
+        })?
+    }
+    Ok(())
+}
+
+// 2^64 - 2^13 - 1, leaving room for the replay window
+pub const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (0x01 << 0x0d);
+
 pub struct Encryptor {
-    r_i: [u8; 0x04], // receiver index
-    key: [u8; 0x20], // sending key
-    s_c: u64,        // send counter
-    s_b: u64,        // send counter upper bound (exclusive)
+    r_i: u32,              // receiver index
+    key: [u8; 0x20],       // sending key
+    s_c: u64,              // send counter
+    s_b: u64,              // send counter upper bound (exclusive)
+    cph: ChaCha20Poly1305, // cipher keyed with the sending key
 }
 
 impl Encryptor {
-    pub fn encrypt(&mut self, buffer: Decrypted) -> Result<Encrypted> {
-        todo!()
+    pub fn new(r_i: u32, key: [u8; 0x20]) -> Self {
+        debug!(index = r_i, "installed sending session");
+        Self {
+            r_i,
+            key,
+            s_c: 0x00,
+            s_b: REJECT_AFTER_MESSAGES,
+            cph: aead(key),
+        }
+    }
+
+    // picks a session back up at counter s_c, for sessions carried over from another process
+    pub fn resume(r_i: u32, key: [u8; 0x20], s_c: u64, s_b: u64) -> Self {
+        debug!(index = r_i, counter = s_c, "resumed sending session");
+        Self {
+            r_i,
+            key,
+            s_c,
+            s_b,
+            cph: aead(key),
+        }
+    }
+
+    pub fn encrypt<'a>(&mut self, buffer: Decrypted<'a>) -> Result<Encrypted<'a>> {
+        self.check(0x01)?;
+        let Decrypted { buffer, length } = buffer;
+        check_room(buffer, length)?;
+        let cnt = self.s_c;
+        self.s_c += 1;
+        let length = self.seal(cnt, buffer, length)?;
+        Ok(Encrypted { buffer, length })
+    }
+
+    // seals every packet with one counter range, in order, or none of them if any would fail
+    pub fn encrypt_batch<'b>(
+        &mut self,
+        buffers: &'b mut [Decrypted],
+    ) -> Result<Vec<Encrypted<'b>>> {
+        self.check(buffers.len() as u64)?;
+        for buffer in buffers.iter() {
+            check_room(buffer.buffer, buffer.length)?;
+        }
+        let base = self.s_c;
+        self.s_c += buffers.len() as u64;
+        buffers
+            .iter_mut()
+            .zip(base..)
+            .map(|(Decrypted { buffer, length }, cnt)| {
+                let length = self.seal(cnt, buffer, *length)?;
+                Ok(Encrypted {
+                    buffer: &mut buffer[..],
+                    length,
+                })
+            })
+            .collect()
+    }
+
+    fn check(&self, amount: u64) -> Result<()> {
+        if self.s_b - self.s_c.min(self.s_b) < amount {
+            debug!(
+                index = self.r_i,
+                "dropped outgoing packet, sending counter exhausted"
+            );
+            Err(Error::CounterExhausted.with_peer(Peer::Index(self.r_i)))?
+        }
+        Ok(())
+    }
+
+    // buffer has passed check_room, the length of the message comes back
+    fn seal(&self, cnt: u64, buffer: &mut [u8], length: usize) -> Result<usize> {
+        // encapsulated_packet = encapsulated_packet || zero padding in order to make the length a multiple of 16
+        let padded = length.next_multiple_of(0x10);
+        buffer[0x10 + length..0x10 + padded].fill(0x00);
+        let msg = TransportData::wrap_mut(buffer)?;
+        // msg.message_type = 4
+        msg.m_t = 0x04;
+        // msg.reserved_zero = { 0, 0, 0 }
+        msg.r_0 = [0x00; 0x03];
+        // msg.receiver_index = little_endian(peer.their_index)
+        msg.r_i.set(self.r_i);
+        // msg.counter = little_endian(initiator.sending_key_counter)
+        msg.cnt.set(cnt);
+        // msg.encrypted_encapsulated_packet = AEAD(initiator.sending_key, counter, encapsulated_packet, [empty])
+        let (lhs, rhs) = buffer[0x10..0x20 + padded].split_at_mut(padded);
+        seal_with(&self.cph, cnt, [], lhs, rhs)?;
+        Ok(padded + 0x20)
     }
 
     pub fn reserve(&mut self, amount: u64) -> Self {
@@ -97,14 +211,15 @@ impl Encryptor {
             key: self.key,
             s_c: self.s_c,
             s_b: {
-                self.s_c = (self.s_c + amount).min(self.s_b);
+                self.s_c = self.s_c.saturating_add(amount).min(self.s_b);
                 self.s_c
             },
             r_i: self.r_i,
+            cph: self.cph.clone(),
         }
     }
 
-    pub fn r_i(&self) -> [u8; 0x04] {
+    pub fn r_i(&self) -> u32 {
         self.r_i
     }
 
@@ -121,13 +236,120 @@ impl Encryptor {
     }
 }
 
+// words in the replay bitmap, one of them is always being recycled
+pub const RING: usize = 0x80;
+
+// how far behind the highest counter seen a packet may still arrive
+pub const WINDOW: u64 = ((RING - 0x01) * 0x40) as u64;
+
+// sliding window over receive counters, as in RFC 6479
+#[derive(Clone)]
+pub struct Window {
+    last: u64,         // highest counter accepted so far
+    ring: [u64; RING], // bit n of word (n / 64) % RING is counter n
+}
+
+impl Window {
+    pub fn new() -> Self {
+        Self {
+            last: 0x00,
+            ring: [0x00; RING],
+        }
+    }
+
+    // a window carried over from another process
+    pub fn resume(last: u64, ring: [u64; RING]) -> Self {
+        Self { last, ring }
+    }
+
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+
+    pub fn ring(&self) -> &[u64; RING] {
+        &self.ring
+    }
+
+    // marks cnt as seen, false if it was already or is too old or too far out to tell
+    pub fn accept(&mut self, cnt: u64) -> bool {
+        if cnt >= REJECT_AFTER_MESSAGES {
+            return false;
+        }
+        let block = cnt >> 0x06;
+        if cnt > self.last {
+            // words the window slides past start over empty
+            let current = self.last >> 0x06;
+            for i in 0x01..=(block - current).min(RING as u64) {
+                self.ring[((current + i) % RING as u64) as usize] = 0x00;
+            }
+            self.last = cnt;
+        } else if self.last - cnt > WINDOW {
+            return false;
+        }
+        let word = &mut self.ring[(block % RING as u64) as usize];
+        let bit = 0x01 << (cnt & 0x3f);
+        let seen = *word & bit != 0x00;
+        *word |= bit;
+        !seen
+    }
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Decryptor {
-    r_i: [u8; 0x04], // receiver index
-    key: [u8; 0x20], // receiving key
+    r_i: u32,              // receiver index
+    key: [u8; 0x20],       // receiving key
+    cph: ChaCha20Poly1305, // cipher keyed with the receiving key
+    window: Mutex<Window>, // counters already opened, shared by every worker opening for this session
 }
 
 impl Decryptor {
+    pub fn new(r_i: u32, key: [u8; 0x20]) -> Self {
+        debug!(index = r_i, "installed receiving session");
+        Self {
+            r_i,
+            key,
+            cph: aead(key),
+            window: Mutex::new(Window::new()),
+        }
+    }
+
+    // picks a session back up with the counters it had already opened, for sessions carried over from another process
+    pub fn resume(r_i: u32, key: [u8; 0x20], window: Window) -> Self {
+        debug!(
+            index = r_i,
+            counter = window.last,
+            "resumed receiving session"
+        );
+        Self {
+            r_i,
+            key,
+            cph: aead(key),
+            window: Mutex::new(window),
+        }
+    }
+
     pub fn decrypt(&self, buffer: &mut [u8]) -> Result<()> {
+        let cnt = self.open(buffer)?;
+        self.accept(&mut self.window.lock().unwrap(), cnt)
+    }
+
+    // opens every message on its own, then checks their counters under one lock of the window
+    pub fn decrypt_batch(&self, buffers: &mut [&mut [u8]]) -> Vec<Result<()>> {
+        let opened: Vec<_> = buffers.iter_mut().map(|buffer| self.open(buffer)).collect();
+        let mut window = self.window.lock().unwrap();
+        opened
+            .into_iter()
+            .map(|cnt| self.accept(&mut window, cnt?))
+            .collect()
+    }
+
+    // authenticates a message in place and returns its counter, which is not yet marked as seen
+    fn open(&self, buffer: &mut [u8]) -> Result<u64> {
         if buffer.len() < 0x20 {
             Err(Error::BufferLengthTooShort {
                 expected: 0x20,
@@ -135,23 +357,216 @@ impl Decryptor {
             })?
         }
         // encapsulated_packet = encapsulated_packet || zero padding in order to make the length a multiple of 16
-        if buffer.len() % 0x10 != 0 {
+        if !buffer.len().is_multiple_of(0x10) {
             Err(Error::BufferLengthInvalid)?
         }
         let msg = TransportData::wrap_mut(buffer)?;
-        let cnt = u64::from_le_bytes(msg.cnt);
+        let cnt = msg.cnt.get();
+        if cnt >= REJECT_AFTER_MESSAGES {
+            Err(Error::CounterExhausted.with_peer(Peer::Index(self.r_i)))?
+        }
         // msg.encrypted_encapsulated_packet = AEAD(initiator.sending_key, counter, encapsulated_packet, [empty])
         let length = buffer.len();
         let (lhs, rhs) = buffer[0x10..].split_at_mut(length - 0x20);
-        open(self.key, cnt, [], lhs, rhs)?;
+        open_with(&self.cph, cnt, [], lhs, rhs).map_err(|e| {
+            trace!(
+                index = self.r_i,
+                counter = cnt,
+                "dropped incoming packet, authentication failed"
+            );
+            Error::from(e).with_peer(Peer::Index(self.r_i))
+        })?;
+        Ok(cnt)
+    }
+
+    // only authenticated counters move the window, so forged ones cannot push real ones out
+    fn accept(&self, window: &mut Window, cnt: u64) -> Result<()> {
+        if !window.accept(cnt) {
+            trace!(
+                index = self.r_i,
+                counter = cnt,
+                "dropped incoming packet, replayed"
+            );
+            Err(Error::Replay.with_peer(Peer::Index(self.r_i)))?
+        }
         Ok(())
     }
 
-    pub fn r_i(&self) -> [u8; 0x04] {
+    pub fn r_i(&self) -> u32 {
         self.r_i
     }
 
     pub fn key(&self) -> [u8; 0x20] {
         self.key
     }
+
+    // a copy of the counters opened so far
+    pub fn window(&self) -> Window {
+        self.window.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealed(encryptor: &mut Encryptor) -> Vec<u8> {
+        let mut buffer = vec![0x00; 0x20];
+        let length = encryptor
+            .encrypt(Decrypted::new(&mut buffer).unwrap())
+            .unwrap()
+            .len();
+        buffer.truncate(length);
+        buffer
+    }
+
+    #[test]
+    fn same_packet_twice() {
+        let mut encryptor = Encryptor::new(0x2a, [0x07; 0x20]);
+        let decryptor = Decryptor::new(0x2a, [0x07; 0x20]);
+        let msg = sealed(&mut encryptor);
+        decryptor.decrypt(&mut msg.clone()).unwrap();
+        let err = decryptor.decrypt(&mut msg.clone()).unwrap_err();
+        assert!(matches!(err.kind(), Error::Replay));
+        // a forged copy never gets as far as the window
+        let mut forged = msg;
+        forged[0x10] ^= 0x01;
+        let err = decryptor.decrypt(&mut forged).unwrap_err();
+        assert!(!matches!(err.kind(), Error::Replay));
+    }
+
+    #[test]
+    fn window_slides() {
+        let mut window = Window::new();
+        assert!(window.accept(0x00));
+        // out of order but within the window is fine, once
+        assert!(window.accept(0x20));
+        assert!(window.accept(0x10));
+        assert!(!window.accept(0x10));
+        assert!(window.accept(WINDOW + 0x20));
+        assert!(window.accept(0x21));
+        // anything further behind than the window can no longer be told apart
+        assert!(!window.accept(0x1f));
+        // a jump past the whole ring forgets everything before it
+        let far = 0x10 * WINDOW;
+        assert!(window.accept(far));
+        assert!(window.accept(far - 0x01));
+        assert!(!window.accept(far - WINDOW - 0x01));
+        assert!(!window.accept(REJECT_AFTER_MESSAGES));
+        assert!(window.accept(REJECT_AFTER_MESSAGES - 0x01));
+        assert!(!window.accept(REJECT_AFTER_MESSAGES - 0x01));
+    }
+
+    // packets of a few lengths, laid out like Decrypted
+    fn buffers() -> Vec<(Vec<u8>, usize)> {
+        [0x00, 0x05, 0x10, 0x23]
+            .into_iter()
+            .map(|length: usize| {
+                let mut buffer = vec![0x00; length.next_multiple_of(0x10) + 0x20];
+                buffer[0x10..0x10 + length].fill(length as u8);
+                (buffer, length)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_matches_single() {
+        let mut single = Encryptor::new(0x2a, [0x07; 0x20]);
+        let mut batch = Encryptor::new(0x2a, [0x07; 0x20]);
+        let expected: Vec<_> = buffers()
+            .into_iter()
+            .map(|(mut buffer, length)| {
+                let mut decrypted = Decrypted::new(&mut buffer).unwrap();
+                decrypted.resize(length);
+                single.encrypt(decrypted).unwrap().to_vec()
+            })
+            .collect();
+        let mut buffers = buffers();
+        let mut decrypted: Vec<_> = buffers
+            .iter_mut()
+            .map(|(buffer, length)| {
+                let mut decrypted = Decrypted::new(buffer).unwrap();
+                decrypted.resize(*length);
+                decrypted
+            })
+            .collect();
+        let sealed: Vec<_> = batch
+            .encrypt_batch(&mut decrypted)
+            .unwrap()
+            .iter()
+            .map(|msg| msg.to_vec())
+            .collect();
+        assert_eq!(sealed, expected);
+        assert_eq!(batch.s_c(), single.s_c());
+
+        // a replay, one within the batch and a forgery in the middle
+        let mut forged = expected[0x02].clone();
+        forged[0x10] ^= 0x01;
+        let msgs = [
+            &expected[0x00],
+            &expected[0x01],
+            &expected[0x00],
+            &forged,
+            &expected[0x02],
+            &expected[0x03],
+            &expected[0x03],
+        ];
+        let single = Decryptor::new(0x2a, [0x07; 0x20]);
+        let batch = Decryptor::new(0x2a, [0x07; 0x20]);
+        single.decrypt(&mut expected[0x00].clone()).unwrap();
+        batch.decrypt(&mut expected[0x00].clone()).unwrap();
+        let mut opened: Vec<_> = msgs.iter().map(|msg| msg.to_vec()).collect();
+        let mut views: Vec<_> = opened.iter_mut().map(|msg| &mut msg[..]).collect();
+        let results = batch.decrypt_batch(&mut views);
+        for ((msg, result), opened) in msgs.iter().zip(&results).zip(&opened) {
+            let mut msg = msg.to_vec();
+            match (single.decrypt(&mut msg), result) {
+                (Ok(()), Ok(())) => assert_eq!(&msg, opened),
+                (Err(e), Err(f)) => {
+                    assert_eq!(format!("{:?}", e.kind()), format!("{:?}", f.kind()))
+                }
+                (single, batch) => panic!("{single:?} but {batch:?} from a batch"),
+            }
+        }
+        let replays = results
+            .iter()
+            .filter(|result| matches!(result, Err(e) if matches!(e.kind(), Error::Replay)))
+            .count();
+        assert_eq!(replays, 0x03);
+        assert!(!matches!(
+            results[0x03].as_ref().unwrap_err().kind(),
+            Error::Replay
+        ));
+        assert!(results[0x04].is_ok());
+    }
+
+    #[test]
+    fn batch_is_all_or_nothing() {
+        let mut encryptor = Encryptor::new(0x2a, [0x07; 0x20]);
+        let mut short = vec![0x00; 0x20];
+        let mut buffers = buffers();
+        let mut decrypted: Vec<_> = buffers
+            .iter_mut()
+            .map(|(buffer, _)| Decrypted::new(buffer).unwrap())
+            .collect();
+        // does not fit once the padding is added
+        let mut packet = Decrypted::new(&mut short).unwrap();
+        packet.length = 0x01;
+        decrypted.push(packet);
+        assert!(encryptor.encrypt_batch(&mut decrypted).is_err());
+        assert_eq!(encryptor.s_c(), 0x00);
+        let mut limited = Encryptor::resume(0x2a, [0x07; 0x20], 0x00, 0x02);
+        let err = limited
+            .encrypt_batch(&mut decrypted[0x00..0x03])
+            .map(|_| ())
+            .unwrap_err();
+        assert!(matches!(err.kind(), Error::CounterExhausted));
+        assert_eq!(
+            limited
+                .encrypt_batch(&mut decrypted[0x00..0x02])
+                .unwrap()
+                .len(),
+            0x02
+        );
+    }
 }
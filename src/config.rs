@@ -0,0 +1,335 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::{
+    forward::{Forward, Protocol},
+    obfuscation::Obfuscation,
+};
+
+// the subset of a wg-quick file a single peer userspace tunnel needs
+pub struct Config {
+    pub private_key: [u8; 0x20],
+    pub addresses: Vec<(IpAddr, u8)>, // Address, with the prefix length
+    pub dns: Vec<IpAddr>,
+    pub listen_port: Option<u16>,
+    pub peer: PeerConfig,
+    pub forwards: Vec<Forward>, // every Local and Remote in the [Forward] sections
+}
+
+pub struct PeerConfig {
+    pub public_key: [u8; 0x20],
+    pub preshared_key: [u8; 0x20],    // all zero when there is none
+    pub endpoint: Option<SocketAddr>, // none when this side waits on ListenPort to be reached
+    pub persistent_keepalive: Option<Duration>,
+    pub transport: Outer, // Transport, how messages reach Endpoint, both ends have to agree
+    pub obfuscation: Obfuscation, // Jc, Jmin, Jmax, S1, S2 and H1 to H4, both ends have to agree
+}
+
+// what carries messages to the peer
+pub enum Outer {
+    Udp,
+    Tcp,                                      // length-prefixed, see transport::TcpTransport
+    WebSocket { host: String, path: String }, // from a ws://host/path URL
+}
+
+impl Config {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut section = "";
+        let mut private_key = None;
+        let mut addresses = Vec::new();
+        let mut dns = Vec::new();
+        let mut listen_port = None;
+        let mut public_key = None;
+        let mut preshared_key = [0x00; 0x20];
+        let mut endpoint = None;
+        let mut persistent_keepalive = None;
+        let mut transport = Outer::Udp;
+        let mut obfuscation = Obfuscation::default();
+        let mut forwards = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                section = match name.trim() {
+                    "Interface" => "Interface",
+                    "Peer" if public_key.is_none() => "Peer",
+                    "Peer" => Err(invalid("only one [Peer] is supported"))?,
+                    "Forward" => "Forward",
+                    // sections for other tools, like wireproxy's, are left to them
+                    _ => "",
+                };
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(&format!("expected key = value, got {line}")))?;
+            let (key, value) = (key.trim(), value.trim());
+            match (section, key) {
+                ("Interface", "PrivateKey") => private_key = Some(key32(value)?),
+                ("Interface", "Address") => {
+                    for address in list(value) {
+                        addresses.push(cidr(address)?);
+                    }
+                }
+                ("Interface", "DNS") => {
+                    for server in list(value) {
+                        dns.push(
+                            server
+                                .parse()
+                                .map_err(|_| invalid(&format!("bad DNS server {server}")))?,
+                        );
+                    }
+                }
+                ("Interface", "ListenPort") => {
+                    listen_port = Some(value.parse().map_err(|_| invalid("bad ListenPort"))?)
+                }
+                ("Peer", "PublicKey") => public_key = Some(key32(value)?),
+                ("Peer", "PresharedKey") => preshared_key = key32(value)?,
+                ("Peer", "Endpoint") => {
+                    endpoint = value.to_socket_addrs()?.next();
+                    if endpoint.is_none() {
+                        Err(invalid(&format!("could not resolve Endpoint {value}")))?
+                    }
+                }
+                ("Peer", "PersistentKeepalive") => {
+                    persistent_keepalive = match value {
+                        "off" | "0" => None,
+                        value => Some(Duration::from_secs(
+                            value
+                                .parse()
+                                .map_err(|_| invalid("bad PersistentKeepalive"))?,
+                        )),
+                    }
+                }
+                ("Peer", "Transport") => transport = outer(value)?,
+                ("Peer", "Jc") => obfuscation.junk_count = number(key, value)?,
+                ("Peer", "Jmin") => obfuscation.junk_min = number(key, value)?,
+                ("Peer", "Jmax") => obfuscation.junk_max = number(key, value)?,
+                ("Peer", "S1") => obfuscation.init_padding = number(key, value)?,
+                ("Peer", "S2") => obfuscation.resp_padding = number(key, value)?,
+                ("Peer", "H1") => obfuscation.headers[0x00] = number(key, value)?,
+                ("Peer", "H2") => obfuscation.headers[0x01] = number(key, value)?,
+                ("Peer", "H3") => obfuscation.headers[0x02] = number(key, value)?,
+                ("Peer", "H4") => obfuscation.headers[0x03] = number(key, value)?,
+                ("Forward", "Local") => forwards.push(local(value)?),
+                ("Forward", "Remote") => forwards.push(remote(value)?),
+                _ => {}
+            }
+        }
+        if endpoint.is_none() && listen_port.is_none() {
+            Err(invalid(
+                "missing [Peer] Endpoint, and no ListenPort to be reached on",
+            ))?
+        }
+        obfuscation
+            .validate()
+            .map_err(|e| invalid(&format!("bad obfuscation settings, {e}")))?;
+        Ok(Self {
+            private_key: private_key.ok_or_else(|| invalid("missing PrivateKey"))?,
+            addresses,
+            dns,
+            listen_port,
+            peer: PeerConfig {
+                public_key: public_key.ok_or_else(|| invalid("missing [Peer] PublicKey"))?,
+                preshared_key,
+                endpoint,
+                persistent_keepalive,
+                transport,
+                obfuscation,
+            },
+            forwards,
+        })
+    }
+}
+
+fn number<T: std::str::FromStr>(key: &str, value: &str) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(&format!("bad {key} {value}")))
+}
+
+// udp, tcp, or a ws:// URL whose host and path go in the upgrade request
+fn outer(value: &str) -> io::Result<Outer> {
+    match value {
+        "udp" => Ok(Outer::Udp),
+        "tcp" => Ok(Outer::Tcp),
+        value => {
+            let bad = || {
+                invalid(&format!(
+                    "bad Transport {value}, expected udp, tcp or ws://host/path"
+                ))
+            };
+            let url = value.strip_prefix("ws://").ok_or_else(bad)?;
+            let (host, path) = url.find('/').map_or((url, "/"), |at| url.split_at(at));
+            if host.is_empty() {
+                Err(bad())?
+            }
+            Ok(Outer::WebSocket {
+                host: host.to_owned(),
+                path: path.to_owned(),
+            })
+        }
+    }
+}
+
+// Local = tcp [address:]port target, where target is an address behind the peer
+fn local(value: &str) -> io::Result<Forward> {
+    let bad = || {
+        invalid(&format!(
+            "bad Local {value}, expected tcp|udp [address:]port address:port"
+        ))
+    };
+    let (protocol, listen, target) = words(value).ok_or_else(bad)?;
+    let listen = match listen.parse::<u16>() {
+        // loopback unless asked otherwise, like ssh -L
+        Ok(port) => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+        Err(_) => listen.parse().map_err(|_| bad())?,
+    };
+    Ok(Forward::Local {
+        protocol: protocol.ok_or_else(bad)?,
+        listen,
+        target: target.parse().map_err(|_| bad())?,
+    })
+}
+
+// Remote = tcp port target, where port is on this side's tunnel addresses and target is reachable from here
+fn remote(value: &str) -> io::Result<Forward> {
+    let bad = || {
+        invalid(&format!(
+            "bad Remote {value}, expected tcp|udp port host:port"
+        ))
+    };
+    let (protocol, port, target) = words(value).ok_or_else(bad)?;
+    Ok(Forward::Remote {
+        protocol: protocol.ok_or_else(bad)?,
+        port: port.parse().map_err(|_| bad())?,
+        target: target.to_socket_addrs()?.next().ok_or_else(bad)?,
+    })
+}
+
+fn words(value: &str) -> Option<(Option<Protocol>, &str, &str)> {
+    let mut words = value.split_whitespace();
+    let protocol = match words.next()? {
+        "tcp" => Some(Protocol::Tcp),
+        "udp" => Some(Protocol::Udp),
+        _ => None,
+    };
+    let (listen, target) = (words.next()?, words.next()?);
+    words.next().is_none().then_some((protocol, listen, target))
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+fn key32(value: &str) -> io::Result<[u8; 0x20]> {
+    STANDARD
+        .decode(value)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| invalid("keys are 32 bytes of base64"))
+}
+
+// an address with its prefix length, or a whole host without one
+fn cidr(value: &str) -> io::Result<(IpAddr, u8)> {
+    let bad = || invalid(&format!("bad Address {value}"));
+    let (address, prefix) = match value.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (value, None),
+    };
+    let address: IpAddr = address.parse().map_err(|_| bad())?;
+    let max = if address.is_ipv4() { 0x20 } else { 0x80 };
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .parse()
+            .ok()
+            .filter(|prefix| *prefix <= max)
+            .ok_or_else(bad)?,
+        None => max,
+    };
+    Ok((address, prefix))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "[Interface]
+PrivateKey = AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+[Peer]
+PublicKey = AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=
+Endpoint = 192.0.2.1:51820
+";
+
+    fn forwards(section: &str) -> io::Result<Vec<String>> {
+        let config = Config::parse(&format!("{BASE}{section}"))?;
+        Ok(config.forwards.iter().map(ToString::to_string).collect())
+    }
+
+    #[test]
+    fn forward_rules() {
+        let rules = forwards(
+            "[Forward]
+Local = tcp 8080 10.0.0.2:80 # web
+Local = udp [::1]:5353 10.0.0.2:53
+[Forward]
+Remote = tcp 2222 127.0.0.1:22
+Remote = udp 5000 [::1]:5000
+",
+        )
+        .unwrap();
+        assert_eq!(
+            rules,
+            [
+                "-L tcp 127.0.0.1:8080 10.0.0.2:80",
+                "-L udp [::1]:5353 10.0.0.2:53",
+                "-R tcp 2222 127.0.0.1:22",
+                "-R udp 5000 [::1]:5000",
+            ]
+        );
+        // the keys only mean something inside [Forward]
+        assert!(forwards("[Other]\nLocal = nonsense\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_forward_rules() {
+        for rule in [
+            "Local = sctp 8080 10.0.0.2:80",  // unknown protocol
+            "Local = tcp 8080",               // no target
+            "Local = tcp 8080 10.0.0.2:80 x", // trailing word
+            "Local = tcp 70000 10.0.0.2:80",  // port out of range
+            "Local = tcp localhost:80 10.0.0.2:80",
+            "Local = tcp 8080 10.0.0.2",
+            "Remote = tcp 10.0.0.1:2222 127.0.0.1:22", // only a port on the tunnel side
+            "Remote = udp 5000 127.0.0.1",
+        ] {
+            assert!(forwards(&format!("[Forward]\n{rule}\n")).is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn listening_side_has_no_endpoint() {
+        let base = BASE.replace("Endpoint = 192.0.2.1:51820\n", "");
+        assert!(Config::parse(&base).is_err());
+        let listening = base.replace("[Peer]", "ListenPort = 443\n[Peer]");
+        let config = Config::parse(&listening).unwrap();
+        assert_eq!(config.listen_port, Some(443));
+        assert!(config.peer.endpoint.is_none());
+    }
+}
@@ -5,8 +5,10 @@ use blake2::{
     },
     Blake2s, Blake2sMac,
 };
-use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit};
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit, XChaCha20Poly1305};
 use hmac::SimpleHmac;
+use rand_core::{CryptoRng, RngCore};
+use x25519::ReusableSecret;
 
 pub fn hash(one: impl AsRef<[u8]>, two: impl AsRef<[u8]>) -> [u8; 0x20] {
     let mut digest: Blake2s<U32> = Digest::new();
@@ -21,6 +23,13 @@ pub fn mac(key: impl AsRef<[u8]>, txt: impl AsRef<[u8]>) -> [u8; 0x10] {
     digest.finalize().into_bytes().into()
 }
 
+// constant time comparison against mac(key, txt)
+pub fn verify(key: impl AsRef<[u8]>, txt: impl AsRef<[u8]>, tag: impl AsRef<[u8]>) -> bool {
+    let mut digest: Blake2sMac<U16> = Mac::new_from_slice(key.as_ref()).unwrap();
+    digest.update(txt.as_ref());
+    digest.verify_slice(tag.as_ref()).is_ok()
+}
+
 pub fn kdf<const N: usize>(key: impl AsRef<[u8]>, txt: impl AsRef<[u8]>) -> [[u8; 0x20]; N] {
     let mut output = [[0x00; 0x20]; N];
     let mut digest: SimpleHmac<Blake2s<U32>> = Mac::new_from_slice(key.as_ref()).unwrap();
@@ -37,26 +46,50 @@ pub fn kdf<const N: usize>(key: impl AsRef<[u8]>, txt: impl AsRef<[u8]>) -> [[u8
     output
 }
 
+pub fn aead(key: impl AsRef<[u8]>) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new_from_slice(key.as_ref()).unwrap()
+}
+
 pub fn seal(
     key: impl AsRef<[u8]>,
     cnt: u64,
     aad: impl AsRef<[u8]>,
+    txt: impl AsMut<[u8]>,
+    tag: impl AsMut<[u8]>,
+) -> Result<(), chacha20poly1305::Error> {
+    seal_with(&aead(key), cnt, aad, txt, tag)
+}
+
+pub fn open(
+    key: impl AsRef<[u8]>,
+    cnt: u64,
+    aad: impl AsRef<[u8]>,
+    txt: impl AsMut<[u8]>,
+    tag: impl AsRef<[u8]>,
+) -> Result<(), chacha20poly1305::Error> {
+    open_with(&aead(key), cnt, aad, txt, tag)
+}
+
+// same as seal, but with a cipher that has already been keyed
+pub fn seal_with(
+    aead: &ChaCha20Poly1305,
+    cnt: u64,
+    aad: impl AsRef<[u8]>,
     mut txt: impl AsMut<[u8]>,
     mut tag: impl AsMut<[u8]>,
 ) -> Result<(), chacha20poly1305::Error> {
     let mut nonce = [0x00; 0x0c];
     nonce[0x04..0x0c].copy_from_slice(&cnt.to_le_bytes());
     tag.as_mut().copy_from_slice(
-        ChaCha20Poly1305::new_from_slice(key.as_ref())
-            .unwrap()
-            .encrypt_in_place_detached(&nonce.into(), aad.as_ref(), txt.as_mut())?
+        aead.encrypt_in_place_detached(&nonce.into(), aad.as_ref(), txt.as_mut())?
             .as_ref(),
     );
     Ok(())
 }
 
-pub fn open(
-    key: impl AsRef<[u8]>,
+// same as open, but with a cipher that has already been keyed
+pub fn open_with(
+    aead: &ChaCha20Poly1305,
     cnt: u64,
     aad: impl AsRef<[u8]>,
     mut txt: impl AsMut<[u8]>,
@@ -64,12 +97,91 @@ pub fn open(
 ) -> Result<(), chacha20poly1305::Error> {
     let mut nonce = [0x00; 0x0c];
     nonce[0x04..0x0c].copy_from_slice(&cnt.to_le_bytes());
-    ChaCha20Poly1305::new_from_slice(key.as_ref())
+    aead.decrypt_in_place_detached(
+        &nonce.into(),
+        aad.as_ref(),
+        txt.as_mut(),
+        tag.as_ref().into(),
+    )
+}
+
+pub fn xseal(
+    key: impl AsRef<[u8]>,
+    non: impl AsRef<[u8]>,
+    aad: impl AsRef<[u8]>,
+    mut txt: impl AsMut<[u8]>,
+    mut tag: impl AsMut<[u8]>,
+) -> Result<(), chacha20poly1305::Error> {
+    tag.as_mut().copy_from_slice(
+        XChaCha20Poly1305::new_from_slice(key.as_ref())
+            .unwrap()
+            .encrypt_in_place_detached(non.as_ref().into(), aad.as_ref(), txt.as_mut())?
+            .as_ref(),
+    );
+    Ok(())
+}
+
+pub fn xopen(
+    key: impl AsRef<[u8]>,
+    non: impl AsRef<[u8]>,
+    aad: impl AsRef<[u8]>,
+    mut txt: impl AsMut<[u8]>,
+    tag: impl AsRef<[u8]>,
+) -> Result<(), chacha20poly1305::Error> {
+    XChaCha20Poly1305::new_from_slice(key.as_ref())
         .unwrap()
         .decrypt_in_place_detached(
-            &nonce.into(),
+            non.as_ref().into(),
             aad.as_ref(),
             txt.as_mut(),
             tag.as_ref().into(),
         )
 }
+
+// the secret random_from_rng makes out of exactly these bytes, for ephemerals that have to be made again
+// this leans on x25519-dalek 2.0 filling the secret with one 32 byte fill_bytes and nothing else,
+// a version that draws more or differently would make other secrets, which the test below catches
+pub(crate) fn ephemeral_from(bytes: [u8; 0x20]) -> ReusableSecret {
+    ReusableSecret::random_from_rng(Fixed(bytes))
+}
+
+// always hands out the same 32 bytes
+struct Fixed([u8; 0x20]);
+
+impl RngCore for Fixed {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for (b, k) in dest.iter_mut().zip(self.0.iter().cycle()) {
+            *b = *k;
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for Fixed {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519::{PublicKey, StaticSecret};
+
+    #[test]
+    fn ephemeral_is_the_bytes() {
+        let bytes = [0x2a; 0x20];
+        assert_eq!(
+            PublicKey::from(&ephemeral_from(bytes)),
+            PublicKey::from(&StaticSecret::from(bytes))
+        );
+    }
+}
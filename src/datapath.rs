@@ -0,0 +1,331 @@
+use rand_core::CryptoRngCore;
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    sync::{mpsc::channel, Arc},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    driver::{Action, Driver},
+    error::Result,
+    packet::TransportData,
+    pipeline::{Lane, Pipeline},
+    pool::{HandshakePool, Job, MAX_QUEUED_HANDSHAKES},
+    tun::{Tun, MAX_PACKET},
+    udp::{UdpSocket, MAX_PAYLOAD},
+};
+
+// packets per job, small enough that one TSO segment spreads over several workers
+const CHUNK: usize = 0x08;
+
+// jobs a lane keeps in flight before it waits for the oldest one
+const DEPTH: u64 = 0x40;
+
+// datagrams taken off the socket per recvmmsg
+const BATCH: usize = 0x20;
+
+// how often the timers get a look
+const TICK: Duration = Duration::from_millis(0x64);
+
+// a kernel TUN device and a UDP socket for one peer, with transport data sealed and opened on a pipeline
+// handshakes go through the driver on a handshake pool, so a flood of them puts the device under load
+pub struct DataPath {
+    driver: Arc<Driver>,
+    tun: Arc<Tun>,
+    udp: Arc<UdpSocket>,
+    pipeline: Pipeline,
+}
+
+impl DataPath {
+    pub fn new(driver: Arc<Driver>, tun: Tun, udp: UdpSocket, pipeline: Pipeline) -> Self {
+        Self {
+            driver,
+            tun: Arc::new(tun),
+            udp: Arc::new(udp),
+            pipeline,
+        }
+    }
+
+    pub fn driver(&self) -> &Arc<Driver> {
+        &self.driver
+    }
+
+    pub fn tun(&self) -> &Tun {
+        &self.tun
+    }
+
+    pub fn udp(&self) -> &UdpSocket {
+        &self.udp
+    }
+
+    // runs until reading or writing either side fails, and returns how
+    // rng is called once for each thread and handshake job, so every one of them draws from its own source
+    pub fn run<R: CryptoRngCore>(
+        self,
+        rng: impl Fn() -> R + Send + Sync + 'static,
+    ) -> io::Result<()> {
+        let rng = Arc::new(rng);
+        let (tx, rx) = channel();
+        let pool = {
+            let (driver, udp, rng) = (self.driver.clone(), self.udp.clone(), rng.clone());
+            HandshakePool::new(
+                self.driver.tunnel().device().clone(),
+                self.pipeline.workers(),
+                MAX_QUEUED_HANDSHAKES,
+                clock,
+                move |mut job: Job| {
+                    let mut out = [0x00; 0x0500];
+                    if let Ok(Action::Network(length)) =
+                        driver.decapsulate(&mut job.msg, job.src, job.now, &mut rng(), &mut out)
+                    {
+                        // lost answers are covered by the initiator retrying
+                        let _ = udp.send_batch(job.src, &[&out[0x00..length]]);
+                    }
+                },
+            )
+        };
+        {
+            let (driver, tun, udp, tx) = (
+                self.driver.clone(),
+                self.tun.clone(),
+                self.udp.clone(),
+                tx.clone(),
+            );
+            let (lane, rng) = (self.pipeline.lane(), rng.clone());
+            thread::spawn(move || tx.send(egress(&driver, &tun, &udp, lane, &mut rng())));
+        }
+        {
+            let (driver, tun, udp, tx) = (
+                self.driver.clone(),
+                self.tun.clone(),
+                self.udp.clone(),
+                tx.clone(),
+            );
+            let (lane, rng) = (self.pipeline.lane(), rng.clone());
+            thread::spawn(move || tx.send(ingress(&driver, &tun, &udp, &pool, lane, &mut rng())));
+        }
+        {
+            let (driver, udp) = (self.driver.clone(), self.udp.clone());
+            thread::spawn(move || tx.send(timers(&driver, &udp, &mut rng())));
+        }
+        // the workers stay up as long as any lane does
+        rx.recv().unwrap()
+    }
+}
+
+// TUN to peer: packets are split out of TSO segments, sealed on the pipeline and sent in batches
+fn egress(
+    driver: &Driver,
+    tun: &Tun,
+    udp: &UdpSocket,
+    mut lane: Lane,
+    rng: &mut impl CryptoRngCore,
+) -> io::Result<()> {
+    let mut buffer = vec![0x00; MAX_PACKET];
+    let mut packets = Vec::new();
+    loop {
+        // nothing more to read right away, so whatever is still in flight goes out before blocking
+        if lane.in_flight() > 0x00 && !readable(tun)? {
+            while let Some(out) = lane.recv() {
+                send(driver, udp, out, clock(), rng)?;
+            }
+        }
+        match tun.recv(&mut buffer, &mut packets) {
+            Ok(()) => {}
+            // a segment the kernel should never have handed out, the rest of the stream is fine
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => continue,
+            Err(e) => Err(e)?,
+        }
+        let now = clock();
+        while !packets.is_empty() {
+            let chunk: Vec<_> = packets.drain(0x00..CHUNK.min(packets.len())).collect();
+            match driver.reserve(chunk.len(), now) {
+                Ok(mut encryptor) => lane.encrypt(&mut encryptor, chunk),
+                // no session to seal with yet, the timers are on it
+                Err(_) => packets.clear(),
+            }
+        }
+        while let Some(out) = lane.try_recv() {
+            send(driver, udp, out, now, rng)?;
+        }
+        while lane.in_flight() > DEPTH {
+            if let Some(out) = lane.recv() {
+                send(driver, udp, out, now, rng)?;
+            }
+        }
+    }
+}
+
+// peer to TUN: transport data is opened on the pipeline, handshakes are queued on the pool
+fn ingress(
+    driver: &Driver,
+    tun: &Tun,
+    udp: &UdpSocket,
+    pool: &HandshakePool,
+    mut lane: Lane,
+    rng: &mut impl CryptoRngCore,
+) -> io::Result<()> {
+    let mut buffers = vec![vec![0x00; MAX_PAYLOAD]; BATCH];
+    let mut out = vec![0x00; MAX_PAYLOAD];
+    // where the messages of each job in flight came from, in submission order
+    let mut sources: VecDeque<Vec<SocketAddr>> = VecDeque::new();
+    loop {
+        if lane.in_flight() > 0x00 && !readable(udp.inner())? {
+            let now = clock();
+            while let Some(opened) = lane.recv() {
+                deliver(
+                    driver,
+                    tun,
+                    opened,
+                    sources.pop_front().unwrap_or_default(),
+                    now,
+                );
+            }
+        }
+        let now = clock();
+        let mut data = Vec::new();
+        for (datagram, src) in udp.recv_batch(&mut buffers)? {
+            // unwrap rewrites the header, and the driver wants the datagram the way it came in
+            let mut msg = datagram.to_vec();
+            match driver
+                .tunnel()
+                .obfuscation()
+                .unwrap(&mut msg)
+                .map(|msg| msg[0x00])
+            {
+                Some(0x04) => match TransportData::wrap_ref(&msg)
+                    .and_then(|data| driver.tunnel().decryptor(data.r_i.get(), now))
+                {
+                    Ok(decryptor) => data.push((decryptor, msg, src)),
+                    Err(e) => driver.tunnel().device().transport_dropped(&e),
+                },
+                // a full queue is counted and logged by the pool
+                Some(0x01 | 0x02) => {
+                    let _ = pool.submit(datagram.to_vec(), src, now);
+                }
+                // cookie replies are cheap, and only ever answer an initiation of ours
+                Some(_) => {
+                    if let Ok(Action::Network(length)) =
+                        driver.decapsulate(datagram, src, now, rng, &mut out)
+                    {
+                        udp.send_batch(src, &[&out[0x00..length]])?;
+                    }
+                }
+                // junk, or somebody else's
+                None => {}
+            }
+        }
+        // consecutive messages for the same session share a job
+        let mut data = data.into_iter().peekable();
+        while let Some((decryptor, msg, src)) = data.next() {
+            let (mut msgs, mut srcs) = (vec![msg], vec![src]);
+            while msgs.len() < CHUNK {
+                let Some((_, msg, src)) = data.next_if(|(next, ..)| Arc::ptr_eq(next, &decryptor))
+                else {
+                    break;
+                };
+                msgs.push(msg);
+                srcs.push(src);
+            }
+            lane.decrypt(&decryptor, msgs);
+            sources.push_back(srcs);
+        }
+        while let Some(opened) = lane.try_recv() {
+            deliver(
+                driver,
+                tun,
+                opened,
+                sources.pop_front().unwrap_or_default(),
+                now,
+            );
+        }
+        while lane.in_flight() > DEPTH {
+            if let Some(opened) = lane.recv() {
+                deliver(
+                    driver,
+                    tun,
+                    opened,
+                    sources.pop_front().unwrap_or_default(),
+                    now,
+                );
+            }
+        }
+    }
+}
+
+// handshakes, their retries and keepalives
+fn timers(driver: &Driver, udp: &UdpSocket, rng: &mut impl CryptoRngCore) -> io::Result<()> {
+    let mut out = vec![0x00; MAX_PAYLOAD];
+    loop {
+        let now = clock();
+        while let Action::Network(length) =
+            driver.tick(now, rng, &mut out).map_err(io::Error::other)?
+        {
+            if let Some(endpoint) = driver.endpoint() {
+                udp.send_batch(endpoint, &[&out[0x00..length]])?;
+            }
+        }
+        thread::sleep(TICK);
+    }
+}
+
+fn send(
+    driver: &Driver,
+    udp: &UdpSocket,
+    out: Vec<Result<Vec<u8>>>,
+    now: Duration,
+    rng: &mut impl CryptoRngCore,
+) -> io::Result<()> {
+    let Some(endpoint) = driver.endpoint() else {
+        return Ok(());
+    };
+    let datagrams: Vec<_> = out
+        .into_iter()
+        .filter_map(|msg| msg.ok())
+        .map(|msg| driver.sealed(msg, now, rng))
+        .collect();
+    let datagrams: Vec<_> = datagrams.iter().map(Vec::as_slice).collect();
+    udp.send_batch(endpoint, &datagrams)
+}
+
+fn deliver(
+    driver: &Driver,
+    tun: &Tun,
+    opened: Vec<Result<Vec<u8>>>,
+    sources: Vec<SocketAddr>,
+    now: Duration,
+) {
+    for (msg, src) in opened.iter().zip(sources) {
+        match driver.opened(msg, src, now) {
+            // the kernel refusing a packet it cannot route is no reason to stop
+            Some(packet) if !packet.is_empty() => {
+                let _ = tun.send(packet);
+            }
+            _ => {}
+        }
+    }
+}
+
+// whether a read would return right away
+fn readable(fd: &impl AsRawFd) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0x00,
+    };
+    // SAFETY: pollfd outlives the call and the count matches
+    match unsafe { libc::poll(&mut pollfd, 0x01, 0x00) } {
+        n if n < 0x00 => Err(io::Error::last_os_error()),
+        n => Ok(n > 0x00),
+    }
+}
+
+fn clock() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
@@ -0,0 +1,390 @@
+use rand_core::CryptoRngCore;
+use std::{
+    collections::HashMap,
+    io,
+    mem::size_of,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use x25519::{PublicKey, StaticSecret};
+use zerocopy::FromZeroes;
+
+use crate::{
+    capture::Capture,
+    error::{Error, Result},
+    handshake::Checker,
+    packet::{CookieReply, HandshakeInit, HandshakeResp},
+    pool::MAX_QUEUED_HANDSHAKES,
+    psk::PskProvider,
+    ratelimiter::RateLimiter,
+    stats::{DeviceMetrics, PeerMetrics, Stats},
+    timestamp::{Record, TimestampStore},
+    trace::debug,
+};
+
+// flips into under load once the handshake queue gets deep, and stays there for a while after it drains
+pub struct LoadMonitor {
+    threshold: usize,                        // queue depth that counts as under load
+    linger: Duration,                        // how long under load outlasts a deep queue
+    state: Mutex<(usize, Option<Duration>)>, // last observed depth, and under load until
+}
+
+impl LoadMonitor {
+    pub fn new(threshold: usize, linger: Duration) -> Self {
+        Self {
+            threshold,
+            linger,
+            state: Mutex::new((0x00, None)),
+        }
+    }
+
+    pub fn observe(&self, depth: usize, now: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = depth;
+        if depth >= self.threshold {
+            if state.1.is_none_or(|until| now >= until) {
+                debug!(depth, "handshake queue under load");
+            }
+            state.1 = Some(now + self.linger);
+        }
+    }
+
+    pub fn under_load(&self, now: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        state.0 >= self.threshold || state.1.is_some_and(|until| now < until)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.state.lock().unwrap().0
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn linger(&self) -> Duration {
+        self.linger
+    }
+}
+
+impl Default for LoadMonitor {
+    // an eighth of the handshake queue, like the reference implementations
+    fn default() -> Self {
+        Self::new(MAX_QUEUED_HANDSHAKES / 0x08, Duration::from_secs(0x01))
+    }
+}
+
+pub enum Admission {
+    Accept,             // worth spending DH on
+    Reply(CookieReply), // send this back instead
+}
+
+pub struct Device {
+    secret: StaticSecret,
+    public: PublicKey,
+    checker: Checker,
+    load: LoadMonitor,
+    limiter: RateLimiter,
+    metrics: DeviceMetrics,
+    peers: Mutex<HashMap<[u8; 0x20], Arc<PeerMetrics>>>,
+    capture: Mutex<Option<Arc<Capture>>>,
+    provider: RwLock<Option<Arc<dyn PskProvider>>>,
+    records: Mutex<HashMap<[u8; 0x20], Record>>,
+    store: RwLock<Option<Arc<dyn TimestampStore>>>,
+}
+
+impl Device {
+    pub fn new(secret: StaticSecret, load: LoadMonitor, limiter: RateLimiter) -> Self {
+        let public = PublicKey::from(&secret);
+        Self {
+            checker: Checker::new(&public),
+            secret,
+            public,
+            load,
+            limiter,
+            metrics: DeviceMetrics::default(),
+            peers: Mutex::new(HashMap::new()),
+            capture: Mutex::new(None),
+            provider: RwLock::new(None),
+            records: Mutex::new(HashMap::new()),
+            store: RwLock::new(None),
+        }
+    }
+
+    // decides whether a handshake message gets processed, before any DH is spent on it
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err(level = "debug", Display), fields(endpoint = %src)))]
+    pub fn admit(
+        &self,
+        msg: &[u8],                   // whole handshake initiation or response
+        src: SocketAddr,              // address it came from
+        now: Duration,                // duration since UNIX epoch
+        rng: &mut impl CryptoRngCore, // source of cookie secrets and nonces
+    ) -> Result<Admission> {
+        let checked = self.check(msg, src, now);
+        if let Err(error) = &checked {
+            self.metrics.handshake_failed(error);
+        }
+        match checked {
+            Ok(()) => Ok(Admission::Accept),
+            // the message is dropped all the same, the reply lets the sender prove its address and try again
+            Err(error) if matches!(error.kind(), Error::CookieRequired) => {
+                let mut reply = CookieReply::new_zeroed();
+                self.checker
+                    .send_cookie_reply(msg, src, now, rng, &mut reply)?;
+                self.metrics.cookie_reply();
+                Ok(Admission::Reply(reply))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    // counters for the peer with this public key, created on first use
+    pub fn peer(&self, public: &PublicKey) -> Arc<PeerMetrics> {
+        self.peers
+            .lock()
+            .unwrap()
+            .entry(public.to_bytes())
+            .or_insert_with(|| Arc::new(PeerMetrics::new(*public)))
+            .clone()
+    }
+
+    // transport data that never reached a session, or did not open under one
+    pub fn transport_dropped(&self, error: &Error) {
+        self.metrics.transport_dropped(error);
+    }
+
+    pub fn remove_peer(&self, public: &PublicKey) {
+        self.peers.lock().unwrap().remove(public.as_bytes());
+    }
+
+    // point in time copy of every counter, peers sorted by public key
+    pub fn stats(&self, now: Duration) -> Stats {
+        let mut peers: Vec<_> = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|peer| peer.snapshot(now))
+            .collect();
+        peers.sort_by_key(|peer| peer.public.to_bytes());
+        self.metrics.snapshot(peers)
+    }
+
+    // starts or stops capturing, the packet path picks this up through capture()
+    pub fn set_capture(&self, capture: Option<Capture>) {
+        *self.capture.lock().unwrap() = capture.map(Arc::new);
+    }
+
+    pub fn capture(&self) -> Option<Arc<Capture>> {
+        self.capture.lock().unwrap().clone()
+    }
+
+    pub fn set_psk_provider(&self, provider: Option<Arc<dyn PskProvider>>) {
+        *self.provider.write().unwrap() = provider;
+    }
+
+    // what the provider says the preshared key for a handshake with peer starting now is
+    pub fn preshared_key(&self, peer: &PublicKey, now: Duration) -> Option<[u8; 0x20]> {
+        self.provider
+            .read()
+            .unwrap()
+            .as_ref()?
+            .preshared_key(peer, now)
+    }
+
+    // reads back what the store remembers, then keeps it up to date with every initiation answered
+    pub fn set_timestamp_store(&self, store: Option<Arc<dyn TimestampStore>>) -> io::Result<()> {
+        if let Some(store) = &store {
+            let mut records = self.records.lock().unwrap();
+            for (public, record) in store.load()? {
+                let entry = records.entry(public).or_insert(record);
+                if record.timestamp > entry.timestamp {
+                    *entry = record;
+                }
+            }
+        }
+        *self.store.write().unwrap() = store;
+        Ok(())
+    }
+
+    pub fn record(&self, peer: &PublicKey) -> Option<Record> {
+        self.records.lock().unwrap().get(peer.as_bytes()).copied()
+    }
+
+    // refuses an initiation unless its timestamp is newer than any seen from peer, and remembers it otherwise
+    pub fn accept_timestamp(
+        &self,
+        peer: &PublicKey,
+        timestamp: [u8; 0x0c], // TAI64N, big endian so byte order is time order
+        now: Duration,
+    ) -> Result<()> {
+        let record = Record {
+            timestamp,
+            handshake: now,
+        };
+        let mut records = self.records.lock().unwrap();
+        let last = records.get(peer.as_bytes());
+        if last.is_some_and(|last| timestamp <= last.timestamp) {
+            Err(Error::StaleTimestamp)?
+        }
+        records.insert(peer.to_bytes(), record);
+        // saved before the lock goes, so a newer record cannot be overtaken by an older one
+        if let Some(store) = self.store.read().unwrap().as_ref() {
+            // the handshake goes ahead regardless, only a restart before the next save loses anything
+            if let Err(_error) = store.save(peer, record) {
+                debug!(error = %_error, "failed to save timestamp record");
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, msg: &[u8], src: SocketAddr, now: Duration) -> Result<()> {
+        match msg.first() {
+            Some(0x01) if msg.len() == size_of::<HandshakeInit>() => {}
+            Some(0x02) if msg.len() == size_of::<HandshakeResp>() => {}
+            Some(0x01) | Some(0x02) => Err(Error::BufferLengthInvalid)?,
+            _ => Err(Error::WrongMessageType)?,
+        }
+        if !self.checker.check_mac1(msg) {
+            Err(Error::InvalidMac1)?
+        }
+        if !self.load.under_load(now) {
+            return Ok(());
+        }
+        if !self.checker.check_mac2(msg, src, now) {
+            Err(Error::CookieRequired)?
+        }
+        // responses answer an initiation of ours, so only initiations are worth limiting
+        if msg[0x00] == 0x01 && !self.limiter.allow(src.ip(), now) {
+            Err(Error::RateLimited)?
+        }
+        Ok(())
+    }
+
+    pub fn secret(&self) -> &StaticSecret {
+        &self.secret
+    }
+
+    pub fn public(&self) -> &PublicKey {
+        &self.public
+    }
+
+    pub fn checker(&self) -> &Checker {
+        &self.checker
+    }
+
+    pub fn load(&self) -> &LoadMonitor {
+        &self.load
+    }
+
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    pub fn metrics(&self) -> &DeviceMetrics {
+        &self.metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::ephemeral_from,
+        handshake::{Initiator, PeerKeys},
+        timestamp::FileStore,
+    };
+    use rand_core::{CryptoRng, RngCore};
+    use zerocopy::AsBytes;
+
+    // cookie secrets and nonces only need to be unpredictable outside of tests
+    struct Zero;
+
+    impl RngCore for Zero {
+        fn next_u32(&mut self) -> u32 {
+            0x00
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            0x00
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.fill(0x00);
+        }
+
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> core::result::Result<(), rand_core::Error> {
+            dest.fill(0x00);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for Zero {}
+
+    #[test]
+    fn under_load_without_cookie() {
+        // a threshold of nothing keeps the device under load
+        let device = Device::new(
+            StaticSecret::from([0x02; 0x20]),
+            LoadMonitor::new(0x00, Duration::from_secs(0x01)),
+            RateLimiter::default(),
+        );
+        let i_s = StaticSecret::from([0x01; 0x20]);
+        let i_k = PeerKeys::new(&i_s, device.public());
+        let now = Duration::from_secs(0x6553f100);
+        let mut msg = HandshakeInit::new_zeroed();
+        Initiator::send_handshake_init(
+            0x07,
+            &PublicKey::from(&i_s),
+            &i_k,
+            ephemeral_from([0x03; 0x20]),
+            now,
+            None,
+            &mut msg,
+        )
+        .unwrap();
+        let src = ([0xc0, 0x00, 0x02, 0x01], 0xca6c).into();
+        let admission = device.admit(msg.as_bytes(), src, now, &mut Zero).unwrap();
+        assert!(matches!(admission, Admission::Reply(_)));
+        let stats = device.stats(now);
+        assert_eq!(stats.cookie_replies, 0x01);
+        assert_eq!(stats.handshakes_failed.get("cookie_required"), Some(&0x01));
+    }
+
+    #[test]
+    fn timestamps_survive_restart() {
+        let path =
+            std::env::temp_dir().join(format!("shyvana-device-{}.timestamps", std::process::id()));
+        let peer = PublicKey::from([0x01; 0x20]);
+        let now = Duration::from_secs(0x6553f100);
+        // TAI64N, a second apart
+        let (mut old, mut new) = ([0x00; 0x0c], [0x00; 0x0c]);
+        old[0x07] = 0x01;
+        new[0x07] = 0x02;
+        let device = || {
+            let device = Device::new(
+                StaticSecret::from([0x02; 0x20]),
+                LoadMonitor::default(),
+                RateLimiter::default(),
+            );
+            let store = Arc::new(FileStore::new(&path));
+            device.set_timestamp_store(Some(store)).unwrap();
+            device
+        };
+        let first = device();
+        first.accept_timestamp(&peer, old, now).unwrap();
+        // the store goes with the device, and writes what it has on the way out
+        drop(first);
+        let second = device();
+        let err = second.accept_timestamp(&peer, old, now).unwrap_err();
+        assert!(matches!(err, Error::StaleTimestamp));
+        second.accept_timestamp(&peer, new, now).unwrap();
+        assert_eq!(second.record(&peer).unwrap().timestamp, new);
+        drop(second);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,647 @@
+use rand_core::CryptoRngCore;
+use std::{
+    collections::VecDeque,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+use zerocopy::{AsBytes, FromZeroes};
+
+use crate::{
+    cipher::{Decryptor, Encryptor},
+    crypto::ephemeral_from,
+    device::Admission,
+    error::{Error, Result},
+    handoff::PeerState,
+    handshake::{recv_cookie_reply, COOKIE_SECRET_LIFETIME},
+    keylog,
+    packet::{CookieReply, HandshakeInit, HandshakeResp},
+    stats::PeerMetrics,
+    trace::{debug, trace},
+    tunnel::{inner, Tunnel, REKEY_AFTER_TIME, REKEY_TIMEOUT},
+};
+
+// what a call left in out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Idle,           // nothing to send or deliver
+    Network(usize), // send out[..n] to the peer
+    Inner(usize),   // out[..n] is a decrypted packet for the local stack
+}
+
+// an initiation waiting for its response, with everything it was made from
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Pending {
+    pub(crate) at: Duration,            // when it went out, also its timestamp
+    pub(crate) m_1: [u8; 0x10],         // its mac1, which a cookie reply is bound to
+    pub(crate) i_i: u32,                // sender index
+    pub(crate) e_b: [u8; 0x20],         // bytes the ephemeral secret was made from
+    pub(crate) l_c: Option<[u8; 0x10]>, // cookie it went out with
+}
+
+struct State {
+    session: Option<Duration>, // when the current session was set up
+    pending: Option<Pending>,  // the last initiation, until it is answered
+    cookie: Option<([u8; 0x10], Duration)>, // latest cookie, and when it came in
+    sent: Duration,            // last time anything went to the peer
+    queue: VecDeque<Vec<u8>>,  // datagrams waiting for the next tick
+}
+
+// the timers and handshake bookkeeping around a tunnel, with no IO of its own
+// whoever owns the socket feeds it datagrams and sends what it hands back, all in caller buffers
+pub struct Driver {
+    tunnel: Arc<Tunnel>,
+    metrics: Arc<PeerMetrics>,
+    keepalive: Option<Duration>,          // persistent keepalive interval
+    endpoint: RwLock<Option<SocketAddr>>, // where the peer was last heard from, read without the state lock
+    handed_over: AtomicBool, // set by hand_over, the driver neither sends nor takes anything in after that
+    state: Mutex<State>,
+}
+
+impl Driver {
+    // without an endpoint the driver only answers, it starts handshakes once the peer has been heard from
+    pub fn new(
+        tunnel: Arc<Tunnel>,
+        endpoint: Option<SocketAddr>,
+        keepalive: Option<Duration>,
+    ) -> Self {
+        Self {
+            metrics: tunnel.device().peer(tunnel.peer_public()),
+            tunnel,
+            keepalive,
+            endpoint: RwLock::new(endpoint),
+            handed_over: AtomicBool::new(false),
+            state: Mutex::new(State {
+                session: None,
+                pending: None,
+                cookie: None,
+                sent: Duration::ZERO,
+                queue: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn tunnel(&self) -> &Arc<Tunnel> {
+        &self.tunnel
+    }
+
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        *self.endpoint.read().unwrap()
+    }
+
+    pub fn set_endpoint(&self, endpoint: Option<SocketAddr>) {
+        *self.endpoint.write().unwrap() = endpoint;
+    }
+
+    // roams to wherever authenticated data last came from, the write lock is only taken when that moved
+    fn roam(&self, src: SocketAddr) {
+        if self.endpoint() != Some(src) {
+            self.set_endpoint(Some(src));
+        }
+    }
+
+    // seals an inner packet for the peer, failing with SessionExpired until tick has set one up
+    pub fn encapsulate(
+        &self,
+        packet: &[u8],
+        now: Duration,
+        rng: &mut impl CryptoRngCore,
+        out: &mut [u8],
+    ) -> Result<Action> {
+        let msg = self.tunnel.encrypt(packet)?;
+        self.capture_inner(packet, now);
+        if let Some(endpoint) = self.endpoint() {
+            self.capture_outer(local(endpoint), endpoint, &msg, now);
+        }
+        let datagram = self.tunnel.obfuscation().wrap(msg, rng);
+        let action = emit(out, &datagram)?;
+        self.metrics.tx(datagram.len());
+        self.state.lock().unwrap().sent = now;
+        Ok(action)
+    }
+
+    // a counter range of the current session for sealing count packets off the driver, like on a pipeline
+    // what gets sealed with it goes through sealed on its way out
+    pub fn reserve(&self, count: usize, now: Duration) -> Result<Encryptor> {
+        let encryptor = self.tunnel.reserve(count as u64)?;
+        self.state.lock().unwrap().sent = now;
+        Ok(encryptor)
+    }
+
+    // disguises and counts a transport data message sealed with a range from reserve
+    pub fn sealed(&self, msg: Vec<u8>, now: Duration, rng: &mut impl CryptoRngCore) -> Vec<u8> {
+        if let Some(endpoint) = self.endpoint() {
+            self.capture_outer(local(endpoint), endpoint, &msg, now);
+        }
+        let datagram = self.tunnel.obfuscation().wrap(msg, rng);
+        self.metrics.tx(datagram.len());
+        datagram
+    }
+
+    // what decapsulate does with transport data once it is open, for messages opened off the driver
+    // msg is the whole message as Decryptor::decrypt left it, the inner packet comes back
+    pub fn opened<'a>(
+        &self,
+        msg: &'a Result<Vec<u8>>,
+        src: SocketAddr,
+        now: Duration,
+    ) -> Option<&'a [u8]> {
+        match msg {
+            Ok(msg) => {
+                self.metrics.rx(msg.len());
+                self.roam(src);
+                let packet = inner(msg);
+                if !packet.is_empty() {
+                    self.capture_inner(packet, now);
+                }
+                Some(packet)
+            }
+            Err(e) => {
+                self.dropped(e);
+                None
+            }
+        }
+    }
+
+    // handles one datagram from the peer, src is where it came from
+    pub fn decapsulate(
+        &self,
+        datagram: &mut [u8],
+        src: SocketAddr,
+        now: Duration,
+        rng: &mut impl CryptoRngCore,
+        out: &mut [u8],
+    ) -> Result<Action> {
+        if self.handed_over.load(Ordering::Relaxed) {
+            trace!(endpoint = %src, "dropped datagram for a handed over driver");
+            return Ok(Action::Idle);
+        }
+        let tunnel = &self.tunnel;
+        let device = tunnel.device();
+        let obfuscation = tunnel.obfuscation();
+        // junk, and anything disguised some other way
+        // a peer set up with other obfuscation values only ever shows up here
+        let Some(msg) = obfuscation.unwrap(datagram) else {
+            trace!(endpoint = %src, length = datagram.len(), "dropped datagram that does not unwrap");
+            return Ok(Action::Idle);
+        };
+        // before transport data is opened in place
+        self.capture_outer(src, local(src), msg, now);
+        // the handshake crypto runs without the state lock, which is only taken to install the result
+        match msg[0x00] {
+            0x01 => match device.admit(msg, src, now, rng)? {
+                Admission::Accept => {
+                    let mut resp = HandshakeResp::new_zeroed();
+                    let init = HandshakeInit::wrap_ref(msg)?;
+                    tunnel
+                        .recv_handshake_init(
+                            init,
+                            rng.next_u32(),
+                            keylog::ephemeral(rng),
+                            now,
+                            None,
+                            &mut resp,
+                        )
+                        .inspect_err(|e| self.metrics.handshake_failed(e))?;
+                    self.metrics.handshake_completed(now);
+                    self.roam(src);
+                    {
+                        let mut state = self.state.lock().unwrap();
+                        state.session = Some(now);
+                        state.sent = now;
+                    }
+                    self.capture_outer(local(src), src, resp.as_bytes(), now);
+                    emit(out, &obfuscation.wrap(resp.as_bytes().to_vec(), rng))
+                }
+                Admission::Reply(reply) => {
+                    self.capture_outer(local(src), src, reply.as_bytes(), now);
+                    emit(out, &obfuscation.wrap(reply.as_bytes().to_vec(), rng))
+                }
+            },
+            0x02 => match device.admit(msg, src, now, rng)? {
+                Admission::Accept => {
+                    let resp = HandshakeResp::wrap_ref(msg)?;
+                    tunnel
+                        .recv_handshake_resp(resp, now)
+                        .inspect_err(|e| self.metrics.handshake_failed(e))?;
+                    self.metrics.handshake_completed(now);
+                    self.roam(src);
+                    let mut state = self.state.lock().unwrap();
+                    state.session = Some(now);
+                    // a tick in the meantime may have started over, that initiation is still waiting
+                    if state
+                        .pending
+                        .is_some_and(|pending| pending.i_i == resp.r_i.get())
+                    {
+                        state.pending = None;
+                    }
+                    Ok(Action::Idle)
+                }
+                Admission::Reply(reply) => {
+                    self.capture_outer(local(src), src, reply.as_bytes(), now);
+                    emit(out, &obfuscation.wrap(reply.as_bytes().to_vec(), rng))
+                }
+            },
+            0x03 => {
+                let reply = CookieReply::wrap_ref(msg)?;
+                // only the mac1 is authenticated, the index has to be checked on its own
+                let pending = self.state.lock().unwrap().pending;
+                let (m_1, i_i) = match pending {
+                    Some(Pending { m_1, i_i, .. }) if reply.r_i.get() == i_i => (m_1, i_i),
+                    _ => Err(Error::UnknownReceiver)?,
+                };
+                let l_c = recv_cookie_reply(tunnel.peer_keys(), m_1, reply)?;
+                let mut state = self.state.lock().unwrap();
+                // go again at the next tick, this time with the cookie
+                state.cookie = Some((l_c, now));
+                if state.pending.is_some_and(|pending| pending.i_i == i_i) {
+                    state.pending = None;
+                }
+                Ok(Action::Idle)
+            }
+            0x04 => {
+                let length = msg.len();
+                let packet = tunnel.decrypt(msg, now).inspect_err(|e| self.dropped(e))?;
+                self.metrics.rx(length);
+                self.roam(src);
+                if packet.is_empty() {
+                    return Ok(Action::Idle);
+                }
+                self.capture_inner(packet, now);
+                emit(out, packet).map(|action| match action {
+                    Action::Network(length) => Action::Inner(length),
+                    action => action,
+                })
+            }
+            _ => Err(Error::WrongMessageType),
+        }
+    }
+
+    // starts and retries handshakes and sends keepalives, call it until it is idle
+    pub fn tick(
+        &self,
+        now: Duration,
+        rng: &mut impl CryptoRngCore,
+        out: &mut [u8],
+    ) -> Result<Action> {
+        if self.handed_over.load(Ordering::Relaxed) {
+            return Ok(Action::Idle);
+        }
+        let tunnel = &self.tunnel;
+        let endpoint = self.endpoint();
+        let mut state = self.state.lock().unwrap();
+        if let Some(datagram) = state.queue.pop_front() {
+            return emit(out, &datagram);
+        }
+        let Some(endpoint) = endpoint else {
+            return Ok(Action::Idle);
+        };
+        let stale = state
+            .session
+            .is_none_or(|at| now.saturating_sub(at) >= REKEY_AFTER_TIME);
+        let waiting = state
+            .pending
+            .is_some_and(|pending| now.saturating_sub(pending.at) < REKEY_TIMEOUT);
+        if stale && !waiting {
+            let obfuscation = tunnel.obfuscation();
+            let l_c = state
+                .cookie
+                .filter(|(_, at)| now.saturating_sub(*at) < COOKIE_SECRET_LIFETIME)
+                .map(|(l_c, _)| l_c);
+            let mut msg = HandshakeInit::new_zeroed();
+            let i_i = rng.next_u32();
+            // kept as bytes, so the initiation can be made again after a handoff
+            let mut e_b = [0x00; 0x20];
+            rng.fill_bytes(&mut e_b);
+            keylog::log(&[("LOCAL_EPHEMERAL_PRIVATE_KEY", &e_b)]);
+            tunnel.send_handshake_init(i_i, ephemeral_from(e_b), now, l_c, &mut msg)?;
+            self.metrics.handshake_initiated();
+            state.pending = Some(Pending {
+                at: now,
+                m_1: msg.m_1,
+                i_i,
+                e_b,
+                l_c,
+            });
+            state.sent = now;
+            let junk = obfuscation.junk(rng);
+            state.queue.extend(junk);
+            self.capture_outer(local(endpoint), endpoint, msg.as_bytes(), now);
+            state
+                .queue
+                .push_back(obfuscation.wrap(msg.as_bytes().to_vec(), rng));
+            let datagram = state.queue.pop_front().unwrap();
+            return emit(out, &datagram);
+        }
+        if let Some(keepalive) = self.keepalive.filter(|_| state.session.is_some()) {
+            if now.saturating_sub(state.sent) >= keepalive {
+                let Ok(datagram) = tunnel.encrypt(&[]) else {
+                    // used up, the next tick starts over
+                    self.metrics.session_expired();
+                    state.session = None;
+                    return Ok(Action::Idle);
+                };
+                state.sent = now;
+                self.capture_outer(local(endpoint), endpoint, &datagram, now);
+                return emit(out, &tunnel.obfuscation().wrap(datagram, rng));
+            }
+        }
+        Ok(Action::Idle)
+    }
+
+    // everything needed to carry on somewhere else, this side cannot send on or open anything for the sessions afterwards
+    // a datagram being opened on another thread right now can still slip past, so stop reading the socket first
+    pub(crate) fn hand_over(&self) -> PeerState {
+        self.handed_over.store(true, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        PeerState {
+            public: self.tunnel.peer_public().to_bytes(),
+            endpoint: self.endpoint(),
+            session: state.session,
+            sent: state.sent,
+            cookie: state.cookie,
+            pending: state.pending.take(),
+            encryptor: self.tunnel.hand_over(),
+            decryptors: self.tunnel.stop_receiving(),
+        }
+    }
+
+    // picks up where hand_over left off in another process
+    pub(crate) fn take_over(&self, peer: PeerState) {
+        let mut state = self.state.lock().unwrap();
+        state.pending = None;
+        if let Some(pending) = peer.pending {
+            // the same inputs make the same initiation, which puts the initiator back as it was
+            let mut msg = HandshakeInit::new_zeroed();
+            let made = self.tunnel.send_handshake_init(
+                pending.i_i,
+                ephemeral_from(pending.e_b),
+                pending.at,
+                pending.l_c,
+                &mut msg,
+            );
+            // unless the keys changed in between, then the next tick starts over
+            state.pending = (made.is_ok() && msg.m_1 == pending.m_1).then_some(pending);
+        }
+        self.tunnel.resume(
+            peer.encryptor,
+            peer.decryptors
+                .into_iter()
+                .map(|(r_i, key, window, born)| (Decryptor::resume(r_i, key, window), born))
+                .collect(),
+        );
+        if let Some(endpoint) = peer.endpoint {
+            self.set_endpoint(Some(endpoint));
+        }
+        state.session = peer.session;
+        state.sent = peer.sent;
+        state.cookie = peer.cookie;
+    }
+
+    // replays are held against the peer, anything else that did not open against the device
+    fn dropped(&self, error: &Error) {
+        match error.kind() {
+            Error::Replay => self.metrics.replay(),
+            _ => self.tunnel.device().transport_dropped(error),
+        }
+    }
+
+    // a message as it went over the wire, before it was disguised or after it was unwrapped
+    fn capture_outer(&self, src: SocketAddr, dst: SocketAddr, msg: &[u8], now: Duration) {
+        if let Some(capture) = self.tunnel.device().capture() {
+            // losing the capture is no reason to lose the packet
+            if let Err(_error) = capture.outer(src, dst, msg, now) {
+                debug!(error = %_error, "failed to capture datagram");
+            }
+        }
+    }
+
+    fn capture_inner(&self, packet: &[u8], now: Duration) {
+        if let Some(capture) = self.tunnel.device().capture() {
+            if let Err(_error) = capture.inner(packet, now) {
+                debug!(error = %_error, "failed to capture packet");
+            }
+        }
+    }
+}
+
+// the driver never sees its own socket, so this end of a captured datagram is the unspecified address
+fn local(peer: SocketAddr) -> SocketAddr {
+    match peer {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0x00).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0x00).into(),
+    }
+}
+
+fn emit(out: &mut [u8], datagram: &[u8]) -> Result<Action> {
+    let got = out.len();
+    out.get_mut(0x00..datagram.len())
+        .ok_or(Error::BufferLengthTooShort {
+            expected: datagram.len(),
+            got,
+        })?
+        .copy_from_slice(datagram);
+    Ok(Action::Network(datagram.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        capture::{read, Capture},
+        device::{Device, LoadMonitor},
+        ratelimiter::RateLimiter,
+    };
+    use rand_core::{CryptoRng, RngCore};
+    use x25519::StaticSecret;
+
+    // predictable, which is all a test needs
+    struct Counter(u64);
+
+    impl RngCore for Counter {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_mul(0x5851f42d4c957f2d).wrapping_add(0x01);
+            self.0 >> 0x10
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = self.next_u64() as u8;
+            }
+        }
+
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> core::result::Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for Counter {}
+
+    const SRC: SocketAddr = SocketAddr::new(
+        std::net::IpAddr::V4(Ipv4Addr::new(0xc0, 0x00, 0x02, 0x01)),
+        0xca6c,
+    );
+    const NOW: Duration = Duration::from_secs(0x6553f100);
+
+    // a starts a handshake with b and both end up with a session
+    fn pair(rng: &mut Counter) -> (Driver, Driver) {
+        let device = |secret| {
+            Arc::new(Device::new(
+                StaticSecret::from(secret),
+                LoadMonitor::default(),
+                RateLimiter::default(),
+            ))
+        };
+        let (a, b) = (device([0x01; 0x20]), device([0x02; 0x20]));
+        let (a_p, b_p) = (*a.public(), *b.public());
+        let a = Driver::new(Arc::new(Tunnel::new(a, b_p, [0x00; 0x20])), Some(SRC), None);
+        let b = Driver::new(Arc::new(Tunnel::new(b, a_p, [0x00; 0x20])), None, None);
+        let (mut out, mut back) = (vec![0x00; 0x0800], vec![0x00; 0x0800]);
+        let Action::Network(length) = a.tick(NOW, rng, &mut out).unwrap() else {
+            panic!("no initiation")
+        };
+        let Action::Network(length) = b
+            .decapsulate(&mut out[0x00..length], SRC, NOW, rng, &mut back)
+            .unwrap()
+        else {
+            panic!("no response")
+        };
+        a.decapsulate(&mut back[0x00..length], SRC, NOW, rng, &mut out)
+            .unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn replays_are_counted() {
+        let mut rng = Counter(0x2a);
+        let (a, b) = pair(&mut rng);
+        let (mut out, mut back) = (vec![0x00; 0x0800], vec![0x00; 0x0800]);
+        let Action::Network(length) = a.encapsulate(&[], NOW, &mut rng, &mut out).unwrap() else {
+            panic!("no keepalive")
+        };
+        let msg = out[0x00..length].to_vec();
+        let mut open =
+            |rng: &mut Counter| b.decapsulate(&mut msg.clone(), SRC, NOW, rng, &mut back);
+        assert_eq!(open(&mut rng).unwrap(), Action::Idle);
+        let err = open(&mut rng).unwrap_err();
+        assert!(matches!(err.kind(), Error::Replay));
+        let stats = b.tunnel().device().stats(NOW);
+        assert_eq!(stats.peers[0x00].replay_drops, 0x01);
+    }
+
+    #[test]
+    fn undecryptable_is_counted() {
+        let mut rng = Counter(0x2a);
+        let (a, b) = pair(&mut rng);
+        let (mut out, mut back) = (vec![0x00; 0x0800], vec![0x00; 0x0800]);
+        let Action::Network(length) = a.encapsulate(&[], NOW, &mut rng, &mut out).unwrap() else {
+            panic!("no keepalive")
+        };
+        let mut forged = out[0x00..length].to_vec();
+        forged[0x10] ^= 0x01;
+        assert!(b
+            .decapsulate(&mut forged, SRC, NOW, &mut rng, &mut back)
+            .is_err());
+        let mut stray = out[0x00..length].to_vec();
+        stray[0x04] ^= 0x01;
+        let err = b
+            .decapsulate(&mut stray, SRC, NOW, &mut rng, &mut back)
+            .unwrap_err();
+        assert!(matches!(err.kind(), Error::UnknownReceiver));
+        let stats = b.tunnel().device().stats(NOW);
+        assert_eq!(stats.transport_dropped["aead_error"], 0x01);
+        assert_eq!(stats.transport_dropped["unknown_receiver"], 0x01);
+        assert_eq!(stats.peers[0x00].replay_drops, 0x00);
+        assert!(stats
+            .to_prometheus()
+            .contains("shyvana_transport_dropped_total{reason=\"aead_error\"} 1"));
+    }
+
+    #[test]
+    fn cookie_reply_for_the_pending_initiation() {
+        let mut rng = Counter(0x2a);
+        let device = |secret, load| {
+            Arc::new(Device::new(
+                StaticSecret::from(secret),
+                load,
+                RateLimiter::default(),
+            ))
+        };
+        let a = device([0x01; 0x20], LoadMonitor::default());
+        // a threshold of nothing keeps b under load
+        let b = device(
+            [0x02; 0x20],
+            LoadMonitor::new(0x00, Duration::from_secs(0x01)),
+        );
+        let (a_p, b_p) = (*a.public(), *b.public());
+        let a = Driver::new(Arc::new(Tunnel::new(a, b_p, [0x00; 0x20])), Some(SRC), None);
+        let b = Driver::new(Arc::new(Tunnel::new(b, a_p, [0x00; 0x20])), None, None);
+        let (mut out, mut back) = (vec![0x00; 0x0800], vec![0x00; 0x0800]);
+        let Action::Network(length) = a.tick(NOW, &mut rng, &mut out).unwrap() else {
+            panic!("no initiation")
+        };
+        let Action::Network(length) = b
+            .decapsulate(&mut out[0x00..length], SRC, NOW, &mut rng, &mut back)
+            .unwrap()
+        else {
+            panic!("no cookie reply")
+        };
+        let mut reply = back[0x00..length].to_vec();
+        let mut other = reply.clone();
+        other[0x04] ^= 0x01;
+        let err = a
+            .decapsulate(&mut other, SRC, NOW, &mut rng, &mut out)
+            .unwrap_err();
+        assert!(matches!(err.kind(), Error::UnknownReceiver));
+        // the initiation is still waiting, so nothing goes out again yet
+        assert_eq!(a.tick(NOW, &mut rng, &mut out).unwrap(), Action::Idle);
+        let action = a.decapsulate(&mut reply, SRC, NOW, &mut rng, &mut out);
+        assert_eq!(action.unwrap(), Action::Idle);
+        assert!(matches!(
+            a.tick(NOW, &mut rng, &mut out).unwrap(),
+            Action::Network(_)
+        ));
+    }
+
+    #[test]
+    fn captures_both_layers() {
+        let mut rng = Counter(0x2a);
+        let (a, b) = pair(&mut rng);
+        let path =
+            std::env::temp_dir().join(format!("shyvana-driver-{}.pcapng", std::process::id()));
+        b.tunnel()
+            .device()
+            .set_capture(Some(Capture::create(&path, u64::MAX, 0x00).unwrap()));
+        let (mut out, mut back) = (vec![0x00; 0x0800], vec![0x00; 0x0800]);
+        // the smallest IPv4 header, so the padding comes off again
+        let mut packet = [0x00; 0x14];
+        packet[0x00] = 0x45;
+        packet[0x03] = 0x14;
+        let Action::Network(length) = a.encapsulate(&packet, NOW, &mut rng, &mut out).unwrap()
+        else {
+            panic!("no transport data")
+        };
+        let msg = out[0x00..length].to_vec();
+        let action = b.decapsulate(&mut out[0x00..length], SRC, NOW, &mut rng, &mut back);
+        assert_eq!(action.unwrap(), Action::Inner(0x14));
+        b.tunnel().device().capture().unwrap().flush().unwrap();
+        let packets = read(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(packets.len(), 0x02);
+        // the message as it came in, behind made up IPv4 and UDP headers
+        assert_eq!(packets[0x00].0, 0x00);
+        assert_eq!(packets[0x00].1[0x1c..], msg);
+        assert_eq!(packets[0x01], (0x01, packet.to_vec()));
+    }
+}
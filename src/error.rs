@@ -1,10 +1,115 @@
-use std::fmt::{Debug, Display, Formatter};
+use std::fmt::{Display, Formatter};
+use x25519::PublicKey;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Malicious,             // someone is forging, replaying or probing
+    BenignDrop,            // normal noise, drop it and move on
+    LocalMisconfiguration, // something is wrong on our side
+    NeedsRekey,            // the session is used up, start a new handshake
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Key(PublicKey), // static public key of the peer
+    Index(u32),     // receiver index of the session
+}
 
 #[derive(Debug)]
 pub enum Error {
     BufferLengthTooShort { expected: usize, got: usize },
     AeadError,
     BufferLengthInvalid,
+    CounterExhausted,
+    InvalidMac1,
+    CookieRequired,
+    Replay,
+    UnknownReceiver,
+    UnknownPeer,
+    StaleTimestamp,
+    WrongMessageType,
+    SessionExpired,
+    RateLimited,
+    QueueFull,
+    InvalidObfuscation,
+    InvalidHandoff,
+    InvalidKey,
+    Peer { peer: Peer, error: Box<Error> },
+}
+
+impl Error {
+    // attaches the peer the error happened with
+    pub fn with_peer(self, peer: Peer) -> Self {
+        match self {
+            Self::Peer { error, .. } => Self::Peer { peer, error },
+            error => Self::Peer {
+                peer,
+                error: Box::new(error),
+            },
+        }
+    }
+
+    pub fn peer(&self) -> Option<&Peer> {
+        match self {
+            Self::Peer { peer, .. } => Some(peer),
+            _ => None,
+        }
+    }
+
+    // the error without any peer attached
+    pub fn kind(&self) -> &Self {
+        match self {
+            Self::Peer { error, .. } => error.kind(),
+            error => error,
+        }
+    }
+
+    // short snake case name, stable enough to use as a metric label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::BufferLengthTooShort { .. } => "buffer_length_too_short",
+            Self::AeadError => "aead_error",
+            Self::BufferLengthInvalid => "buffer_length_invalid",
+            Self::CounterExhausted => "counter_exhausted",
+            Self::InvalidMac1 => "invalid_mac1",
+            Self::CookieRequired => "cookie_required",
+            Self::Replay => "replay",
+            Self::UnknownReceiver => "unknown_receiver",
+            Self::UnknownPeer => "unknown_peer",
+            Self::StaleTimestamp => "stale_timestamp",
+            Self::WrongMessageType => "wrong_message_type",
+            Self::SessionExpired => "session_expired",
+            Self::RateLimited => "rate_limited",
+            Self::QueueFull => "queue_full",
+            Self::InvalidObfuscation => "invalid_obfuscation",
+            Self::InvalidHandoff => "invalid_handoff",
+            Self::InvalidKey => "invalid_key",
+            Self::Peer { error, .. } => error.reason(),
+        }
+    }
+
+    pub fn category(&self) -> Category {
+        match self {
+            Self::BufferLengthTooShort { .. } => Category::BenignDrop,
+            Self::AeadError => Category::Malicious,
+            Self::BufferLengthInvalid => Category::BenignDrop,
+            Self::CounterExhausted => Category::NeedsRekey,
+            Self::InvalidMac1 => Category::Malicious,
+            Self::CookieRequired => Category::BenignDrop,
+            Self::Replay => Category::Malicious,
+            Self::UnknownReceiver => Category::BenignDrop,
+            Self::UnknownPeer => Category::LocalMisconfiguration,
+            Self::StaleTimestamp => Category::Malicious,
+            Self::WrongMessageType => Category::Malicious,
+            Self::SessionExpired => Category::NeedsRekey,
+            Self::RateLimited => Category::BenignDrop,
+            Self::QueueFull => Category::BenignDrop,
+            Self::InvalidObfuscation => Category::LocalMisconfiguration,
+            Self::InvalidHandoff => Category::LocalMisconfiguration,
+            Self::InvalidKey => Category::Malicious,
+            Self::Peer { error, .. } => error.category(),
+        }
+    }
 }
 
 impl From<chacha20poly1305::Error> for Error {
@@ -13,9 +118,62 @@ impl From<chacha20poly1305::Error> for Error {
     }
 }
 
+impl Display for Category {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::Malicious => "malicious",
+            Self::BenignDrop => "benign drop",
+            Self::LocalMisconfiguration => "local misconfiguration",
+            Self::NeedsRekey => "needs rekey",
+        })
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Key(key) => {
+                f.write_str("peer ")?;
+                key.as_bytes().iter().try_for_each(|b| write!(f, "{b:02x}"))
+            }
+            Self::Index(index) => write!(f, "session {index:#010x}"),
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        Debug::fmt(&self, f)
+        match self {
+            Self::BufferLengthTooShort { expected, got } => {
+                write!(
+                    f,
+                    "buffer too short, expected {expected} bytes but got {got}"
+                )
+            }
+            Self::AeadError => f.write_str("authenticated decryption failed"),
+            Self::BufferLengthInvalid => f.write_str("buffer length is invalid for this message"),
+            Self::CounterExhausted => f.write_str("sending counter exhausted"),
+            Self::InvalidMac1 => f.write_str("invalid mac1"),
+            Self::CookieRequired => f.write_str("invalid mac2, cookie required"),
+            Self::Replay => f.write_str("replayed transport counter"),
+            Self::UnknownReceiver => f.write_str("unknown receiver index"),
+            Self::UnknownPeer => f.write_str("unknown peer"),
+            Self::StaleTimestamp => {
+                f.write_str("handshake timestamp is not newer than the last one")
+            }
+            Self::WrongMessageType => f.write_str("wrong message type"),
+            Self::SessionExpired => f.write_str("session expired"),
+            Self::RateLimited => f.write_str("handshake rate limited"),
+            Self::QueueFull => f.write_str("handshake queue full"),
+            Self::InvalidObfuscation => {
+                f.write_str("obfuscation headers collide or padding does not fit")
+            }
+            Self::InvalidHandoff => {
+                f.write_str("handoff state is malformed, from another version or another device")
+            }
+            Self::InvalidKey => f.write_str("key exchange message is malformed"),
+            Self::Peer { peer, error } => write!(f, "{peer}: {error}"),
+        }
     }
 }
 
@@ -0,0 +1,451 @@
+// the C API, cbindgen turns this file into include/shyvana.h, see build.rs
+// everything below is sans-IO: the caller owns the socket, the clock it ticks on and every buffer
+
+use rand_core::OsRng;
+use std::{
+    ffi::{c_char, c_void, CString},
+    fmt::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ptr, slice,
+    sync::{Arc, OnceLock, RwLock},
+    time::Duration,
+};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Event, Level, Metadata, Subscriber,
+};
+use x25519::{PublicKey, StaticSecret};
+
+use crate::{
+    device::{Device, LoadMonitor},
+    driver::{Action, Driver},
+    error::Result,
+    ratelimiter::RateLimiter,
+    trace::debug,
+    tunnel::Tunnel,
+};
+
+/// A tunnel to one peer, created with `shyvana_tunnel_new` and freed with `shyvana_tunnel_free`.
+///
+/// Calls that need the time take `now_ms`, milliseconds since the UNIX epoch on the caller's
+/// clock. It goes into handshake timestamps, so it has to keep going forward across restarts.
+pub struct ShyvanaTunnel {
+    driver: Driver,
+}
+
+/// What a call left in the destination buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShyvanaOp {
+    /// Nothing to do.
+    Idle,
+    /// Send the first `size` bytes to the peer.
+    WriteToNetwork,
+    /// The first `size` bytes are a decrypted IP packet for the local stack.
+    WriteToTunnel,
+    /// The call failed, the reason went to the log callback.
+    Error,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ShyvanaResult {
+    pub op: ShyvanaOp,
+    pub size: usize,
+}
+
+impl ShyvanaResult {
+    const ERROR: Self = Self {
+        op: ShyvanaOp::Error,
+        size: 0x00,
+    };
+}
+
+/// An IPv4 or IPv6 address and port, IPv4 addresses take up the first four bytes.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ShyvanaEndpoint {
+    /// 4 or 6.
+    pub family: u8,
+    pub address: [u8; 0x10],
+    pub port: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShyvanaStats {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub handshakes_initiated: u64,
+    pub handshakes_completed: u64,
+    pub replay_drops: u64,
+    /// Milliseconds since the UNIX epoch of the last completed handshake, 0 if there was none.
+    pub last_handshake_ms: u64,
+    /// 1 if there is a session and 0 if not, and how old it is.
+    pub session: u8,
+    pub session_age_ms: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShyvanaLogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// Gets one log line at a time, the line is only valid for the duration of the call.
+pub type ShyvanaLogCallback =
+    Option<extern "C" fn(level: ShyvanaLogLevel, line: *const c_char, user_data: *mut c_void)>;
+
+// the callback and its user data, kept as an address so it can be shared between threads
+static LOG: RwLock<(ShyvanaLogCallback, usize)> = RwLock::new((None, 0x00));
+
+// whether the forwarding subscriber could be installed, there is only the one global slot
+static INSTALLED: OnceLock<bool> = OnceLock::new();
+
+/// Creates a tunnel to one peer, or returns null if a key is missing.
+///
+/// Without an endpoint the tunnel only answers handshakes, it starts its own once the peer has
+/// been heard from. A keepalive of 0 turns persistent keepalives off.
+///
+/// # Safety
+///
+/// `private_key` and `peer_public` must point to 32 bytes each, `preshared_key` to 32 bytes or be
+/// null, and `endpoint` to an endpoint or be null.
+#[no_mangle]
+pub unsafe extern "C" fn shyvana_tunnel_new(
+    private_key: *const u8,
+    peer_public: *const u8,
+    preshared_key: *const u8,
+    endpoint: *const ShyvanaEndpoint,
+    keepalive: u16,
+) -> *mut ShyvanaTunnel {
+    let (Some(private_key), Some(peer_public)) = (key(private_key), key(peer_public)) else {
+        return ptr::null_mut();
+    };
+    let preshared_key = key(preshared_key).unwrap_or([0x00; 0x20]);
+    let device = Arc::new(Device::new(
+        StaticSecret::from(private_key),
+        LoadMonitor::default(),
+        RateLimiter::default(),
+    ));
+    let tunnel = Arc::new(Tunnel::new(
+        device,
+        PublicKey::from(peer_public),
+        preshared_key,
+    ));
+    let endpoint = endpoint.as_ref().and_then(address);
+    let keepalive = (keepalive != 0x00).then(|| Duration::from_secs(keepalive as u64));
+    Box::into_raw(Box::new(ShyvanaTunnel {
+        driver: Driver::new(tunnel, endpoint, keepalive),
+    }))
+}
+
+/// Frees a tunnel, null is ignored.
+///
+/// # Safety
+///
+/// `tunnel` must come from `shyvana_tunnel_new` and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn shyvana_tunnel_free(tunnel: *mut ShyvanaTunnel) {
+    if !tunnel.is_null() {
+        drop(Box::from_raw(tunnel));
+    }
+}
+
+/// Seals an IP packet for the peer. Fails until `shyvana_tick` has set up a session.
+///
+/// # Safety
+///
+/// `tunnel` must be null or live, from `shyvana_tunnel_new` and not freed yet. `src` must be
+/// readable for `src_size` bytes and `dst` writable for `dst_size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn shyvana_encapsulate(
+    tunnel: *const ShyvanaTunnel,
+    now_ms: u64,
+    src: *const u8,
+    src_size: usize,
+    dst: *mut u8,
+    dst_size: usize,
+) -> ShyvanaResult {
+    let Some(driver) = driver("encapsulate", tunnel) else {
+        return ShyvanaResult::ERROR;
+    };
+    let packet = bytes(src, src_size);
+    let out = bytes_mut(dst, dst_size);
+    result(
+        "encapsulate",
+        driver.encapsulate(packet, Duration::from_millis(now_ms), &mut OsRng, out),
+    )
+}
+
+/// Handles one datagram from the peer, `from` is where it came from or null if unknown.
+///
+/// # Safety
+///
+/// `tunnel` must be null or live, from `shyvana_tunnel_new` and not freed yet. `src` must be
+/// readable for `src_size` bytes, `dst` writable for `dst_size` bytes and `from` point to an
+/// endpoint or be null.
+#[no_mangle]
+pub unsafe extern "C" fn shyvana_decapsulate(
+    tunnel: *const ShyvanaTunnel,
+    now_ms: u64,
+    from: *const ShyvanaEndpoint,
+    src: *const u8,
+    src_size: usize,
+    dst: *mut u8,
+    dst_size: usize,
+) -> ShyvanaResult {
+    let Some(driver) = driver("decapsulate", tunnel) else {
+        return ShyvanaResult::ERROR;
+    };
+    let from = from
+        .as_ref()
+        .and_then(address)
+        .unwrap_or(SocketAddr::from(([0x00; 0x04], 0x00)));
+    // messages are opened in place, the caller's datagram stays as it was
+    let mut datagram = bytes(src, src_size).to_vec();
+    let out = bytes_mut(dst, dst_size);
+    result(
+        "decapsulate",
+        driver.decapsulate(
+            &mut datagram,
+            from,
+            Duration::from_millis(now_ms),
+            &mut OsRng,
+            out,
+        ),
+    )
+}
+
+/// Starts and retries handshakes and sends keepalives. Call it every 100ms or so, and again
+/// straight away for as long as it asks for a network write.
+///
+/// # Safety
+///
+/// `tunnel` must be null or live, from `shyvana_tunnel_new` and not freed yet. `dst` must be
+/// writable for `dst_size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn shyvana_tick(
+    tunnel: *const ShyvanaTunnel,
+    now_ms: u64,
+    dst: *mut u8,
+    dst_size: usize,
+) -> ShyvanaResult {
+    let Some(driver) = driver("tick", tunnel) else {
+        return ShyvanaResult::ERROR;
+    };
+    let out = bytes_mut(dst, dst_size);
+    result(
+        "tick",
+        driver.tick(Duration::from_millis(now_ms), &mut OsRng, out),
+    )
+}
+
+/// Where network writes go, false if the peer has no endpoint yet or a pointer is null.
+///
+/// # Safety
+///
+/// `tunnel` must be null or live, from `shyvana_tunnel_new` and not freed yet. `endpoint` must be
+/// null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn shyvana_endpoint(
+    tunnel: *const ShyvanaTunnel,
+    endpoint: *mut ShyvanaEndpoint,
+) -> bool {
+    let (Some(driver), Some(endpoint)) = (driver("endpoint", tunnel), endpoint.as_mut()) else {
+        return false;
+    };
+    let Some(address) = driver.endpoint() else {
+        return false;
+    };
+    let (family, octets) = match address.ip() {
+        IpAddr::V4(ip) => {
+            let mut octets = [0x00; 0x10];
+            octets[0x00..0x04].copy_from_slice(&ip.octets());
+            (0x04, octets)
+        }
+        IpAddr::V6(ip) => (0x06, ip.octets()),
+    };
+    *endpoint = ShyvanaEndpoint {
+        family,
+        address: octets,
+        port: address.port(),
+    };
+    true
+}
+
+/// Counters for the peer, false if a pointer is null and nothing was written.
+///
+/// # Safety
+///
+/// `tunnel` must be null or live, from `shyvana_tunnel_new` and not freed yet. `stats` must be
+/// null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn shyvana_stats(
+    tunnel: *const ShyvanaTunnel,
+    now_ms: u64,
+    stats: *mut ShyvanaStats,
+) -> bool {
+    let (Some(driver), Some(stats)) = (driver("stats", tunnel), stats.as_mut()) else {
+        return false;
+    };
+    let tunnel = driver.tunnel();
+    let now = Duration::from_millis(now_ms);
+    let peer = tunnel.device().peer(tunnel.peer_public()).snapshot(now);
+    *stats = ShyvanaStats {
+        rx_bytes: peer.rx_bytes,
+        rx_packets: peer.rx_packets,
+        tx_bytes: peer.tx_bytes,
+        tx_packets: peer.tx_packets,
+        handshakes_initiated: peer.handshakes_initiated,
+        handshakes_completed: peer.handshakes_completed,
+        replay_drops: peer.replay_drops,
+        last_handshake_ms: peer.last_handshake.map_or(0x00, |at| at.as_millis() as u64),
+        session: peer.session_age.is_some() as u8,
+        session_age_ms: peer.session_age.map_or(0x00, |age| age.as_millis() as u64),
+    };
+    true
+}
+
+/// Sends log lines to `callback` from now on, null turns logging off. Lines only ever carry
+/// indices, endpoints, key fingerprints and reasons, never key material.
+///
+/// Returns false if the process already has a tracing subscriber and the lines go there instead.
+///
+/// # Safety
+///
+/// `callback` must stay callable with `user_data`, from any thread, until it is replaced.
+#[no_mangle]
+pub unsafe extern "C" fn shyvana_set_log_callback(
+    callback: ShyvanaLogCallback,
+    user_data: *mut c_void,
+) -> bool {
+    *LOG.write().unwrap() = (callback, user_data as usize);
+    *INSTALLED.get_or_init(|| tracing::subscriber::set_global_default(Forward).is_ok())
+}
+
+// hands tracing events to the log callback, spans are not kept
+struct Forward;
+
+impl Subscriber for Forward {
+    // asked again every time, the callback can come and go
+    fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, _: &Metadata) -> bool {
+        LOG.read().unwrap().0.is_some()
+    }
+
+    fn new_span(&self, _: &Attributes) -> Id {
+        Id::from_u64(0x01)
+    }
+
+    fn record(&self, _: &Id, _: &Record) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event) {
+        let (Some(callback), user_data) = *LOG.read().unwrap() else {
+            return;
+        };
+        let mut line = Line::default();
+        event.record(&mut line);
+        let level = match *event.metadata().level() {
+            Level::ERROR => ShyvanaLogLevel::Error,
+            Level::WARN => ShyvanaLogLevel::Warn,
+            Level::INFO => ShyvanaLogLevel::Info,
+            Level::DEBUG => ShyvanaLogLevel::Debug,
+            Level::TRACE => ShyvanaLogLevel::Trace,
+        };
+        let line = CString::new(format!("{}{}", line.message, line.fields).replace('\0', ""))
+            .unwrap_or_default();
+        callback(level, line.as_ptr(), user_data as *mut c_void);
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+// an event as one line, the message and then its fields as key=value
+#[derive(Default)]
+struct Line {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Line {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{value}"));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.message, "{value:?}"),
+            name => write!(self.fields, " {name}={value:?}"),
+        };
+    }
+}
+
+fn result(call: &str, action: Result<Action>) -> ShyvanaResult {
+    let (op, size) = match action {
+        Ok(Action::Idle) => (ShyvanaOp::Idle, 0x00),
+        Ok(Action::Network(size)) => (ShyvanaOp::WriteToNetwork, size),
+        Ok(Action::Inner(size)) => (ShyvanaOp::WriteToTunnel, size),
+        Err(error) => {
+            debug!(reason = error.reason(), "{call} failed: {error}");
+            (ShyvanaOp::Error, 0x00)
+        }
+    };
+    ShyvanaResult { op, size }
+}
+
+// a null tunnel is the caller's mistake, so it is logged and the call fails
+unsafe fn driver<'a>(call: &str, tunnel: *const ShyvanaTunnel) -> Option<&'a Driver> {
+    let driver = tunnel.as_ref().map(|tunnel| &tunnel.driver);
+    if driver.is_none() {
+        debug!(reason = "null tunnel", "{call} failed: no tunnel");
+    }
+    driver
+}
+
+unsafe fn key(key: *const u8) -> Option<[u8; 0x20]> {
+    (!key.is_null()).then(|| *(key as *const [u8; 0x20]))
+}
+
+unsafe fn bytes<'a>(src: *const u8, size: usize) -> &'a [u8] {
+    match src.is_null() {
+        true => &[],
+        false => slice::from_raw_parts(src, size),
+    }
+}
+
+unsafe fn bytes_mut<'a>(dst: *mut u8, size: usize) -> &'a mut [u8] {
+    match dst.is_null() {
+        true => &mut [],
+        false => slice::from_raw_parts_mut(dst, size),
+    }
+}
+
+fn address(endpoint: &ShyvanaEndpoint) -> Option<SocketAddr> {
+    let ip = match endpoint.family {
+        0x04 => IpAddr::V4(Ipv4Addr::new(
+            endpoint.address[0x00],
+            endpoint.address[0x01],
+            endpoint.address[0x02],
+            endpoint.address[0x03],
+        )),
+        0x06 => IpAddr::V6(Ipv6Addr::from(endpoint.address)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, endpoint.port))
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{self, SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    netstack::{NetStack, UdpSocket},
+    proxy::pipe,
+    trace::{debug, warn},
+};
+
+// how long a connection through the tunnel gets to come up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(0x0a);
+
+// a UDP flow that hears nothing back for this long is forgotten
+const UDP_IDLE: Duration = Duration::from_secs(0x3c);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+// one static rule, ssh style
+#[derive(Clone, Copy)]
+pub enum Forward {
+    // -L, listens on this machine and dials target through the tunnel
+    Local {
+        protocol: Protocol,
+        listen: SocketAddr,
+        target: SocketAddr,
+    },
+    // -R, listens on port at this side's tunnel addresses and dials target from this machine
+    Remote {
+        protocol: Protocol,
+        port: u16,
+        target: SocketAddr,
+    },
+}
+
+impl Forward {
+    // carries flows for this rule until its listener fails, logging each one tagged with the rule
+    pub fn serve(self, stack: Arc<NetStack>) -> io::Result<()> {
+        match self {
+            Self::Local {
+                protocol: Protocol::Tcp,
+                listen,
+                target,
+            } => self.local_tcp(stack, TcpListener::bind(listen)?, target),
+            Self::Remote {
+                protocol: Protocol::Tcp,
+                port,
+                target,
+            } => self.remote_tcp(stack, port, target),
+            Self::Local {
+                protocol: Protocol::Udp,
+                listen,
+                target,
+            } => self.local_udp(stack, listen, target),
+            Self::Remote {
+                protocol: Protocol::Udp,
+                port,
+                target,
+            } => self.remote_udp(stack, port, target),
+        }
+    }
+
+    fn local_tcp(
+        self,
+        stack: Arc<NetStack>,
+        listener: TcpListener,
+        target: SocketAddr,
+    ) -> io::Result<()> {
+        debug!(rule = %self, "listening");
+        for client in listener.incoming() {
+            let client = client?;
+            let stack = stack.clone();
+            thread::spawn(move || {
+                let _src = client.peer_addr().ok();
+                match stack.connect_timeout(target, CONNECT_TIMEOUT) {
+                    Ok(stream) => {
+                        debug!(rule = %self, src = %or_unknown(_src), "connected");
+                        let (_up, _down) = match client.try_clone() {
+                            Ok(reader) => pipe(reader, client, stream),
+                            Err(_) => (0x00, 0x00),
+                        };
+                        debug!(rule = %self, src = %or_unknown(_src), out = _up, back = _down, "closed");
+                    }
+                    Err(_error) => {
+                        warn!(rule = %self, src = %or_unknown(_src), error = %_error, "refused");
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn remote_tcp(self, stack: Arc<NetStack>, port: u16, target: SocketAddr) -> io::Result<()> {
+        debug!(rule = %self, "listening");
+        loop {
+            let stream = stack.accept(port)?;
+            thread::spawn(move || {
+                let _src = stream.peer_addr();
+                match net::TcpStream::connect_timeout(&target, CONNECT_TIMEOUT) {
+                    Ok(client) => {
+                        debug!(rule = %self, src = %or_unknown(_src), "connected");
+                        let (_up, _down) = match client.try_clone() {
+                            Ok(reader) => pipe(reader, client, stream),
+                            Err(_) => (0x00, 0x00),
+                        };
+                        debug!(rule = %self, src = %or_unknown(_src), out = _down, back = _up, "closed");
+                    }
+                    Err(_error) => {
+                        warn!(rule = %self, src = %or_unknown(_src), error = %_error, "refused");
+                    }
+                }
+            });
+        }
+    }
+
+    // a tunnel side socket per client address, so replies find their way back
+    fn local_udp(
+        self,
+        stack: Arc<NetStack>,
+        listen: SocketAddr,
+        target: SocketAddr,
+    ) -> io::Result<()> {
+        let socket = Arc::new(net::UdpSocket::bind(listen)?);
+        let flows: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>> = Arc::default();
+        debug!(rule = %self, "listening");
+        let mut buffer = vec![0x00; 0x10000];
+        loop {
+            let (length, src) = socket.recv_from(&mut buffer)?;
+            let flow = flows.lock().unwrap().get(&src).cloned();
+            let flow = match flow {
+                Some(flow) => flow,
+                None => {
+                    let flow = Arc::new(stack.bind_udp(0x00)?);
+                    flow.set_read_timeout(Some(UDP_IDLE));
+                    flows.lock().unwrap().insert(src, flow.clone());
+                    debug!(rule = %self, src = %src, "opened");
+                    let (socket, flows, reader) = (socket.clone(), flows.clone(), flow.clone());
+                    thread::spawn(move || {
+                        let mut buffer = vec![0x00; 0x10000];
+                        while let Ok((length, from)) = reader.recv_from(&mut buffer) {
+                            if from == target {
+                                let _ = socket.send_to(&buffer[0x00..length], src);
+                            }
+                        }
+                        flows.lock().unwrap().remove(&src);
+                        debug!(rule = %self, src = %src, "idle, closed");
+                    });
+                    flow
+                }
+            };
+            let _ = flow.send_to(&buffer[0x00..length], target);
+        }
+    }
+
+    // a socket on this machine per peer address, so replies find their way back
+    fn remote_udp(self, stack: Arc<NetStack>, port: u16, target: SocketAddr) -> io::Result<()> {
+        let socket = Arc::new(stack.bind_udp(port)?);
+        let flows: Arc<Mutex<HashMap<SocketAddr, Arc<net::UdpSocket>>>> = Arc::default();
+        debug!(rule = %self, "listening");
+        let mut buffer = vec![0x00; 0x10000];
+        loop {
+            let (length, src) = socket.recv_from(&mut buffer)?;
+            let flow = flows.lock().unwrap().get(&src).cloned();
+            let flow = match flow {
+                Some(flow) => flow,
+                None => {
+                    let any = match target {
+                        SocketAddr::V4(_) => SocketAddr::from(([0x00; 0x04], 0x00)),
+                        SocketAddr::V6(_) => SocketAddr::from(([0x00; 0x10], 0x00)),
+                    };
+                    let flow = net::UdpSocket::bind(any)?;
+                    flow.connect(target)?;
+                    flow.set_read_timeout(Some(UDP_IDLE))?;
+                    let flow = Arc::new(flow);
+                    flows.lock().unwrap().insert(src, flow.clone());
+                    debug!(rule = %self, src = %src, "opened");
+                    let (socket, flows, reader) = (socket.clone(), flows.clone(), flow.clone());
+                    thread::spawn(move || {
+                        let mut buffer = vec![0x00; 0x10000];
+                        while let Ok(length) = reader.recv(&mut buffer) {
+                            let _ = socket.send_to(&buffer[0x00..length], src);
+                        }
+                        flows.lock().unwrap().remove(&src);
+                        debug!(rule = %self, src = %src, "idle, closed");
+                    });
+                    flow
+                }
+            };
+            let _ = flow.send(&buffer[0x00..length]);
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+        })
+    }
+}
+
+// the same shape the config file takes
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Local {
+                protocol,
+                listen,
+                target,
+            } => write!(f, "-L {protocol} {listen} {target}"),
+            Self::Remote {
+                protocol,
+                port,
+                target,
+            } => write!(f, "-R {protocol} {port} {target}"),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+// not display, the tracing macros bring their own into scope
+fn or_unknown(address: Option<SocketAddr>) -> String {
+    address.map_or_else(|| "?".to_owned(), |address| address.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netstack::tests::{pair, A, B};
+    use std::{
+        io::{Read, Write},
+        net::Ipv4Addr,
+    };
+
+    fn ping(stream: &mut (impl Read + Write)) {
+        stream.write_all(b"ping").unwrap();
+        let mut buffer = [0x00; 0x04];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+    }
+
+    fn echo(mut stream: impl Read + Write) {
+        let mut buffer = [0x00; 0x04];
+        stream.read_exact(&mut buffer).unwrap();
+        stream.write_all(&buffer).unwrap();
+        stream.flush().unwrap();
+    }
+
+    #[test]
+    fn local_tcp() {
+        let (a, b, _linked) = pair();
+        let server = b.listen(0x1f90).unwrap();
+        let echoed = thread::spawn(move || echo(server));
+        // bound here rather than in serve, so the test knows the port
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0x00)).unwrap();
+        let listen = listener.local_addr().unwrap();
+        let target = SocketAddr::new(B, 0x1f90);
+        let rule = Forward::Local {
+            protocol: Protocol::Tcp,
+            listen,
+            target,
+        };
+        thread::spawn(move || rule.local_tcp(a, listener, target));
+        let mut client = net::TcpStream::connect(listen).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(0x0a)))
+            .unwrap();
+        ping(&mut client);
+        echoed.join().unwrap();
+    }
+
+    #[test]
+    fn remote_tcp() {
+        let (a, b, _linked) = pair();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0x00)).unwrap();
+        let target = listener.local_addr().unwrap();
+        let echoed = thread::spawn(move || echo(listener.accept().unwrap().0));
+        let rule = Forward::Remote {
+            protocol: Protocol::Tcp,
+            port: 0x1f90,
+            target,
+        };
+        thread::spawn(move || rule.serve(b));
+        // the rule may not be listening yet, and then the SYN is refused
+        let mut stream = (0x00..0x32)
+            .find_map(|_| {
+                let stream = a.connect(SocketAddr::new(B, 0x1f90)).ok();
+                if stream.is_none() {
+                    thread::sleep(Duration::from_millis(0x14));
+                }
+                stream
+            })
+            .unwrap();
+        assert_eq!(stream.local_addr().map(|local| local.ip()), Some(A));
+        ping(&mut stream);
+        echoed.join().unwrap();
+    }
+}
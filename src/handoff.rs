@@ -0,0 +1,483 @@
+// state handed from a process on its way out to the one replacing it, so peers keep their sessions
+// the blob is sealed with a key derived from the device's static secret, so only the same device can read it:
+//
+//     version (1) || nonce (24) || XAEAD(HASH(LABEL_HANDOFF || static_private), nonce, state, version) || tag (16)
+//
+// a receiving session is carried over as its index, key, replay window and when it was set up,
+// so packets the old process already opened are not let through again
+
+use rand_core::CryptoRngCore;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::{
+    cipher::{Encryptor, Window, RING},
+    crypto::{hash, xopen, xseal},
+    device::Device,
+    driver::{Driver, Pending},
+    error::{Error, Result},
+    trace::debug,
+};
+
+// bumped whenever the layout of the state changes, blobs from other versions are refused
+pub const VERSION: u8 = 0x03;
+
+// what a driver carries over
+pub(crate) struct PeerState {
+    pub(crate) public: [u8; 0x20],                     // peer static public
+    pub(crate) endpoint: Option<SocketAddr>,           // where the peer was last heard from
+    pub(crate) session: Option<Duration>,              // when the current session was set up
+    pub(crate) sent: Duration,                         // last time anything went to the peer
+    pub(crate) cookie: Option<([u8; 0x10], Duration)>, // latest cookie, and when it came in
+    pub(crate) pending: Option<Pending>,               // initiation waiting for its response
+    pub(crate) encryptor: Option<Encryptor>,           // sending session, counter included
+    pub(crate) decryptors: Vec<(u32, [u8; 0x20], Window, Duration)>, // index, key, replay window and set up time of every receiving session
+}
+
+// seals the state of every driver for the process taking over
+// the drivers stop sending and receiving, tick and decapsulate do nothing and encapsulate fails after this
+// stop reading the socket before calling it, a datagram opened while it runs would be opened again on the other side
+pub fn export(
+    device: &Device,
+    drivers: &[&Driver],
+    rng: &mut impl CryptoRngCore,
+) -> Result<Vec<u8>> {
+    let mut txt = Vec::new();
+    match device.checker().secret() {
+        Some((r_m, born)) => {
+            txt.push(0x01);
+            txt.extend_from_slice(&r_m);
+            put_duration(&mut txt, born);
+        }
+        None => txt.push(0x00),
+    }
+    txt.extend_from_slice(&(drivers.len() as u32).to_le_bytes());
+    for driver in drivers {
+        put_peer(&mut txt, driver.hand_over());
+    }
+    let mut blob = vec![VERSION];
+    let mut nonce = [0x00; 0x18];
+    rng.fill_bytes(&mut nonce);
+    blob.extend_from_slice(&nonce);
+    let mut tag = [0x00; 0x10];
+    xseal(key(device), nonce, [VERSION], &mut txt, &mut tag)?;
+    blob.extend_from_slice(&txt);
+    blob.extend_from_slice(&tag);
+    debug!(peers = drivers.len(), "exported handoff state");
+    Ok(blob)
+}
+
+// restores a blob from export into drivers set up from the same configuration
+// peers in the blob without a driver are left out, drivers without a peer in the blob start fresh
+// returns how many drivers picked up where they left off
+pub fn import(device: &Device, drivers: &[&Driver], blob: &[u8]) -> Result<usize> {
+    if blob.len() < 0x29 || blob[0x00] != VERSION {
+        Err(Error::InvalidHandoff)?
+    }
+    let nonce = &blob[0x01..0x19];
+    let (txt, tag) = blob[0x19..].split_at(blob.len() - 0x29);
+    let mut txt = txt.to_vec();
+    xopen(key(device), nonce, [VERSION], &mut txt, tag).map_err(|_| Error::InvalidHandoff)?;
+    let mut reader = Reader(&txt);
+    let r_m = match reader.flag()? {
+        true => Some((reader.array()?, reader.duration()?)),
+        false => None,
+    };
+    let mut peers = Vec::new();
+    for _ in 0x00..reader.u32()? {
+        peers.push(reader.peer()?);
+    }
+    if !reader.0.is_empty() {
+        Err(Error::InvalidHandoff)?
+    }
+    // a peer twice is as wrong as a short blob, and nothing has been touched yet
+    for (i, peer) in peers.iter().enumerate() {
+        if peers[0x00..i]
+            .iter()
+            .any(|other| other.public == peer.public)
+        {
+            Err(Error::InvalidHandoff)?
+        }
+    }
+    device.checker().set_secret(r_m);
+    let mut restored = 0x00;
+    for peer in peers {
+        let driver = drivers
+            .iter()
+            .find(|driver| driver.tunnel().peer_public().as_bytes() == &peer.public);
+        if let Some(driver) = driver {
+            driver.take_over(peer);
+            restored += 1;
+        }
+    }
+    debug!(peers = restored, "imported handoff state");
+    Ok(restored)
+}
+
+// HASH(LABEL_HANDOFF || static_private)
+fn key(device: &Device) -> [u8; 0x20] {
+    hash("handoff-", device.secret().as_bytes())
+}
+
+fn put_peer(txt: &mut Vec<u8>, peer: PeerState) {
+    txt.extend_from_slice(&peer.public);
+    match peer.endpoint {
+        Some(SocketAddr::V4(endpoint)) => {
+            txt.push(0x04);
+            txt.extend_from_slice(&endpoint.ip().octets());
+            txt.extend_from_slice(&endpoint.port().to_le_bytes());
+        }
+        Some(SocketAddr::V6(endpoint)) => {
+            txt.push(0x06);
+            txt.extend_from_slice(&endpoint.ip().octets());
+            txt.extend_from_slice(&endpoint.port().to_le_bytes());
+        }
+        None => txt.push(0x00),
+    }
+    match peer.session {
+        Some(session) => {
+            txt.push(0x01);
+            put_duration(txt, session);
+        }
+        None => txt.push(0x00),
+    }
+    put_duration(txt, peer.sent);
+    match peer.cookie {
+        Some((l_c, at)) => {
+            txt.push(0x01);
+            txt.extend_from_slice(&l_c);
+            put_duration(txt, at);
+        }
+        None => txt.push(0x00),
+    }
+    match peer.pending {
+        Some(pending) => {
+            txt.push(0x01);
+            put_duration(txt, pending.at);
+            txt.extend_from_slice(&pending.m_1);
+            txt.extend_from_slice(&pending.i_i.to_le_bytes());
+            txt.extend_from_slice(&pending.e_b);
+            match pending.l_c {
+                Some(l_c) => {
+                    txt.push(0x01);
+                    txt.extend_from_slice(&l_c);
+                }
+                None => txt.push(0x00),
+            }
+        }
+        None => txt.push(0x00),
+    }
+    match peer.encryptor {
+        Some(encryptor) => {
+            txt.push(0x01);
+            txt.extend_from_slice(&encryptor.r_i().to_le_bytes());
+            txt.extend_from_slice(&encryptor.key());
+            txt.extend_from_slice(&encryptor.s_c().to_le_bytes());
+            txt.extend_from_slice(&encryptor.s_b().to_le_bytes());
+        }
+        None => txt.push(0x00),
+    }
+    txt.extend_from_slice(&(peer.decryptors.len() as u32).to_le_bytes());
+    for (r_i, key, window, born) in peer.decryptors {
+        txt.extend_from_slice(&r_i.to_le_bytes());
+        txt.extend_from_slice(&key);
+        txt.extend_from_slice(&window.last().to_le_bytes());
+        for word in window.ring() {
+            txt.extend_from_slice(&word.to_le_bytes());
+        }
+        put_duration(txt, born);
+    }
+}
+
+fn put_duration(txt: &mut Vec<u8>, duration: Duration) {
+    txt.extend_from_slice(&(duration.as_nanos() as u64).to_le_bytes());
+}
+
+// walks the opened state, anything short or out of place means the blob is not ours
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let Some((lhs, rhs)) = self.0.split_first_chunk() else {
+            Err(Error::InvalidHandoff)?
+        };
+        self.0 = rhs;
+        Ok(*lhs)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.array::<0x01>()?[0x00])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn duration(&mut self) -> Result<Duration> {
+        Ok(Duration::from_nanos(self.u64()?))
+    }
+
+    fn flag(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0x00 => Ok(false),
+            0x01 => Ok(true),
+            _ => Err(Error::InvalidHandoff),
+        }
+    }
+
+    fn peer(&mut self) -> Result<PeerState> {
+        let public = self.array()?;
+        let endpoint = match self.u8()? {
+            0x00 => None,
+            0x04 => Some(SocketAddr::new(
+                Ipv4Addr::from(self.array::<0x04>()?).into(),
+                self.u16()?,
+            )),
+            0x06 => Some(SocketAddr::new(
+                Ipv6Addr::from(self.array::<0x10>()?).into(),
+                self.u16()?,
+            )),
+            _ => Err(Error::InvalidHandoff)?,
+        };
+        let session = match self.flag()? {
+            true => Some(self.duration()?),
+            false => None,
+        };
+        let sent = self.duration()?;
+        let cookie = match self.flag()? {
+            true => Some((self.array()?, self.duration()?)),
+            false => None,
+        };
+        let pending = match self.flag()? {
+            true => Some(Pending {
+                at: self.duration()?,
+                m_1: self.array()?,
+                i_i: self.u32()?,
+                e_b: self.array()?,
+                l_c: match self.flag()? {
+                    true => Some(self.array()?),
+                    false => None,
+                },
+            }),
+            false => None,
+        };
+        let encryptor = match self.flag()? {
+            true => Some(Encryptor::resume(
+                self.u32()?,
+                self.array()?,
+                self.u64()?,
+                self.u64()?,
+            )),
+            false => None,
+        };
+        let mut decryptors = Vec::new();
+        for _ in 0x00..self.u32()? {
+            let (r_i, key, last) = (self.u32()?, self.array()?, self.u64()?);
+            let mut ring = [0x00; RING];
+            for word in &mut ring {
+                *word = self.u64()?;
+            }
+            decryptors.push((r_i, key, Window::resume(last, ring), self.duration()?));
+        }
+        Ok(PeerState {
+            public,
+            endpoint,
+            session,
+            sent,
+            cookie,
+            pending,
+            encryptor,
+            decryptors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::LoadMonitor, driver::Action, ratelimiter::RateLimiter, tunnel::Tunnel};
+    use rand_core::{CryptoRng, RngCore};
+    use std::sync::Arc;
+    use x25519::StaticSecret;
+
+    // predictable, which is all a test needs
+    struct Counter(u64);
+
+    impl RngCore for Counter {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_mul(0x5851f42d4c957f2d).wrapping_add(0x01);
+            self.0 >> 0x10
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = self.next_u64() as u8;
+            }
+        }
+
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> core::result::Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for Counter {}
+
+    const SRC: SocketAddr = SocketAddr::new(
+        std::net::IpAddr::V4(Ipv4Addr::new(0xc0, 0x00, 0x02, 0x01)),
+        0xca6c,
+    );
+    const NOW: Duration = Duration::from_secs(0x6553f100);
+
+    fn device(secret: [u8; 0x20]) -> Arc<Device> {
+        Arc::new(Device::new(
+            StaticSecret::from(secret),
+            LoadMonitor::default(),
+            RateLimiter::default(),
+        ))
+    }
+
+    // the responder end of a session with [0x01; 0x20], as a process starting up would make it
+    fn responder() -> (Arc<Device>, Driver) {
+        let b = device([0x02; 0x20]);
+        let a_p = x25519::PublicKey::from(&StaticSecret::from([0x01; 0x20]));
+        let driver = Driver::new(
+            Arc::new(Tunnel::new(b.clone(), a_p, [0x00; 0x20])),
+            None,
+            None,
+        );
+        (b, driver)
+    }
+
+    #[test]
+    fn replays_stay_out_after_handoff() {
+        let mut rng = Counter(0x2a);
+        let a = device([0x01; 0x20]);
+        let (b, old) = responder();
+        let a = Driver::new(
+            Arc::new(Tunnel::new(a, *b.public(), [0x00; 0x20])),
+            Some(SRC),
+            None,
+        );
+        let (mut out, mut back) = (vec![0x00; 0x0800], vec![0x00; 0x0800]);
+        let Action::Network(length) = a.tick(NOW, &mut rng, &mut out).unwrap() else {
+            panic!("no initiation")
+        };
+        let Action::Network(length) = old
+            .decapsulate(&mut out[0x00..length], SRC, NOW, &mut rng, &mut back)
+            .unwrap()
+        else {
+            panic!("no response")
+        };
+        a.decapsulate(&mut back[0x00..length], SRC, NOW, &mut rng, &mut out)
+            .unwrap();
+        let Action::Network(length) = a.encapsulate(&[], NOW, &mut rng, &mut out).unwrap() else {
+            panic!("no keepalive")
+        };
+        let opened = out[0x00..length].to_vec();
+        old.decapsulate(&mut opened.clone(), SRC, NOW, &mut rng, &mut back)
+            .unwrap();
+
+        let blob = export(&b, &[&old], &mut rng).unwrap();
+        let (b, new) = responder();
+        // a blob that does not open leaves the driver as it was
+        let mut tampered = blob.clone();
+        *tampered.last_mut().unwrap() ^= 0x01;
+        assert!(import(&b, &[&new], &tampered).is_err());
+        assert!(new.tunnel().receiving().is_empty());
+        assert_eq!(import(&b, &[&new], &blob).unwrap(), 0x01);
+
+        // what the old process opened stays opened, the rest of the session goes on
+        let err = new
+            .decapsulate(&mut opened.clone(), SRC, NOW, &mut rng, &mut back)
+            .unwrap_err();
+        assert!(matches!(err.kind(), Error::Replay));
+        let Action::Network(length) = a.encapsulate(&[], NOW, &mut rng, &mut out).unwrap() else {
+            panic!("no keepalive")
+        };
+        let action = new.decapsulate(&mut out[0x00..length], SRC, NOW, &mut rng, &mut back);
+        assert_eq!(action.unwrap(), Action::Idle);
+    }
+
+    #[test]
+    fn pending_initiation_finishes_after_handoff() {
+        let mut rng = Counter(0x2b);
+        let (b, responder) = responder();
+        // the initiator is the side that moves this time
+        let initiator = || {
+            let a = device([0x01; 0x20]);
+            let driver = Driver::new(
+                Arc::new(Tunnel::new(a.clone(), *b.public(), [0x00; 0x20])),
+                Some(SRC),
+                None,
+            );
+            (a, driver)
+        };
+        let (a, old) = initiator();
+        let (mut init, mut resp, mut out) =
+            (vec![0x00; 0x0800], vec![0x00; 0x0800], vec![0x00; 0x0800]);
+        let Action::Network(length) = old.tick(NOW, &mut rng, &mut init).unwrap() else {
+            panic!("no initiation")
+        };
+        init.truncate(length);
+
+        let blob = export(&a, &[&old], &mut rng).unwrap();
+        // the old process is out of it, even with a response on the way
+        assert_eq!(old.tick(NOW, &mut rng, &mut out).unwrap(), Action::Idle);
+        let (a, new) = initiator();
+        assert_eq!(import(&a, &[&new], &blob).unwrap(), 0x01);
+
+        let Action::Network(length) = responder
+            .decapsulate(&mut init, SRC, NOW, &mut rng, &mut resp)
+            .unwrap()
+        else {
+            panic!("no response")
+        };
+        let action = old.decapsulate(
+            &mut resp[0x00..length].to_vec(),
+            SRC,
+            NOW,
+            &mut rng,
+            &mut out,
+        );
+        assert_eq!(action.unwrap(), Action::Idle);
+        assert!(old.tunnel().receiving().is_empty());
+        // the new process still waits for it, so it does not start over
+        assert_eq!(new.tick(NOW, &mut rng, &mut out).unwrap(), Action::Idle);
+        let action = new.decapsulate(&mut resp[0x00..length], SRC, NOW, &mut rng, &mut out);
+        assert_eq!(action.unwrap(), Action::Idle);
+
+        // and the session it finished carries data both ways
+        let Action::Network(length) = new.encapsulate(&[], NOW, &mut rng, &mut out).unwrap() else {
+            panic!("no keepalive")
+        };
+        let action = responder.decapsulate(&mut out[0x00..length], SRC, NOW, &mut rng, &mut resp);
+        assert_eq!(action.unwrap(), Action::Idle);
+        let Action::Network(length) = responder
+            .encapsulate(&[], NOW, &mut rng, &mut resp)
+            .unwrap()
+        else {
+            panic!("no keepalive")
+        };
+        let action = new.decapsulate(&mut resp[0x00..length], SRC, NOW, &mut rng, &mut out);
+        assert_eq!(action.unwrap(), Action::Idle);
+        assert!(old.encapsulate(&[], NOW, &mut rng, &mut out).is_err());
+    }
+}
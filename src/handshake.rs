@@ -1,12 +1,18 @@
-use std::time::Duration;
+use rand_core::CryptoRngCore;
+use std::{net::SocketAddr, sync::Mutex, time::Duration};
 use x25519::{PublicKey, ReusableSecret, StaticSecret};
 
 use crate::{
-    crypto::{hash, kdf, mac, open, seal},
-    error::Result,
-    packet::{HandshakeInit, HandshakeResp},
+    crypto::{hash, kdf, mac, open, seal, verify, xopen, xseal},
+    error::{Error, Peer, Result},
+    keylog,
+    packet::{CookieReply, HandshakeInit, HandshakeResp},
+    trace::debug,
 };
 
+#[cfg(feature = "tracing")]
+use crate::trace::Fingerprint;
+
 pub const INITIAL_H_H: [u8; 0x20] = [
     0x22, 0x11, 0xb3, 0x61, 0x08, 0x1a, 0xc5, 0x66, 0x69, 0x12, 0x43, 0xdb, 0x45, 0x8a, 0xd5, 0x32,
     0x2d, 0x9c, 0x6c, 0x66, 0x22, 0x93, 0xe8, 0xb7, 0x0e, 0xe1, 0x9c, 0x65, 0xba, 0x07, 0x9e, 0xf3,
@@ -17,6 +23,44 @@ pub const INITIAL_C_K: [u8; 0x20] = [
     0x16, 0xeb, 0x42, 0x06, 0xf8, 0x72, 0x77, 0xf5, 0x2d, 0x38, 0xd1, 0x98, 0x8b, 0x78, 0xcd, 0x36,
 ];
 
+// cookies stop being accepted once the secret they were made with is this old
+pub const COOKIE_SECRET_LIFETIME: Duration = Duration::from_secs(0x78);
+
+// everything about a peer that only changes when one of the two static keys does
+pub struct PeerKeys {
+    p_p: PublicKey,  // peer static public
+    s_s: [u8; 0x20], // DH(local.static_private, peer.static_public)
+    h_h: [u8; 0x20], // HASH(HASH(CONSTRUCTION || IDENTIFIER) || peer.static_public)
+    m_k: [u8; 0x20], // HASH(LABEL_MAC1 || peer.static_public)
+    c_k: [u8; 0x20], // HASH(LABEL_COOKIE || peer.static_public)
+}
+
+impl PeerKeys {
+    pub fn new(l_s: &StaticSecret, p_p: &PublicKey) -> Self {
+        Self {
+            p_p: *p_p,
+            s_s: l_s.diffie_hellman(p_p).to_bytes(),
+            h_h: hash(INITIAL_H_H, p_p),
+            m_k: hash("mac1----", p_p),
+            c_k: hash("cookie--", p_p),
+        }
+    }
+
+    pub fn public(&self) -> &PublicKey {
+        &self.p_p
+    }
+
+    // key for the mac1 of messages sent to the peer
+    pub fn mac1_key(&self) -> [u8; 0x20] {
+        self.m_k
+    }
+
+    // key for cookie replies sent by the peer
+    pub fn cookie_key(&self) -> [u8; 0x20] {
+        self.c_k
+    }
+}
+
 pub struct Initiator {
     h_h: [u8; 0x20],     // handshake hash
     c_k: [u8; 0x20],     // chaining key
@@ -24,24 +68,25 @@ pub struct Initiator {
 }
 
 impl Initiator {
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err(level = "debug", Display), fields(peer = %Fingerprint(r_k.public()), index = i_i)))]
     pub fn send_handshake_init(
-        i_i: [u8; 0x04],         // initiator index
-        i_s: &StaticSecret,      // initiator static secret
+        i_i: u32,                // initiator index
         i_p: &PublicKey,         // initiator static public
-        r_p: &PublicKey,         // responder static public
+        r_k: &PeerKeys,          // precomputed responder keys
         e_s: ReusableSecret,     // initiator ephemeral secret
         now: Duration,           // duration since UNIX epoch
-        l_c: Option<[u8; 0x20]>, // latest cookie received
+        l_c: Option<[u8; 0x10]>, // latest cookie received
         msg: &mut HandshakeInit, // destination buffer
     ) -> Result<Self> {
+        keylog::log(&[("REMOTE_STATIC_PUBLIC_KEY", r_k.p_p.as_bytes())]);
         // initiator.hash = HASH(HASH(initiator.chaining_key || IDENTIFIER) || responder.static_public)
-        let h_h = hash(INITIAL_H_H, r_p);
+        let h_h = r_k.h_h;
         // msg.message_type = 1
         msg.m_t = 0x01;
         // msg.reserved_zero = { 0, 0, 0 }
         msg.r_0 = [0x00; 0x03];
         // msg.sender_index = little_endian(initiator.sender_index)
-        msg.s_i = i_i;
+        msg.s_i.set(i_i);
 
         // msg.unencrypted_ephemeral = DH_PUBKEY(initiator.ephemeral_private)
         let e_p = PublicKey::from(&e_s);
@@ -56,7 +101,7 @@ impl Initiator {
         // temp = HMAC(initiator.chaining_key, DH(initiator.ephemeral_private, responder.static_public))
         // initiator.chaining_key = HMAC(temp, 0x1)
         // key = HMAC(temp, initiator.chaining_key || 0x2)
-        let [c_k, key] = kdf(c_k, e_s.diffie_hellman(&r_p));
+        let [c_k, key] = kdf(c_k, e_s.diffie_hellman(&r_k.p_p));
 
         // msg.encrypted_static = AEAD(key, 0, initiator.static_public, initiator.hash)
         let (lhs, rhs) = msg.e_s.split_at_mut(0x20);
@@ -68,7 +113,7 @@ impl Initiator {
         // temp = HMAC(initiator.chaining_key, DH(initiator.static_private, responder.static_public))
         // initiator.chaining_key = HMAC(temp, 0x1)
         // key = HMAC(temp, initiator.chaining_key || 0x2)
-        let [c_k, key] = kdf(c_k, i_s.diffie_hellman(&r_p));
+        let [c_k, key] = kdf(c_k, r_k.s_s);
 
         // msg.encrypted_timestamp = AEAD(key, 0, TAI64N(), initiator.hash)
         let (lhs, rhs) = msg.e_t.split_at_mut(0x0c);
@@ -79,7 +124,7 @@ impl Initiator {
         let h_h = hash(h_h, msg.e_t);
 
         // msg.mac1 = MAC(HASH(LABEL_MAC1 || responder.static_public), msg[0:offsetof(msg.mac1)])
-        msg.m_1 = mac(hash("mac1----", r_p), &msg[0x00..0x74]);
+        msg.m_1 = mac(r_k.m_k, &msg[0x00..0x74]);
 
         // if (initiator.last_received_cookie is empty or expired)
         //     msg.mac2 = [zeros]
@@ -91,15 +136,21 @@ impl Initiator {
             [0x00; 0x10]
         };
 
+        debug!("sent handshake initiation");
         Ok(Self { c_k, h_h, e_s })
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err(level = "debug", Display), fields(index = src.r_i.get(), sender = src.s_i.get())))]
     pub fn recv_handshake_resp(
-        self,
+        &self,
         i_s: &StaticSecret,      // initiator static secret
         p_k: Option<[u8; 0x20]>, // preshared key
         src: &HandshakeResp,     // source buffer
     ) -> Result<([u8; 0x20], [u8; 0x20])> {
+        keylog::log(&[
+            ("LOCAL_STATIC_PRIVATE_KEY", i_s.as_bytes()),
+            ("PRESHARED_KEY", &p_k.unwrap_or_default()),
+        ]);
         let msg = HandshakeResp::wrap_ref(src)?;
 
         // msg.unencrypted_ephemeral = DH_PUBKEY(responder.ephemeral_private)
@@ -128,7 +179,7 @@ impl Initiator {
         let h_h = hash(h_h, tau);
 
         // msg.encrypted_nothing = AEAD(key, 0, [empty], responder.hash)
-        open(key, 0, h_h, [], &msg.e_n)?;
+        open(key, 0, h_h, [], msg.e_n)?;
 
         // temp1 = HMAC(initiator.chaining_key, [empty])
         // temp2 = HMAC(temp1, 0x1)
@@ -137,6 +188,7 @@ impl Initiator {
         // initiator.receiving_key = temp3
         let [_, s_k, r_k] = kdf(c_k, []);
 
+        debug!("received handshake response");
         Ok((s_k, r_k))
     }
 }
@@ -145,15 +197,21 @@ pub struct Responder {
     h_h: [u8; 0x20], // handshake hash
     c_k: [u8; 0x20], // chaining key
     e_p: PublicKey,  // initiator ephemeral public
+    t_s: [u8; 0x0c], // initiator TAI64N timestamp
 }
 
 impl Responder {
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err(level = "debug", Display), fields(peer = %Fingerprint(i_k.public()), sender = msg.s_i.get())))]
     pub fn recv_handshake_init(
         r_s: &StaticSecret,  // responder static secret
         r_p: &PublicKey,     // responder static public
-        i_p: &PublicKey,     // initiator public
+        i_k: &PeerKeys,      // precomputed initiator keys
         msg: &HandshakeInit, // source buffer
     ) -> Result<Self> {
+        keylog::log(&[
+            ("LOCAL_STATIC_PRIVATE_KEY", r_s.as_bytes()),
+            ("REMOTE_STATIC_PUBLIC_KEY", i_k.p_p.as_bytes()),
+        ]);
         // initiator.hash = HASH(HASH(initiator.chaining_key || IDENTIFIER) || responder.static_public)
         let h_h = hash(INITIAL_H_H, r_p);
 
@@ -174,43 +232,57 @@ impl Responder {
 
         let mut e_s = msg.e_s;
         let (lhs, rhs) = e_s.split_at_mut(0x20);
-        open(key, 0, h_h, lhs, rhs)?;
+        open(key, 0, h_h, &mut *lhs, rhs)?;
+        // an initiation from some other peer of ours opens just as well, it is just not this one
+        if lhs != i_k.p_p.as_bytes() {
+            Err(Error::UnknownPeer)?
+        }
         // initiator.hash = HASH(initiator.hash || msg.encrypted_static)
         let h_h = hash(h_h, msg.e_s);
 
         // temp = HMAC(initiator.chaining_key, DH(initiator.static_private, responder.static_public))
         // initiator.chaining_key = HMAC(temp, 0x1)
         // key = HMAC(temp, initiator.chaining_key || 0x2)
-        let [c_k, key] = kdf(c_k, r_s.diffie_hellman(&i_p));
+        let [c_k, key] = kdf(c_k, i_k.s_s);
 
         // msg.encrypted_timestamp = AEAD(key, 0, TAI64N(), initiator.hash)
         let mut e_t = msg.e_t;
         let (lhs, rhs) = e_t.split_at_mut(0x0c);
-        seal(key, 0, h_h, lhs, rhs)?;
+        open(key, 0, h_h, &mut *lhs, rhs)?;
+        let t_s = lhs.try_into().unwrap();
         // initiator.hash = HASH(initiator.hash || msg.encrypted_timestamp)
         let h_h = hash(h_h, msg.e_t);
 
-        Ok(Self { h_h, c_k, e_p })
+        debug!("received handshake initiation");
+        Ok(Self { h_h, c_k, e_p, t_s })
     }
 
+    // only tells a replayed initiation apart from a fresh one, newer than any before it or refused
+    pub fn timestamp(&self) -> [u8; 0x0c] {
+        self.t_s
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err(level = "debug", Display), fields(peer = %Fingerprint(i_k.public()), index = r_i, receiver = i_i)))]
+    #[allow(clippy::too_many_arguments)]
     pub fn send_handshake_resp(
         self,
-        i_i: [u8; 0x04],         // initiator index
-        i_p: &PublicKey,         // initiator static public
-        r_i: [u8; 0x04],         // responder index
+        i_i: u32,                // initiator index
+        i_k: &PeerKeys,          // precomputed initiator keys
+        r_i: u32,                // responder index
         e_s: ReusableSecret,     // responder ephemeral secret
         p_k: Option<[u8; 0x20]>, // preshared key
-        l_c: Option<[u8; 0x20]>, // latest cookie received
+        l_c: Option<[u8; 0x10]>, // latest cookie received
         msg: &mut HandshakeResp,
     ) -> Result<([u8; 0x20], [u8; 0x20])> {
+        keylog::log(&[("PRESHARED_KEY", &p_k.unwrap_or_default())]);
         // msg.message_type = 2
         msg.m_t = 0x02;
         // msg.reserved_zero = { 0, 0, 0 }
         msg.r_0 = [0x00; 0x03];
         // msg.sender_index = little_endian(responder.sender_index)
-        msg.s_i = r_i;
+        msg.s_i.set(r_i);
         // msg.receiver_index = little_endian(initiator.sender_index)
-        msg.r_i = i_i;
+        msg.r_i.set(i_i);
 
         // msg.unencrypted_ephemeral = DH_PUBKEY(responder.ephemeral_private)
         let e_p = PublicKey::from(&e_s);
@@ -228,7 +300,7 @@ impl Responder {
 
         // temp = HMAC(responder.chaining_key, DH(responder.ephemeral_private, initiator.static_public))
         // responder.chaining_key = HMAC(temp, 0x1)
-        let [c_k] = kdf(c_k, e_s.diffie_hellman(i_p));
+        let [c_k] = kdf(c_k, e_s.diffie_hellman(&i_k.p_p));
 
         // temp = HMAC(responder.chaining_key, preshared_key)
         // responder.chaining_key = HMAC(temp, 0x1)
@@ -242,7 +314,7 @@ impl Responder {
         seal(key, 0, h_h, [], &mut msg.e_n)?;
 
         // msg.mac1 = MAC(HASH(LABEL_MAC1 || initiator.static_public), msg[0:offsetof(msg.mac1)])
-        msg.m_1 = mac(hash("mac1----", i_p), &msg[0x00..0x3c]);
+        msg.m_1 = mac(i_k.m_k, &msg[0x00..0x3c]);
 
         // if (responder.last_received_cookie is empty or expired)
         //     msg.mac2 = [zeros]
@@ -261,6 +333,136 @@ impl Responder {
         // responder.sending_key = temp3
         let [_, r_k, s_k] = kdf(c_k, []);
 
+        debug!("sent handshake response");
         Ok((s_k, r_k))
     }
 }
+
+pub struct Checker {
+    m_k: [u8; 0x20],                            // mac1 key
+    c_k: [u8; 0x20],                            // cookie key
+    r_m: Mutex<Option<([u8; 0x20], Duration)>>, // cookie secret and when it was picked
+}
+
+impl Checker {
+    pub fn new(r_p: &PublicKey) -> Self {
+        Self {
+            // HASH(LABEL_MAC1 || responder.static_public)
+            m_k: hash("mac1----", r_p),
+            // HASH(LABEL_COOKIE || responder.static_public)
+            c_k: hash("cookie--", r_p),
+            r_m: Mutex::new(None),
+        }
+    }
+
+    // the cookie secret and when it was picked, so cookies already handed out survive a handoff
+    pub fn secret(&self) -> Option<([u8; 0x20], Duration)> {
+        *self.r_m.lock().unwrap()
+    }
+
+    pub fn set_secret(&self, r_m: Option<([u8; 0x20], Duration)>) {
+        *self.r_m.lock().unwrap() = r_m;
+    }
+
+    // msg is a whole handshake message, which always ends in mac1 || mac2
+    pub fn check_mac1(&self, msg: &[u8]) -> bool {
+        if msg.len() < 0x24 {
+            return false;
+        }
+        // msg.mac1 = MAC(HASH(LABEL_MAC1 || responder.static_public), msg[0:offsetof(msg.mac1)])
+        let (lhs, rhs) = msg.split_at(msg.len() - 0x20);
+        verify(self.m_k, lhs, &rhs[0x00..0x10])
+    }
+
+    // msg is a whole handshake message, which always ends in mac1 || mac2
+    pub fn check_mac2(&self, msg: &[u8], src: SocketAddr, now: Duration) -> bool {
+        if msg.len() < 0x24 {
+            return false;
+        }
+        let r_m = match *self.r_m.lock().unwrap() {
+            Some((r_m, born)) if now.saturating_sub(born) < COOKIE_SECRET_LIFETIME => r_m,
+            _ => return false,
+        };
+        // msg.mac2 = MAC(initiator.last_received_cookie, msg[0:offsetof(msg.mac2)])
+        let (lhs, rhs) = msg.split_at(msg.len() - 0x10);
+        verify(cookie(r_m, src), lhs, rhs)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err(level = "debug", Display), fields(endpoint = %s_a)))]
+    pub fn send_cookie_reply(
+        &self,
+        src: &[u8],                   // handshake message being answered
+        s_a: SocketAddr,              // address it came from
+        now: Duration,                // duration since UNIX epoch
+        rng: &mut impl CryptoRngCore, // source of the secret and nonce
+        msg: &mut CookieReply,        // destination buffer
+    ) -> Result<()> {
+        if src.len() < 0x24 {
+            Err(Error::BufferLengthTooShort {
+                expected: 0x24,
+                got: src.len(),
+            })?
+        }
+        let r_m = {
+            let mut r_m = self.r_m.lock().unwrap();
+            match *r_m {
+                Some((r_m, born)) if now.saturating_sub(born) < COOKIE_SECRET_LIFETIME => r_m,
+                _ => {
+                    let mut secret = [0x00; 0x20];
+                    rng.fill_bytes(&mut secret);
+                    *r_m = Some((secret, now));
+                    debug!("rotated cookie secret");
+                    secret
+                }
+            }
+        };
+        // msg.message_type = 3
+        msg.m_t = 0x03;
+        // msg.reserved_zero = { 0, 0, 0 }
+        msg.r_0 = [0x00; 0x03];
+        // msg.receiver_index = little_endian(initiator.sender_index)
+        msg.r_i
+            .set(u32::from_le_bytes(src[0x04..0x08].try_into().unwrap()));
+        // msg.nonce = RAND(24)
+        rng.fill_bytes(&mut msg.n_c);
+        // msg.encrypted_cookie = XAEAD(HASH(LABEL_COOKIE || responder.static_public), msg.nonce, cookie, last_received_msg.mac1)
+        let m_1 = &src[src.len() - 0x20..src.len() - 0x10];
+        let (lhs, rhs) = msg.e_c.split_at_mut(0x10);
+        lhs.copy_from_slice(&cookie(r_m, s_a));
+        xseal(self.c_k, msg.n_c, m_1, lhs, rhs)?;
+        debug!("issued cookie");
+        Ok(())
+    }
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err(level = "debug", Display), fields(peer = %Fingerprint(p_k.public()), index = msg.r_i.get())))]
+pub fn recv_cookie_reply(
+    p_k: &PeerKeys,    // precomputed keys of whoever sent the cookie reply
+    m_1: [u8; 0x10],   // mac1 of the handshake message it answers
+    msg: &CookieReply, // source buffer
+) -> Result<[u8; 0x10]> {
+    // msg.encrypted_cookie = XAEAD(HASH(LABEL_COOKIE || responder.static_public), msg.nonce, cookie, last_received_msg.mac1)
+    let mut e_c = msg.e_c;
+    let (lhs, rhs) = e_c.split_at_mut(0x10);
+    xopen(p_k.c_k, msg.n_c, m_1, &mut *lhs, rhs)
+        .map_err(|e| Error::from(e).with_peer(Peer::Key(p_k.p_p)))?;
+    debug!("received cookie");
+    Ok(lhs.try_into().unwrap())
+}
+
+// cookie = MAC(responder.changing_secret_every_two_minutes, initiator.ip_address || initiator.udp_port)
+fn cookie(r_m: [u8; 0x20], s_a: SocketAddr) -> [u8; 0x10] {
+    let mut txt = [0x00; 0x12];
+    let len = match s_a {
+        SocketAddr::V4(s_a) => {
+            txt[0x00..0x04].copy_from_slice(&s_a.ip().octets());
+            0x04
+        }
+        SocketAddr::V6(s_a) => {
+            txt[0x00..0x10].copy_from_slice(&s_a.ip().octets());
+            0x10
+        }
+    };
+    txt[len..len + 0x02].copy_from_slice(&s_a.port().to_be_bytes());
+    mac(r_m, &txt[0x00..len + 0x02])
+}
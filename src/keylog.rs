@@ -0,0 +1,211 @@
+// key log in the format the Wireshark WireGuard dissector reads from wg.keylog_file
+// anything written here decrypts every captured session, so it stays off unless asked for by name
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand_core::{CryptoRng, CryptoRngCore, RngCore};
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+use x25519::ReusableSecret;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+// every handshake from here on writes its secrets to sink, for debugging only
+pub fn dangerously_log_session_keys_to(sink: impl Write + Send + 'static) {
+    *SINK.lock().unwrap() = Some(Box::new(sink));
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn stop_logging_session_keys() {
+    ENABLED.store(false, Ordering::Release);
+    if let Some(mut sink) = SINK.lock().unwrap().take() {
+        let _ = sink.flush();
+    }
+}
+
+pub fn is_logging_session_keys() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+// ReusableSecret cannot be read back, so ephemerals that should show up in the log have to be made here
+pub fn ephemeral(rng: &mut impl CryptoRngCore) -> ReusableSecret {
+    if !is_logging_session_keys() {
+        return ReusableSecret::random_from_rng(rng);
+    }
+    let mut tee = Tee {
+        rng,
+        out: [0x00; 0x20],
+        len: 0x00,
+    };
+    let e_s = ReusableSecret::random_from_rng(&mut tee);
+    if tee.len == 0x20 {
+        log(&[("LOCAL_EPHEMERAL_PRIVATE_KEY", &tee.out)]);
+    }
+    e_s
+}
+
+pub(crate) fn log(entries: &[(&str, &[u8; 0x20])]) {
+    if !is_logging_session_keys() {
+        return;
+    }
+    let mut sink = SINK.lock().unwrap();
+    if let Some(sink) = sink.as_mut() {
+        let mut out = String::new();
+        for (label, key) in entries {
+            out.push_str(label);
+            out.push_str(" = ");
+            out.push_str(&STANDARD.encode(key));
+            out.push('\n');
+        }
+        let _ = sink.write_all(out.as_bytes()).and_then(|_| sink.flush());
+    }
+}
+
+// remembers the bytes handed out, which become the secret unchanged
+struct Tee<'a, R> {
+    rng: &'a mut R,
+    out: [u8; 0x20],
+    len: usize,
+}
+
+impl<R: CryptoRngCore> RngCore for Tee<'_, R> {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest);
+        self.record(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.rng.try_fill_bytes(dest)?;
+        self.record(dest);
+        Ok(())
+    }
+}
+
+impl<R: CryptoRngCore> CryptoRng for Tee<'_, R> {}
+
+impl<R> Tee<'_, R> {
+    fn record(&mut self, bytes: &[u8]) {
+        let take = bytes.len().min(0x20 - self.len);
+        self.out[self.len..self.len + take].copy_from_slice(&bytes[0x00..take]);
+        self.len += take;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::{Device, LoadMonitor},
+        packet::{HandshakeInit, HandshakeResp},
+        ratelimiter::RateLimiter,
+        tunnel::Tunnel,
+    };
+    use std::{io, sync::Arc, time::Duration};
+    use x25519::StaticSecret;
+    use zerocopy::FromZeroes;
+
+    // the same byte over and over, so the ephemerals are known up front
+    struct Fill(u8);
+
+    impl RngCore for Fill {
+        fn next_u32(&mut self) -> u32 {
+            rand_core::impls::next_u32_via_fill(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            rand_core::impls::next_u64_via_fill(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.fill(self.0);
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for Fill {}
+
+    // a sink the test can still read once the log has it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handshake_lines() {
+        let device = |secret| {
+            Arc::new(Device::new(
+                StaticSecret::from(secret),
+                LoadMonitor::default(),
+                RateLimiter::default(),
+            ))
+        };
+        let (a, b) = (device([0x01; 0x20]), device([0x02; 0x20]));
+        let (a_p, b_p) = (*a.public(), *b.public());
+        let a = Tunnel::new(a, b_p, [0x2a; 0x20]);
+        let b = Tunnel::new(b, a_p, [0x2a; 0x20]);
+        let now = Duration::from_secs(0x6553f100);
+
+        let sink = Shared::default();
+        dangerously_log_session_keys_to(sink.clone());
+        let mut init = HandshakeInit::new_zeroed();
+        a.send_handshake_init(0x07, ephemeral(&mut Fill(0x03)), now, None, &mut init)
+            .unwrap();
+        let mut resp = HandshakeResp::new_zeroed();
+        b.recv_handshake_init(
+            &init,
+            0x09,
+            ephemeral(&mut Fill(0x04)),
+            now,
+            None,
+            &mut resp,
+        )
+        .unwrap();
+        a.recv_handshake_resp(&resp, now).unwrap();
+        stop_logging_session_keys();
+
+        // other tests may be running handshakes at the same time, their lines are let through
+        let log = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        for line in [
+            // a sends the initiation
+            "LOCAL_EPHEMERAL_PRIVATE_KEY = AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=",
+            "REMOTE_STATIC_PUBLIC_KEY = zo060cy2M+x7cMF4FKXHbs0CloUFDTRHRboFhw5YfVk=",
+            // b answers it
+            "LOCAL_STATIC_PRIVATE_KEY = AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=",
+            "REMOTE_STATIC_PUBLIC_KEY = pOCSkrZRwni5dyxWn1+puxPZBrRqtoyd+dwrRAn4ogk=",
+            "LOCAL_EPHEMERAL_PRIVATE_KEY = BAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQ=",
+            "PRESHARED_KEY = KioqKioqKioqKioqKioqKioqKioqKioqKioqKioqKio=",
+            // a takes the response
+            "LOCAL_STATIC_PRIVATE_KEY = AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=",
+        ] {
+            assert!(lines.contains(&line), "{line} missing from\n{log}");
+        }
+        assert!(!is_logging_session_keys());
+    }
+}
@@ -1,7 +1,45 @@
-pub mod async_tunnel;
+pub mod capture;
+mod checksum;
 pub mod cipher;
+#[cfg(feature = "proxy")]
+pub mod config;
 pub mod crypto;
+#[cfg(target_os = "linux")]
+pub mod datapath;
+pub mod device;
+pub mod driver;
 pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "proxy")]
+pub mod forward;
+pub mod handoff;
 pub mod handshake;
+pub mod keylog;
+#[cfg(feature = "mlkem")]
+pub mod mlkem;
+#[cfg(feature = "netstack")]
+pub mod netstack;
+pub mod obfuscation;
 pub mod packet;
+pub mod pipeline;
+pub mod pool;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+pub mod psk;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod ratelimiter;
+pub mod stats;
+pub mod timestamp;
+mod trace;
+pub mod transport;
+#[cfg(target_os = "linux")]
+pub mod tun;
 pub mod tunnel;
+#[cfg(target_os = "linux")]
+pub mod udp;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "websocket")]
+pub mod websocket;
@@ -0,0 +1,241 @@
+use rand_core::OsRng;
+use std::{
+    env, fs,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
+    process::ExitCode,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+use x25519::{PublicKey, StaticSecret};
+
+use shyvana::{
+    config::{Config, Outer},
+    device::{Device, LoadMonitor},
+    driver::Driver,
+    netstack::NetStack,
+    proxy::Proxy,
+    ratelimiter::RateLimiter,
+    transport::{TcpTransport, Transport},
+    tunnel::Tunnel,
+    websocket::WsTransport,
+};
+#[cfg(target_os = "linux")]
+use shyvana::{datapath::DataPath, pipeline::Pipeline, tun::Tun, udp};
+
+const USAGE: &str =
+    "usage: shyvana proxy <wg-quick config> [listen address, 127.0.0.1:1080 by default]
+       shyvana forward <wg-quick config>
+       shyvana tun <wg-quick config> [interface name [crypto workers, one per core by default]]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(0x01).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["proxy", config] => proxy(config, "127.0.0.1:1080"),
+        ["proxy", config, listen] => proxy(config, listen),
+        ["forward", config] => forward(config),
+        #[cfg(target_os = "linux")]
+        ["tun", config] => tun(config, "shyvana%d", None),
+        #[cfg(target_os = "linux")]
+        ["tun", config, name] => tun(config, name, None),
+        #[cfg(target_os = "linux")]
+        ["tun", config, name, workers] => match workers.parse() {
+            Ok(workers) => tun(config, name, Some(workers)),
+            Err(_) => Err(io::Error::new(ErrorKind::InvalidInput, USAGE)),
+        },
+        _ => Err(io::Error::new(ErrorKind::InvalidInput, USAGE)),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("shyvana: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// SOCKS5 and HTTP CONNECT on listen, plus the config's forwards, through the one peer in config
+fn proxy(config: &str, listen: &str) -> io::Result<()> {
+    let config = Config::parse(&fs::read_to_string(config)?)?;
+    let listener = TcpListener::bind(listen)?;
+    let (stack, run) = start(&config)?;
+    eprintln!(
+        "shyvana: proxying on {} through {}",
+        listener.local_addr()?,
+        peer(&config)
+    );
+    forward_all(&stack, &config);
+    thread::spawn(move || Arc::new(Proxy::new(stack, config.dns)).serve(listener));
+    wait(run)
+}
+
+// only the config's forwards, for when nothing else should listen
+fn forward(config: &str) -> io::Result<()> {
+    let config = Config::parse(&fs::read_to_string(config)?)?;
+    if config.forwards.is_empty() {
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "no Local or Remote in a [Forward] section",
+        ))?
+    }
+    let (stack, run) = start(&config)?;
+    eprintln!("shyvana: forwarding through {}", peer(&config));
+    forward_all(&stack, &config);
+    wait(run)
+}
+
+// a kernel TUN device for the peer in config, addresses and routes are left to ip like with wg
+#[cfg(target_os = "linux")]
+fn tun(config: &str, name: &str, workers: Option<usize>) -> io::Result<()> {
+    let config = Config::parse(&fs::read_to_string(config)?)?;
+    let Outer::Udp = config.peer.transport else {
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "only Transport = udp works with a TUN device",
+        ))?
+    };
+    let udp = udp::UdpSocket::bind(SocketAddr::new(
+        any(&config),
+        config.listen_port.unwrap_or(0x00),
+    ))?;
+    let tun = Tun::open(name)?;
+    let pipeline = workers.map_or_else(Pipeline::default, Pipeline::new);
+    eprintln!(
+        "shyvana: {} up on {} with {} crypto workers, peer at {}",
+        tun.name(),
+        udp.inner().local_addr()?,
+        pipeline.workers(),
+        peer(&config)
+    );
+    let driver = Driver::new(
+        tunnel(&config)?,
+        config.peer.endpoint,
+        config.peer.persistent_keepalive,
+    );
+    DataPath::new(Arc::new(driver), tun, udp, pipeline).run(|| OsRng)
+}
+
+fn tunnel(config: &Config) -> io::Result<Arc<Tunnel>> {
+    let device = Arc::new(Device::new(
+        StaticSecret::from(config.private_key),
+        LoadMonitor::default(),
+        RateLimiter::default(),
+    ));
+    let tunnel = Tunnel::new(
+        device,
+        PublicKey::from(config.peer.public_key),
+        config.peer.preshared_key,
+    );
+    tunnel
+        .set_obfuscation(config.peer.obfuscation)
+        .map_err(io::Error::other)?;
+    Ok(Arc::new(tunnel))
+}
+
+// sets up the userspace stack and starts talking to the peer, no TUN device needed
+fn start(config: &Config) -> io::Result<(Arc<NetStack>, JoinHandle<io::Result<()>>)> {
+    let tunnel = tunnel(config)?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let endpoint = config.peer.endpoint;
+    let driver = Driver::new(tunnel, endpoint, config.peer.persistent_keepalive);
+    let stack = Arc::new(NetStack::new(
+        Arc::new(driver),
+        &config.addresses,
+        now,
+        &mut OsRng,
+    ));
+
+    let runner = stack.clone();
+    let run = match (&config.peer.transport, config.listen_port) {
+        (Outer::Udp, port) => {
+            let Some(endpoint) = endpoint else {
+                Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "Transport = udp needs an Endpoint without a TUN device",
+                ))?
+            };
+            let socket = UdpSocket::bind(SocketAddr::new(any(config), port.unwrap_or(0x00)))?;
+            thread::spawn(move || runner.run(&socket, endpoint, &mut OsRng))
+        }
+        // the side with a ListenPort is the server, it takes the peer's connection and a new one whenever that drops
+        (outer, Some(port)) => {
+            let listener = TcpListener::bind(SocketAddr::new(any(config), port))?;
+            let websocket = matches!(outer, Outer::WebSocket { .. });
+            thread::spawn(move || loop {
+                let accepted = if websocket {
+                    WsTransport::accept(&listener)
+                        .map(|socket| (socket.peer(), Box::new(socket) as Box<dyn Transport>))
+                } else {
+                    TcpTransport::accept(&listener)
+                        .map(|socket| (socket.peer(), Box::new(socket) as Box<dyn Transport>))
+                };
+                // a probe or a failed upgrade should not take the server down
+                let (peer, socket) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("shyvana: could not take a connection, {e}");
+                        thread::sleep(Duration::from_millis(0x64));
+                        continue;
+                    }
+                };
+                eprintln!("shyvana: peer connected from {peer}");
+                if let Err(e) = runner.run(&*socket, peer, &mut OsRng) {
+                    eprintln!("shyvana: peer at {peer} went away, {e}");
+                }
+            })
+        }
+        (outer, None) => {
+            // parse makes sure there is one or the other
+            let endpoint = endpoint.unwrap();
+            let socket: Box<dyn Transport> = match outer {
+                Outer::WebSocket { host, path } => {
+                    Box::new(WsTransport::connect(endpoint, host, path, OsRng)?)
+                }
+                _ => Box::new(TcpTransport::connect(endpoint)?),
+            };
+            thread::spawn(move || runner.run(&*socket, endpoint, &mut OsRng))
+        }
+    };
+    Ok((stack, run))
+}
+
+// where the tunnel goes, for the startup line
+fn peer(config: &Config) -> String {
+    match (config.peer.endpoint, config.listen_port) {
+        (Some(endpoint), _) => endpoint.to_string(),
+        (None, port) => format!("whoever reaches port {}", port.unwrap_or_default()),
+    }
+}
+
+// the unspecified address in the endpoint's family, IPv4 when there is none
+fn any(config: &Config) -> IpAddr {
+    match config.peer.endpoint {
+        Some(SocketAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+        _ => Ipv4Addr::UNSPECIFIED.into(),
+    }
+}
+
+// a rule that fails is logged and left down, the others keep going
+fn forward_all(stack: &Arc<NetStack>, config: &Config) {
+    for rule in config.forwards.iter().copied() {
+        let stack = stack.clone();
+        thread::spawn(move || {
+            if let Err(e) = rule.serve(stack) {
+                eprintln!("shyvana: [{rule}] {e}");
+            }
+        });
+    }
+}
+
+// nothing is any use without the tunnel, so whichever way run ends is the way out
+fn wait(run: JoinHandle<io::Result<()>>) -> io::Result<()> {
+    loop {
+        if run.is_finished() {
+            return run.join().unwrap();
+        }
+        thread::sleep(Duration::from_millis(0x64));
+    }
+}
@@ -0,0 +1,797 @@
+use rand_core::CryptoRngCore;
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, DeviceCapabilities, Medium},
+    socket::{tcp, udp},
+    time::Instant,
+    wire::{HardwareAddress, IpAddress, IpCidr},
+};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    driver::{Action, Driver},
+    error::Result,
+    transport::Transport,
+    tunnel::Tunnel,
+};
+
+// the most a tunnel can carry once WireGuard and the outer UDP/IPv6 headers are paid for
+pub const MTU: usize = 0x0578 - 0x50;
+
+// run never sleeps longer than this, so writes from other threads go out promptly
+const MAX_WAIT: Duration = Duration::from_millis(0x0a);
+
+// how long connect waits for the handshake with the far end
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(0x0a);
+
+// room for anything the driver hands back besides inner packets, handshakes and junk included
+const MAX_DATAGRAM: usize = 0x0800;
+
+const TCP_BUFFER: usize = 0x10000;
+const UDP_BUFFER: usize = 0x10000;
+const UDP_PACKETS: usize = 0x40;
+
+// packets waiting to go in or out of the stack
+#[derive(Default)]
+struct Queue {
+    rx: VecDeque<Vec<u8>>, // decrypted, waiting for the stack
+    tx: VecDeque<Vec<u8>>, // from the stack, waiting to be encrypted
+}
+
+struct Rx(Vec<u8>);
+
+struct Tx<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::RxToken for Rx {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for Tx<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut packet = vec![0x00; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+impl phy::Device for Queue {
+    type RxToken<'a> = Rx;
+    type TxToken<'a> = Tx<'a>;
+
+    fn receive(&mut self, _: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((Rx(packet), Tx(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _: Instant) -> Option<Self::TxToken<'_>> {
+        Some(Tx(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = MTU;
+        capabilities
+    }
+}
+
+struct Inner {
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    queue: Queue,
+    closing: Vec<SocketHandle>, // dropped streams still saying goodbye
+    port: u16,                  // next ephemeral port
+}
+
+impl Inner {
+    fn ephemeral(&mut self) -> u16 {
+        self.port = match self.port {
+            0xffff => 0xc000,
+            port => port + 0x01,
+        };
+        self.port
+    }
+}
+
+// a userspace TCP/IP stack behind a tunnel, for when there is no TUN device to be had
+// nothing happens on its own, whoever owns the UDP socket feeds recv and sends what poll returns
+pub struct NetStack {
+    driver: Arc<Driver>,
+    inner: Mutex<Inner>,
+    ready: Condvar, // signalled after every poll, streams wait on it
+}
+
+impl NetStack {
+    pub fn new(
+        driver: Arc<Driver>,
+        addresses: &[(IpAddr, u8)], // addresses assigned to this side of the tunnel
+        now: Duration,              // duration since UNIX epoch
+        rng: &mut impl CryptoRngCore, // seeds TCP sequence numbers and ports
+    ) -> Self {
+        let mut queue = Queue::default();
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = rng.next_u64();
+        let mut iface = Interface::new(config, &mut queue, instant(now));
+        iface.update_ip_addrs(|cidrs| {
+            for (address, prefix) in addresses {
+                let _ = cidrs.push(IpCidr::new(IpAddress::from(*address), *prefix));
+            }
+        });
+        // everything goes to the peer, there are no neighbours to resolve on a tunnel
+        for (address, _) in addresses {
+            let _ = match address {
+                IpAddr::V4(address) => iface
+                    .routes_mut()
+                    .add_default_ipv4_route(*address)
+                    .map(|_| ()),
+                IpAddr::V6(address) => iface
+                    .routes_mut()
+                    .add_default_ipv6_route(*address)
+                    .map(|_| ()),
+            };
+        }
+        let port = 0xc000 | rng.next_u32() as u16;
+        Self {
+            driver,
+            inner: Mutex::new(Inner {
+                iface,
+                sockets: SocketSet::new(Vec::new()),
+                queue,
+                closing: Vec::new(),
+                port,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    // a datagram from the peer at src, through the driver like any other
+    // what comes back goes to src, the answer to a handshake or a cookie reply
+    pub fn recv(
+        &self,
+        datagram: &mut [u8],
+        src: SocketAddr,
+        now: Duration,
+        rng: &mut impl CryptoRngCore,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut out = vec![0x00; datagram.len().max(MAX_DATAGRAM)];
+        match self.driver.decapsulate(datagram, src, now, rng, &mut out)? {
+            Action::Network(length) => Ok(Some(out[0x00..length].to_vec())),
+            Action::Inner(length) => {
+                self.push(&out[0x00..length]);
+                Ok(None)
+            }
+            Action::Idle => Ok(None),
+        }
+    }
+
+    // runs the timers and the stack and returns datagrams for the driver's endpoint
+    // a packet that cannot be sealed is dropped on its own, TCP will send it again once there is a session
+    pub fn poll(&self, now: Duration, rng: &mut impl CryptoRngCore) -> Result<Vec<Vec<u8>>> {
+        let mut out = vec![0x00; MAX_DATAGRAM];
+        let mut datagrams = Vec::new();
+        while let Action::Network(length) = self.driver.tick(now, rng, &mut out)? {
+            datagrams.push(out[0x00..length].to_vec());
+        }
+        for packet in self.step(now) {
+            if let Ok(Action::Network(length)) =
+                self.driver.encapsulate(&packet, now, rng, &mut out)
+            {
+                datagrams.push(out[0x00..length].to_vec());
+            }
+        }
+        Ok(datagrams)
+    }
+
+    // runs the stack and returns the inner packets it wants sent
+    fn step(&self, now: Duration) -> Vec<Vec<u8>> {
+        let packets = {
+            let mut inner = self.lock();
+            let Inner {
+                iface,
+                sockets,
+                queue,
+                closing,
+                ..
+            } = &mut *inner;
+            iface.poll(instant(now), queue, sockets);
+            closing.retain(|handle| {
+                let done = matches!(
+                    sockets.get::<tcp::Socket>(*handle).state(),
+                    tcp::State::Closed | tcp::State::TimeWait
+                );
+                if done {
+                    sockets.remove(*handle);
+                }
+                !done
+            });
+            queue.tx.drain(..).collect()
+        };
+        self.ready.notify_all();
+        packets
+    }
+
+    // an inner packet for the stack to take in at its next poll
+    fn push(&self, packet: &[u8]) {
+        self.inner
+            .lock()
+            .unwrap()
+            .queue
+            .rx
+            .push_back(packet.to_vec());
+    }
+
+    // how long poll can wait before the stack has something to do, none if only incoming packets matter
+    pub fn poll_delay(&self, now: Duration) -> Option<Duration> {
+        let mut inner = self.lock();
+        let Inner { iface, sockets, .. } = &mut *inner;
+        iface
+            .poll_delay(instant(now), sockets)
+            .map(|delay| Duration::from_micros(delay.total_micros()))
+    }
+
+    // connects to address through the tunnel, failing with TimedOut after CONNECT_TIMEOUT
+    pub fn connect(self: &Arc<Self>, address: SocketAddr) -> io::Result<TcpStream> {
+        self.connect_timeout(address, CONNECT_TIMEOUT)
+    }
+
+    // like connect, but gives up after timeout
+    pub fn connect_timeout(
+        self: &Arc<Self>,
+        address: SocketAddr,
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        let stream = self.open(address)?;
+        self.wait_timeout(Some(timeout), |inner| {
+            match inner.sockets.get::<tcp::Socket>(stream.handle).state() {
+                tcp::State::SynSent | tcp::State::SynReceived => None,
+                tcp::State::Closed => Some(Err(io::Error::from(io::ErrorKind::ConnectionRefused))),
+                _ => Some(Ok(())),
+            }
+        })
+        .ok_or_else(|| io::Error::from(io::ErrorKind::TimedOut))??;
+        Ok(stream)
+    }
+
+    // sends the SYN and hands the stream out right away
+    fn open(self: &Arc<Self>, address: SocketAddr) -> io::Result<TcpStream> {
+        let mut inner = self.lock();
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0x00; TCP_BUFFER]),
+            tcp::SocketBuffer::new(vec![0x00; TCP_BUFFER]),
+        );
+        let port = inner.ephemeral();
+        socket
+            .connect(inner.iface.context(), address, port)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let handle = inner.sockets.add(socket);
+        Ok(TcpStream {
+            stack: self.clone(),
+            handle,
+        })
+    }
+
+    // waits for one connection from the peer on port, reads and writes block until it arrives
+    pub fn listen(self: &Arc<Self>, port: u16) -> io::Result<TcpStream> {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0x00; TCP_BUFFER]),
+            tcp::SocketBuffer::new(vec![0x00; TCP_BUFFER]),
+        );
+        socket
+            .listen(port)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let handle = self.lock().sockets.add(socket);
+        Ok(TcpStream {
+            stack: self.clone(),
+            handle,
+        })
+    }
+
+    // like listen, but only returns once the peer has connected
+    pub fn accept(self: &Arc<Self>, port: u16) -> io::Result<TcpStream> {
+        let stream = self.listen(port)?;
+        self.wait(
+            |inner| match inner.sockets.get::<tcp::Socket>(stream.handle).state() {
+                tcp::State::Listen | tcp::State::SynReceived => None,
+                _ => Some(()),
+            },
+        );
+        Ok(stream)
+    }
+
+    // a UDP socket on the tunnel side, an ephemeral port if port is zero
+    pub fn bind_udp(self: &Arc<Self>, port: u16) -> io::Result<UdpSocket> {
+        let mut inner = self.lock();
+        let mut socket = udp::Socket::new(
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; UDP_PACKETS],
+                vec![0x00; UDP_BUFFER],
+            ),
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; UDP_PACKETS],
+                vec![0x00; UDP_BUFFER],
+            ),
+        );
+        let port = match port {
+            0x00 => inner.ephemeral(),
+            port => port,
+        };
+        socket
+            .bind(port)
+            .map_err(|_| io::Error::from(io::ErrorKind::AddrInUse))?;
+        let handle = inner.sockets.add(socket);
+        Ok(UdpSocket {
+            stack: self.clone(),
+            handle,
+            timeout: Mutex::new(None),
+        })
+    }
+
+    // talks to the peer over socket until it fails, starting and answering handshakes as needed
+    pub fn run(
+        &self,
+        socket: &(impl Transport + ?Sized), // unprivileged UDP socket, or a stream transport, facing the peer
+        endpoint: SocketAddr,               // where the peer is to be found for now
+        rng: &mut impl CryptoRngCore,       // source of indices and ephemerals
+    ) -> io::Result<()> {
+        self.driver.set_endpoint(Some(endpoint));
+        let mut buffer = vec![0x00; 0x10000];
+        loop {
+            let now = clock();
+            let datagrams = self.poll(now, rng).map_err(io::Error::other)?;
+            // it roams with authenticated data from the peer
+            let endpoint = self.driver.endpoint().unwrap_or(endpoint);
+            for datagram in datagrams {
+                socket.send_to(&datagram, endpoint)?;
+            }
+
+            let wait = self.poll_delay(now).unwrap_or(MAX_WAIT);
+            let wait = wait.clamp(Duration::from_millis(0x01), MAX_WAIT);
+            let (length, src) = match socket.recv_from(&mut buffer, Some(wait)) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => Err(e)?,
+            };
+            if let Ok(Some(reply)) = self.recv(&mut buffer[0x00..length], src, clock(), rng) {
+                socket.send_to(&reply, src)?;
+            }
+        }
+    }
+
+    pub fn driver(&self) -> &Arc<Driver> {
+        &self.driver
+    }
+
+    pub fn tunnel(&self) -> &Arc<Tunnel> {
+        self.driver.tunnel()
+    }
+
+    // blocks until f has an answer, f gets another go after every poll
+    fn wait<T>(&self, f: impl FnMut(&mut Inner) -> Option<T>) -> T {
+        self.wait_timeout(None, f).unwrap()
+    }
+
+    // same as wait, but gives up with none once timeout has passed
+    fn wait_timeout<T>(
+        &self,
+        timeout: Option<Duration>,
+        mut f: impl FnMut(&mut Inner) -> Option<T>,
+    ) -> Option<T> {
+        let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            if let Some(value) = f(&mut inner) {
+                return Some(value);
+            }
+            inner = match deadline {
+                Some(deadline) => {
+                    let left = deadline.checked_duration_since(std::time::Instant::now())?;
+                    self.ready.wait_timeout(inner, left).unwrap().0
+                }
+                None => self.ready.wait(inner).unwrap(),
+            };
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+}
+
+pub struct TcpStream {
+    stack: Arc<NetStack>,
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    // no more writes, the peer reads to the end of what was sent
+    pub fn shutdown(&self) {
+        self.stack
+            .lock()
+            .sockets
+            .get_mut::<tcp::Socket>(self.handle)
+            .close();
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        let inner = self.stack.lock();
+        let endpoint = inner
+            .sockets
+            .get::<tcp::Socket>(self.handle)
+            .local_endpoint()?;
+        Some(SocketAddr::new(endpoint.addr.into(), endpoint.port))
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        let inner = self.stack.lock();
+        let endpoint = inner
+            .sockets
+            .get::<tcp::Socket>(self.handle)
+            .remote_endpoint()?;
+        Some(SocketAddr::new(endpoint.addr.into(), endpoint.port))
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Read for &TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stack.wait(|inner| {
+            let socket = inner.sockets.get_mut::<tcp::Socket>(self.handle);
+            if socket.can_recv() {
+                return Some(
+                    socket
+                        .recv_slice(buf)
+                        .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset)),
+                );
+            }
+            match socket.state() {
+                tcp::State::Listen | tcp::State::SynSent | tcp::State::SynReceived => None,
+                _ if socket.may_recv() => None,
+                _ => Some(Ok(0x00)),
+            }
+        })
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Write for &TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stack.wait(|inner| {
+            let socket = inner.sockets.get_mut::<tcp::Socket>(self.handle);
+            if socket.can_send() {
+                return Some(
+                    socket
+                        .send_slice(buf)
+                        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe)),
+                );
+            }
+            match socket.state() {
+                tcp::State::Listen | tcp::State::SynSent | tcp::State::SynReceived => None,
+                _ if socket.may_send() => None,
+                _ => Some(Err(io::Error::from(io::ErrorKind::BrokenPipe))),
+            }
+        })
+    }
+
+    // waits until the peer has acknowledged everything written
+    fn flush(&mut self) -> io::Result<()> {
+        self.stack.wait(|inner| {
+            let socket = inner.sockets.get::<tcp::Socket>(self.handle);
+            match socket.send_queue() {
+                0x00 => Some(Ok(())),
+                _ if !socket.may_send() && !socket.is_active() => {
+                    Some(Err(io::Error::from(io::ErrorKind::BrokenPipe)))
+                }
+                _ => None,
+            }
+        })
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut inner = self.stack.lock();
+        inner.sockets.get_mut::<tcp::Socket>(self.handle).close();
+        inner.closing.push(self.handle);
+    }
+}
+
+pub struct UdpSocket {
+    stack: Arc<NetStack>,
+    handle: SocketHandle,
+    timeout: Mutex<Option<Duration>>, // how long recv_from waits
+}
+
+impl UdpSocket {
+    // queued until the next poll, fails if the send buffer is full
+    pub fn send_to(&self, buf: &[u8], address: SocketAddr) -> io::Result<usize> {
+        self.stack
+            .lock()
+            .sockets
+            .get_mut::<udp::Socket>(self.handle)
+            .send_slice(buf, address)
+            .map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+        Ok(buf.len())
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let timeout = *self.timeout.lock().unwrap();
+        self.stack
+            .wait_timeout(timeout, |inner| {
+                let socket = inner.sockets.get_mut::<udp::Socket>(self.handle);
+                let (length, meta) = socket.recv_slice(buf).ok()?;
+                Some((
+                    length,
+                    SocketAddr::new(meta.endpoint.addr.into(), meta.endpoint.port),
+                ))
+            })
+            .ok_or_else(|| io::Error::from(io::ErrorKind::TimedOut))
+    }
+
+    // none blocks forever, like std::net::UdpSocket
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        *self.timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.stack
+            .lock()
+            .sockets
+            .get::<udp::Socket>(self.handle)
+            .endpoint()
+            .port
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.stack.lock().sockets.remove(self.handle);
+    }
+}
+
+fn clock() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn instant(now: Duration) -> Instant {
+    Instant::from_micros(now.as_micros() as i64)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        device::{Device, LoadMonitor},
+        ratelimiter::RateLimiter,
+    };
+    use rand_core::{CryptoRng, RngCore};
+    use std::{
+        net::Ipv4Addr,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::{channel, Receiver, Sender},
+        },
+        thread::{spawn, JoinHandle},
+    };
+    use x25519::StaticSecret;
+
+    pub(crate) const A: IpAddr = IpAddr::V4(Ipv4Addr::new(0x0a, 0x00, 0x00, 0x01));
+    pub(crate) const B: IpAddr = IpAddr::V4(Ipv4Addr::new(0x0a, 0x00, 0x00, 0x02));
+
+    // predictable, which is all a test needs
+    pub(crate) struct Counter(pub(crate) u64);
+
+    impl RngCore for Counter {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_mul(0x5851f42d4c957f2d).wrapping_add(0x01);
+            self.0 >> 0x10
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = self.next_u64() as u8;
+            }
+        }
+
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> core::result::Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for Counter {}
+
+    // one end of an in-process link, whatever is sent comes out of the other end
+    struct Pipe {
+        tx: Sender<Vec<u8>>,
+        rx: Mutex<Receiver<Vec<u8>>>,
+        peer: SocketAddr, // where the other end's messages appear to come from
+        closed: Arc<AtomicBool>,
+    }
+
+    impl Transport for Pipe {
+        fn send_to(&self, msg: &[u8], _: SocketAddr) -> io::Result<()> {
+            // the other end going away is noticed by its own recv
+            let _ = self.tx.send(msg.to_vec());
+            Ok(())
+        }
+
+        fn recv_from(
+            &self,
+            buf: &mut [u8],
+            timeout: Option<Duration>,
+        ) -> io::Result<(usize, SocketAddr)> {
+            if self.closed.load(Ordering::Relaxed) {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))?
+            }
+            let msg = self
+                .rx
+                .lock()
+                .unwrap()
+                .recv_timeout(timeout.unwrap_or(MAX_WAIT))
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?;
+            buf[0x00..msg.len()].copy_from_slice(&msg);
+            Ok((msg.len(), self.peer))
+        }
+    }
+
+    // both stacks running, until this is dropped
+    pub(crate) struct Linked {
+        closed: Arc<AtomicBool>,
+        runs: Vec<JoinHandle<io::Result<()>>>,
+    }
+
+    impl Drop for Linked {
+        fn drop(&mut self) {
+            self.closed.store(true, Ordering::Relaxed);
+            for run in self.runs.drain(..) {
+                let _ = run.join();
+            }
+        }
+    }
+
+    fn stack(secret: [u8; 0x20], peer: [u8; 0x20], address: IpAddr, seed: u64) -> Arc<NetStack> {
+        let device = Arc::new(Device::new(
+            StaticSecret::from(secret),
+            LoadMonitor::default(),
+            RateLimiter::default(),
+        ));
+        let peer = x25519::PublicKey::from(&StaticSecret::from(peer));
+        let tunnel = Arc::new(Tunnel::new(device, peer, [0x00; 0x20]));
+        let driver = Arc::new(Driver::new(tunnel, None, None));
+        Arc::new(NetStack::new(
+            driver,
+            &[(address, 0x18)],
+            clock(),
+            &mut Counter(seed),
+        ))
+    }
+
+    // a at A and b at B, talking over an in-process link
+    pub(crate) fn pair() -> (Arc<NetStack>, Arc<NetStack>, Linked) {
+        let a = stack([0x01; 0x20], [0x02; 0x20], A, 0x01);
+        let b = stack([0x02; 0x20], [0x01; 0x20], B, 0x02);
+        let (a_at, b_at) = (
+            SocketAddr::from(([0xc0, 0x00, 0x02, 0x01], 0xca6c)),
+            SocketAddr::from(([0xc0, 0x00, 0x02, 0x02], 0xca6c)),
+        );
+        let closed = Arc::new(AtomicBool::new(false));
+        let ((a_tx, b_rx), (b_tx, a_rx)) = (channel(), channel());
+        let link = |tx, rx, peer| Pipe {
+            tx,
+            rx: Mutex::new(rx),
+            peer,
+            closed: closed.clone(),
+        };
+        let (a_pipe, b_pipe) = (link(a_tx, a_rx, b_at), link(b_tx, b_rx, a_at));
+        let runs = vec![
+            {
+                let a = a.clone();
+                spawn(move || a.run(&a_pipe, b_at, &mut Counter(0x03)))
+            },
+            {
+                let b = b.clone();
+                spawn(move || b.run(&b_pipe, a_at, &mut Counter(0x04)))
+            },
+        ];
+        (a, b, Linked { closed, runs })
+    }
+
+    #[test]
+    fn tcp_echo() {
+        let (a, b, _linked) = pair();
+        // listening before the SYN can get there, so it is not refused
+        let mut server = b.listen(0x1f90).unwrap();
+        let echo = spawn(move || {
+            let mut buffer = [0x00; 0x10];
+            let length = server.read(&mut buffer).unwrap();
+            server.write_all(&buffer[0x00..length]).unwrap();
+            server.flush().unwrap();
+        });
+        let mut stream = a.connect(SocketAddr::new(B, 0x1f90)).unwrap();
+        assert_eq!(stream.peer_addr(), Some(SocketAddr::new(B, 0x1f90)));
+        stream.write_all(b"ping").unwrap();
+        let mut buffer = [0x00; 0x04];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn udp_round_trip() {
+        let (a, b, _linked) = pair();
+        let (client, server) = (a.bind_udp(0x00).unwrap(), b.bind_udp(0x35).unwrap());
+        server.set_read_timeout(Some(Duration::from_secs(0x05)));
+        // answers the first ping that makes it through, and hands the socket back
+        // dropping it would take the answer with it, that only leaves at the next poll
+        let echo = spawn(move || {
+            let mut buffer = [0x00; 0x10];
+            let (length, src) = server.recv_from(&mut buffer).unwrap();
+            server.send_to(&buffer[0x00..length], src).unwrap();
+            server
+        });
+        // nothing gets through before the handshake, so keep asking
+        client.set_read_timeout(Some(Duration::from_millis(0xc8)));
+        let mut buffer = [0x00; 0x10];
+        let (length, src) = (0x00..0x32)
+            .find_map(|_| {
+                client.send_to(b"ping", SocketAddr::new(B, 0x35)).unwrap();
+                client.recv_from(&mut buffer).ok()
+            })
+            .unwrap();
+        assert_eq!(
+            (&buffer[0x00..length], src),
+            (&b"ping"[..], SocketAddr::new(B, 0x35))
+        );
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn connect_times_out() {
+        // nobody runs this stack, so the SYN never leaves
+        let a = stack([0x01; 0x20], [0x02; 0x20], A, 0x01);
+        let err = a
+            .connect_timeout(SocketAddr::new(B, 0x1f90), Duration::from_millis(0x32))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
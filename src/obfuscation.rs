@@ -0,0 +1,252 @@
+use rand_core::CryptoRngCore;
+use std::mem::size_of;
+
+use crate::{
+    error::{Error, Result},
+    packet::{CookieReply, HandshakeInit, HandshakeResp, TransportData},
+};
+
+// junk and padded handshakes stay under the smallest MTU anyone still uses
+const MAX_DATAGRAM: usize = 0x0500;
+
+// AmneziaWG-style disguise for the messages of one peer
+// there is no way to negotiate it on the wire without giving it away, so both ends have to be set up alike
+// the default leaves every message exactly as standard WireGuard sends it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Obfuscation {
+    pub junk_count: u8,       // Jc, random datagrams sent ahead of every initiation
+    pub junk_min: u16,        // Jmin, shortest junk datagram
+    pub junk_max: u16,        // Jmax, longest junk datagram
+    pub init_padding: u16,    // S1, random bytes in front of every initiation
+    pub resp_padding: u16,    // S2, random bytes in front of every response
+    pub headers: [u32; 0x04], // H1 to H4, sent in place of message types 1 to 4 and the reserved bytes
+}
+
+impl Default for Obfuscation {
+    fn default() -> Self {
+        Self {
+            junk_count: 0x00,
+            junk_min: 0x00,
+            junk_max: 0x00,
+            init_padding: 0x00,
+            resp_padding: 0x00,
+            headers: [0x01, 0x02, 0x03, 0x04],
+        }
+    }
+}
+
+impl Obfuscation {
+    pub fn is_standard(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // messages have to stay tellable apart once disguised, and fit in a datagram
+    pub fn validate(&self) -> Result<()> {
+        let [h_1, h_2, h_3, h_4] = self.headers;
+        let distinct =
+            h_1 != h_2 && h_1 != h_3 && h_1 != h_4 && h_2 != h_3 && h_2 != h_4 && h_3 != h_4;
+        let fits = self.junk_min <= self.junk_max
+            && self.junk_max as usize <= MAX_DATAGRAM
+            && self.init_padding as usize + size_of::<HandshakeInit>() <= MAX_DATAGRAM
+            && self.resp_padding as usize + size_of::<HandshakeResp>() <= MAX_DATAGRAM;
+        match distinct && fits {
+            true => Ok(()),
+            false => Err(Error::InvalidObfuscation),
+        }
+    }
+
+    // random datagrams to send right before an initiation, none by default
+    pub fn junk(&self, rng: &mut impl CryptoRngCore) -> Vec<Vec<u8>> {
+        (0x00..self.junk_count)
+            .map(|_| {
+                let spread = (self.junk_max - self.junk_min) as u32 + 0x01;
+                let mut junk =
+                    vec![0x00; self.junk_min as usize + (rng.next_u32() % spread) as usize];
+                rng.fill_bytes(&mut junk);
+                junk
+            })
+            .collect()
+    }
+
+    // disguises a message on its way out, after its macs have been computed over the standard form
+    pub fn wrap(&self, mut msg: Vec<u8>, rng: &mut impl CryptoRngCore) -> Vec<u8> {
+        let (header, padding) = match msg.first() {
+            Some(0x01) => (self.headers[0x00], self.init_padding),
+            Some(0x02) => (self.headers[0x01], self.resp_padding),
+            Some(0x03) => (self.headers[0x02], 0x00),
+            Some(0x04) => (self.headers[0x03], 0x00),
+            _ => return msg,
+        };
+        if let Some(first) = msg.get_mut(0x00..0x04) {
+            first.copy_from_slice(&header.to_le_bytes());
+        }
+        if padding == 0x00 {
+            return msg;
+        }
+        let mut padded = vec![0x00; padding as usize];
+        rng.fill_bytes(&mut padded);
+        padded.append(&mut msg);
+        padded
+    }
+
+    // puts a message back the way standard WireGuard would have sent it, none for junk and strangers
+    pub fn unwrap<'a>(&self, datagram: &'a mut [u8]) -> Option<&'a mut [u8]> {
+        let length = datagram.len();
+        let [h_1, h_2, h_3, h_4] = self.headers;
+        let header = |at: usize| {
+            let header = datagram.get(at..at + 0x04)?;
+            Some(u32::from_le_bytes(header.try_into().ok()?))
+        };
+        let (s_1, s_2) = (self.init_padding as usize, self.resp_padding as usize);
+        let (m_t, at) = if length == s_1 + size_of::<HandshakeInit>() && header(s_1) == Some(h_1) {
+            (0x01u32, s_1)
+        } else if length == s_2 + size_of::<HandshakeResp>() && header(s_2) == Some(h_2) {
+            (0x02, s_2)
+        } else if length == size_of::<CookieReply>() && header(0x00) == Some(h_3) {
+            (0x03, 0x00)
+        } else if length >= size_of::<TransportData>() + 0x10 && header(0x00) == Some(h_4) {
+            (0x04, 0x00)
+        } else {
+            return None;
+        };
+        let msg = &mut datagram[at..];
+        msg[0x00..0x04].copy_from_slice(&m_t.to_le_bytes());
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::{CryptoRng, RngCore};
+
+    // counts up, so padding and junk are not all zeros
+    struct Counter(u8);
+
+    impl RngCore for Counter {
+        fn next_u32(&mut self) -> u32 {
+            self.0 = self.0.wrapping_add(0x01);
+            self.0 as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.next_u32() as u64
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.iter_mut().for_each(|b| *b = self.next_u32() as u8);
+        }
+
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> core::result::Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for Counter {}
+
+    const OBFUSCATED: Obfuscation = Obfuscation {
+        junk_count: 0x03,
+        junk_min: 0x28,
+        junk_max: 0x46,
+        init_padding: 0x20,
+        resp_padding: 0x11,
+        headers: [0x5c1e3b07, 0x0f9a2d61, 0x7b33c8e4, 0x1d47a0f2],
+    };
+
+    // one of each message, as the handshake and cipher code hands them over
+    fn messages() -> [Vec<u8>; 0x04] {
+        let message = |m_t: u8, length: usize| {
+            let mut msg: Vec<u8> = (0x00..length).map(|i| i as u8 | 0x80).collect();
+            msg[0x00..0x04].copy_from_slice(&[m_t, 0x00, 0x00, 0x00]);
+            msg
+        };
+        [
+            message(0x01, size_of::<HandshakeInit>()),
+            message(0x02, size_of::<HandshakeResp>()),
+            message(0x03, size_of::<CookieReply>()),
+            message(0x04, size_of::<TransportData>() + 0x10 + 0x40),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = Counter(0x00);
+        for (obfuscation, padding) in [
+            (Obfuscation::default(), [0x00; 0x04]),
+            (OBFUSCATED, [0x20, 0x11, 0x00, 0x00]),
+        ] {
+            for (i, msg) in messages().into_iter().enumerate() {
+                let mut datagram = obfuscation.wrap(msg.clone(), &mut rng);
+                assert_eq!(datagram.len(), msg.len() + padding[i]);
+                let header = &datagram[padding[i]..padding[i] + 0x04];
+                assert_eq!(header, obfuscation.headers[i].to_le_bytes());
+                assert_eq!(obfuscation.unwrap(&mut datagram).unwrap(), msg);
+            }
+        }
+    }
+
+    #[test]
+    fn default_is_standard() {
+        let mut rng = Counter(0x00);
+        assert!(Obfuscation::default().is_standard());
+        assert!(!OBFUSCATED.is_standard());
+        assert!(Obfuscation::default().junk(&mut rng).is_empty());
+        for msg in messages() {
+            assert_eq!(Obfuscation::default().wrap(msg.clone(), &mut rng), msg);
+        }
+    }
+
+    #[test]
+    fn junk_is_dropped() {
+        let mut rng = Counter(0x00);
+        let junk = OBFUSCATED.junk(&mut rng);
+        assert_eq!(junk.len(), 0x03);
+        for mut junk in junk {
+            assert!((0x28..=0x46).contains(&junk.len()));
+            assert!(OBFUSCATED.unwrap(&mut junk).is_none());
+        }
+        // standard messages do not get through a disguised peer, nor disguised ones a standard peer
+        for msg in messages() {
+            assert!(OBFUSCATED.unwrap(&mut msg.clone()).is_none());
+            let mut datagram = OBFUSCATED.wrap(msg, &mut rng);
+            assert!(Obfuscation::default().unwrap(&mut datagram).is_none());
+        }
+        // the right header at the wrong length
+        let mut datagram = OBFUSCATED.wrap(messages()[0x00].clone(), &mut rng);
+        datagram.push(0x00);
+        assert!(OBFUSCATED.unwrap(&mut datagram).is_none());
+    }
+
+    #[test]
+    fn validate() {
+        let with = |change: fn(&mut Obfuscation)| {
+            let mut obfuscation = OBFUSCATED;
+            change(&mut obfuscation);
+            obfuscation.validate().is_ok()
+        };
+        assert!(Obfuscation::default().validate().is_ok());
+        assert!(OBFUSCATED.validate().is_ok());
+        assert!(!with(|o| o.headers[0x03] = o.headers[0x00]));
+        assert!(!with(|o| o.headers[0x02] = o.headers[0x01]));
+        assert!(with(|o| o.junk_min = o.junk_max));
+        assert!(!with(|o| o.junk_min = o.junk_max + 0x01));
+        assert!(with(|o| o.junk_max = MAX_DATAGRAM as u16));
+        assert!(!with(|o| o.junk_max = MAX_DATAGRAM as u16 + 0x01));
+        assert!(with(|o| {
+            o.init_padding = (MAX_DATAGRAM - size_of::<HandshakeInit>()) as u16
+        }));
+        assert!(!with(|o| {
+            o.init_padding = (MAX_DATAGRAM - size_of::<HandshakeInit>()) as u16 + 0x01
+        }));
+        assert!(with(|o| {
+            o.resp_padding = (MAX_DATAGRAM - size_of::<HandshakeResp>()) as u16
+        }));
+        assert!(!with(|o| {
+            o.resp_padding = (MAX_DATAGRAM - size_of::<HandshakeResp>()) as u16 + 0x01
+        }));
+    }
+}
@@ -1,8 +1,13 @@
+#![forbid(unsafe_code)]
+
 use crate::error::{Error, Result};
 use core::{
     mem::size_of,
     ops::{Deref, DerefMut},
-    slice,
+};
+use zerocopy::{
+    little_endian::{U32, U64},
+    AsBytes, FromBytes, FromZeroes, Unaligned,
 };
 
 macro_rules! define {
@@ -10,46 +15,36 @@ macro_rules! define {
         $(
             $name:ident {
                 $(
-                    $field_name:ident: $field_size:literal
+                    $field_name:ident: $field_type:ty,
                 )+
             }
         )+
     ) => {
         $(
-            #[repr(packed)]
+            #[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
+            #[repr(C)]
             pub struct $name {
                 pub m_t: u8,
                 pub r_0: [u8; 0x03],
                 $(
-                    pub $field_name: [u8; $field_size],
+                    pub $field_name: $field_type,
                 )+
             }
 
             impl $name {
                 pub fn wrap_mut(buffer: &mut [u8]) -> Result<&mut Self> {
-                    if buffer.len() < size_of::<Self>() {
-                        Err(Error::BufferLengthTooShort {
-                            expected: size_of::<Self>(),
-                            got: buffer.len()
-                        })
-                    } else {
-                        unsafe {
-                            Ok(&mut*(buffer as *mut _ as *mut Self))
-                        }
-                    }
+                    let got = buffer.len();
+                    Self::mut_from_prefix(buffer).ok_or(Error::BufferLengthTooShort {
+                        expected: size_of::<Self>(),
+                        got,
+                    })
                 }
 
                 pub fn wrap_ref(buffer: &[u8]) -> Result<&Self> {
-                    if buffer.len() < size_of::<Self>() {
-                        Err(Error::BufferLengthTooShort {
-                            expected: size_of::<Self>(),
-                            got: buffer.len()
-                        })
-                    } else {
-                        unsafe {
-                            Ok(&*(buffer as *const _ as *const Self))
-                        }
-                    }
+                    Self::ref_from_prefix(buffer).ok_or(Error::BufferLengthTooShort {
+                        expected: size_of::<Self>(),
+                        got: buffer.len(),
+                    })
                 }
             }
 
@@ -57,13 +52,13 @@ macro_rules! define {
                 type Target = [u8];
 
                 fn deref(&self) -> &Self::Target {
-                    unsafe { slice::from_raw_parts(self as *const _ as *const u8, size_of::<Self>()) }
+                    self.as_bytes()
                 }
             }
 
             impl DerefMut for $name {
                 fn deref_mut(&mut self) -> &mut Self::Target {
-                    unsafe { slice::from_raw_parts_mut(self as *mut _ as *mut u8, size_of::<Self>()) }
+                    self.as_bytes_mut()
                 }
             }
         )+
@@ -72,23 +67,28 @@ macro_rules! define {
 
 define! {
     HandshakeInit {
-        s_i: 0x04
-        u_e: 0x20
-        e_s: 0x30
-        e_t: 0x1c
-        m_1: 0x10
-        m_2: 0x10
+        s_i: U32,
+        u_e: [u8; 0x20],
+        e_s: [u8; 0x30],
+        e_t: [u8; 0x1c],
+        m_1: [u8; 0x10],
+        m_2: [u8; 0x10],
     }
     HandshakeResp {
-        s_i: 0x04
-        r_i: 0x04
-        u_e: 0x20
-        e_n: 0x10
-        m_1: 0x10
-        m_2: 0x10
+        s_i: U32,
+        r_i: U32,
+        u_e: [u8; 0x20],
+        e_n: [u8; 0x10],
+        m_1: [u8; 0x10],
+        m_2: [u8; 0x10],
+    }
+    CookieReply {
+        r_i: U32,
+        n_c: [u8; 0x18],
+        e_c: [u8; 0x20],
     }
     TransportData {
-        r_i: 0x04
-        cnt: 0x08
+        r_i: U32,
+        cnt: U64,
     }
 }
@@ -0,0 +1,249 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::{available_parallelism, spawn, JoinHandle},
+};
+
+use crate::{
+    cipher::{Decrypted, Decryptor, Encryptor},
+    error::{Error, Result},
+};
+
+type Output = Vec<Result<Vec<u8>>>;
+
+enum Work {
+    // each buffer is laid out like Decrypted, paired with the payload length
+    Encrypt(Encryptor, Vec<(Vec<u8>, usize)>),
+    Decrypt(Arc<Decryptor>, Vec<Vec<u8>>),
+}
+
+struct Job {
+    seq: u64,   // position within the lane
+    work: Work, // packets and the key to process them with
+    ret: Sender<(u64, Output)>,
+}
+
+// workers exit once the pipeline and every lane handed out by it are dropped
+pub struct Pipeline {
+    txs: Vec<Sender<Job>>, // one queue per worker, so no worker waits on another to pick up a job
+    next: Arc<AtomicUsize>, // worker the next job goes to, shared by every lane
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Pipeline {
+    pub fn new(workers: usize) -> Self {
+        let (txs, handles) = (0x00..workers.max(0x01))
+            .map(|_| {
+                let (tx, rx) = channel::<Job>();
+                let handle = spawn(move || {
+                    for job in rx {
+                        work(job);
+                    }
+                });
+                (tx, handle)
+            })
+            .unzip();
+        Self {
+            txs,
+            next: Arc::new(AtomicUsize::new(0x00)),
+            handles,
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn lane(&self) -> Lane {
+        let (ret, rx) = channel();
+        Lane {
+            txs: self.txs.clone(),
+            next: self.next.clone(),
+            ret,
+            rx,
+            s_q: 0x00,
+            r_q: 0x00,
+            pending: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new(available_parallelism().map_or(0x01, |n| n.get()))
+    }
+}
+
+// a per-peer stream of batches that come back out in the order they were submitted
+pub struct Lane {
+    txs: Vec<Sender<Job>>,
+    next: Arc<AtomicUsize>,
+    ret: Sender<(u64, Output)>,
+    rx: Receiver<(u64, Output)>,
+    s_q: u64, // next sequence number to submit
+    r_q: u64, // next sequence number to release
+    pending: BTreeMap<u64, Output>,
+}
+
+impl Lane {
+    // buffers are laid out like Decrypted and paired with their payload length, and come back as complete messages
+    pub fn encrypt(&mut self, encryptor: &mut Encryptor, buffers: Vec<(Vec<u8>, usize)>) {
+        // carve out a counter range for this batch so workers never share a nonce
+        let encryptor = encryptor.reserve(buffers.len() as u64);
+        self.submit(Work::Encrypt(encryptor, buffers));
+    }
+
+    // buffers are complete messages, and come back decrypted in place like Decryptor::decrypt leaves them
+    pub fn decrypt(&mut self, decryptor: &Arc<Decryptor>, buffers: Vec<Vec<u8>>) {
+        self.submit(Work::Decrypt(decryptor.clone(), buffers));
+    }
+
+    // blocks until the next batch in submission order is done, or returns None if nothing is in flight
+    pub fn recv(&mut self) -> Option<Output> {
+        while self.r_q < self.s_q {
+            if let Some(out) = self.pending.remove(&self.r_q) {
+                self.r_q += 1;
+                return Some(out);
+            }
+            let (seq, out) = self.rx.recv().ok()?;
+            self.pending.insert(seq, out);
+        }
+        None
+    }
+
+    // returns the next batch in submission order if it is already done
+    pub fn try_recv(&mut self) -> Option<Output> {
+        while let Ok((seq, out)) = self.rx.try_recv() {
+            self.pending.insert(seq, out);
+        }
+        let out = self.pending.remove(&self.r_q)?;
+        self.r_q += 1;
+        Some(out)
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.s_q - self.r_q
+    }
+
+    fn submit(&mut self, work: Work) {
+        let job = Job {
+            seq: self.s_q,
+            work,
+            ret: self.ret.clone(),
+        };
+        self.s_q += 1;
+        // round robin, the reordering above puts jobs that finish out of turn back in line
+        let worker = self.next.fetch_add(0x01, Ordering::Relaxed) % self.txs.len();
+        // this only fails if that worker has panicked
+        let _ = self.txs[worker].send(job);
+    }
+}
+
+fn work(job: Job) {
+    let out = match job.work {
+        Work::Encrypt(mut encryptor, buffers) => buffers
+            .into_iter()
+            .map(|(mut buffer, length)| {
+                if buffer.len() < length + 0x20 {
+                    Err(Error::BufferLengthTooShort {
+                        expected: length + 0x20,
+                        got: buffer.len(),
+                    })?
+                }
+                let mut decrypted = Decrypted::new(&mut buffer)?;
+                decrypted.resize(length);
+                let length = encryptor.encrypt(decrypted)?.len();
+                buffer.truncate(length);
+                Ok(buffer)
+            })
+            .collect(),
+        Work::Decrypt(decryptor, mut buffers) => {
+            let mut views: Vec<_> = buffers.iter_mut().map(|buffer| &mut buffer[..]).collect();
+            let results = decryptor.decrypt_batch(&mut views);
+            buffers
+                .into_iter()
+                .zip(results)
+                .map(|(buffer, result)| result.map(|()| buffer))
+                .collect()
+        }
+    };
+    // the lane may have been dropped in the meantime
+    let _ = job.ret.send((job.seq, out));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a packet carrying its own sequence number, laid out like Decrypted
+    fn packet(n: u32) -> (Vec<u8>, usize) {
+        let mut buffer = vec![0x00; 0x40];
+        buffer[0x10..0x14].copy_from_slice(&n.to_le_bytes());
+        (buffer, 0x04)
+    }
+
+    #[test]
+    fn order() {
+        let pipeline = Pipeline::new(0x04);
+        let mut lane = pipeline.lane();
+        let mut encryptor = Encryptor::new(0x2a, [0x07; 0x20]);
+        let decryptor = Arc::new(Decryptor::new(0x2a, [0x07; 0x20]));
+
+        // batches of uneven size, so later ones often finish first
+        let mut n = 0x00;
+        for i in 0x00..0x80 {
+            let size = [0x01, 0x20, 0x03, 0x10][i % 0x04];
+            let buffers = (n..n + size).map(packet).collect();
+            lane.encrypt(&mut encryptor, buffers);
+            n += size;
+        }
+        let mut msgs = Vec::new();
+        while let Some(out) = lane.recv() {
+            msgs.extend(out.into_iter().map(Result::unwrap));
+        }
+        assert_eq!(lane.in_flight(), 0x00);
+        assert_eq!(msgs.len(), n as usize);
+        for (i, msg) in msgs.iter().enumerate() {
+            // counters go out in the order the packets came in
+            assert_eq!(msg[0x08..0x10], (i as u64).to_le_bytes());
+        }
+
+        for chunk in msgs.chunks(0x07) {
+            lane.decrypt(&decryptor, chunk.to_vec());
+        }
+        let mut i = 0x00u32;
+        while let Some(out) = lane.recv() {
+            for msg in out {
+                assert_eq!(msg.unwrap()[0x10..0x14], i.to_le_bytes());
+                i += 1;
+            }
+        }
+        assert_eq!(i, n);
+    }
+
+    #[test]
+    fn failures_stay_in_place() {
+        let pipeline = Pipeline::new(0x02);
+        let mut lane = pipeline.lane();
+        let mut encryptor = Encryptor::new(0x2a, [0x07; 0x20]);
+        let decryptor = Arc::new(Decryptor::new(0x2a, [0x07; 0x20]));
+        lane.encrypt(&mut encryptor, (0x00..0x04).map(packet).collect());
+        let mut msgs: Vec<_> = lane
+            .recv()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        msgs[0x01][0x20] ^= 0x01;
+        lane.decrypt(&decryptor, msgs);
+        let out = lane.recv().unwrap();
+        assert!(out[0x00].is_ok());
+        assert!(matches!(out[0x01], Err(Error::Peer { .. })));
+        assert!(out[0x02].is_ok() && out[0x03].is_ok());
+        assert!(lane.try_recv().is_none());
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{spawn, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    device::Device,
+    error::{Error, Result},
+    trace::debug,
+};
+
+// deep enough that the default LoadMonitor flips well before anything gets dropped
+pub const MAX_QUEUED_HANDSHAKES: usize = 0x1000;
+
+pub struct Job {
+    pub msg: Vec<u8>,    // whole handshake message
+    pub src: SocketAddr, // address it came from
+    pub now: Duration,   // when a worker picked it up, duration since UNIX epoch
+}
+
+// handshakes get their own bounded queue and threads, so a flood of them cannot starve transport data
+pub struct HandshakePool {
+    tx: Option<SyncSender<Job>>, // taken on drop, which lets the workers run out of jobs and stop
+    device: Arc<Device>,
+    depth: Arc<AtomicUsize>,
+    capacity: usize,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl HandshakePool {
+    // handler does the actual work, starting with Device::admit
+    // clock is the caller's, it stamps each job when a worker picks it up
+    pub fn new(
+        device: Arc<Device>,
+        workers: usize,
+        capacity: usize,
+        clock: impl Fn() -> Duration + Send + Sync + 'static,
+        handler: impl Fn(Job) + Send + Sync + 'static,
+    ) -> Self {
+        let (tx, rx) = sync_channel::<Job>(capacity);
+        let rx = Arc::new(Mutex::new(rx));
+        let depth = Arc::new(AtomicUsize::new(0x00));
+        let (clock, handler) = (Arc::new(clock), Arc::new(handler));
+        let handles = (0x00..workers.max(0x01))
+            .map(|_| {
+                let rx = rx.clone();
+                let device = device.clone();
+                let depth = depth.clone();
+                let (clock, handler) = (clock.clone(), handler.clone());
+                spawn(move || loop {
+                    let mut job = match rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    // it may have waited a while, and both the load and the handshake go by the time now
+                    job.now = clock();
+                    let queued = depth.fetch_sub(0x01, Ordering::Relaxed) - 0x01;
+                    device.load().observe(queued, job.now);
+                    handler(job);
+                })
+            })
+            .collect();
+        Self {
+            tx: Some(tx),
+            device,
+            depth,
+            capacity,
+            handles,
+        }
+    }
+
+    // never blocks, so the thread reading the socket can go straight back to transport data
+    pub fn submit(&self, msg: Vec<u8>, src: SocketAddr, now: Duration) -> Result<()> {
+        let queued = self.depth.fetch_add(0x01, Ordering::Relaxed) + 0x01;
+        self.device.load().observe(queued, now);
+        // only drop takes the sender away
+        let tx = self.tx.as_ref().unwrap();
+        match tx.try_send(Job { msg, src, now }) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.depth.fetch_sub(0x01, Ordering::Relaxed);
+                debug!(endpoint = %src, "dropped handshake, queue full");
+                self.device.metrics().handshake_failed(&Error::QueueFull);
+                Err(Error::QueueFull)
+            }
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn workers(&self) -> usize {
+        self.handles.len()
+    }
+}
+
+impl Drop for HandshakePool {
+    // whatever is still queued gets handled, then the workers are waited for
+    fn drop(&mut self) {
+        self.tx = None;
+        for handle in self.handles.drain(..) {
+            // a handler that panicked has nothing left to clean up
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::LoadMonitor, ratelimiter::RateLimiter};
+    use std::sync::{atomic::AtomicU64, mpsc::channel};
+    use x25519::StaticSecret;
+
+    const NOW: Duration = Duration::from_secs(0x6553f100);
+
+    #[test]
+    fn backlog_puts_device_under_load() {
+        let device = Arc::new(Device::new(
+            StaticSecret::from([0x01; 0x20]),
+            LoadMonitor::new(0x04, Duration::from_secs(0x01)),
+            RateLimiter::default(),
+        ));
+        // the test moves the time along by hand, in milliseconds
+        let time = Arc::new(AtomicU64::new(NOW.as_millis() as u64));
+        let clock = {
+            let time = time.clone();
+            move || Duration::from_millis(time.load(Ordering::Relaxed))
+        };
+        // the one worker holds on to its first job until told to let go
+        let (release, held) = channel::<()>();
+        let held = Mutex::new(held);
+        let (done, finished) = channel();
+        let pool = HandshakePool::new(device.clone(), 0x01, 0x08, clock, move |job| {
+            let _ = held.lock().unwrap().recv();
+            done.send(job.now).unwrap();
+        });
+        let src = ([0xc0, 0x00, 0x02, 0x01], 0xca6c).into();
+        assert!(!device.load().under_load(NOW));
+        pool.submit(vec![0x01], src, NOW).unwrap();
+        // one job is with the worker, the rest fill the queue
+        while pool.depth() > 0x00 {
+            std::thread::yield_now();
+        }
+        for _ in 0x00..0x08 {
+            pool.submit(vec![0x01], src, NOW).unwrap();
+        }
+        assert!(device.load().under_load(NOW));
+        assert!(matches!(
+            pool.submit(vec![0x01], src, NOW),
+            Err(Error::QueueFull)
+        ));
+        assert_eq!(pool.depth(), 0x08);
+
+        // the worker goes by the caller's clock when it picks the job up, not when it was queued
+        let later = NOW + Duration::from_millis(0x0a);
+        time.store(later.as_millis() as u64, Ordering::Relaxed);
+        for _ in 0x00..0x09 {
+            release.send(()).unwrap();
+        }
+        let mut picked = Vec::new();
+        for _ in 0x00..0x09 {
+            picked.push(finished.recv().unwrap());
+        }
+        assert_eq!(picked[0x00], NOW);
+        assert!(picked[0x01..].iter().all(|&at| at == later));
+        assert_eq!(pool.depth(), 0x00);
+        assert_eq!(device.load().depth(), 0x00);
+        // and the load outlasts the backlog for a while
+        assert!(device.load().under_load(later));
+        // dropping the pool waits for the workers, and with them goes the handler
+        drop(pool);
+        assert!(finished.recv().is_err());
+    }
+}
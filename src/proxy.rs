@@ -0,0 +1,733 @@
+use rand_core::{OsRng, RngCore};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{self, IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::netstack::{NetStack, TcpStream};
+
+// how long a connection through the tunnel gets to come up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(0x0a);
+
+// how long a lookup waits on each DNS server
+const DNS_TIMEOUT: Duration = Duration::from_secs(0x03);
+
+// how often UDP relays look up to see if the association is over
+const RELAY_TICK: Duration = Duration::from_secs(0x01);
+
+// the most an HTTP request line and its headers may take up
+const MAX_HEAD: u64 = 0x2000;
+
+// SOCKS5 address types
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+// SOCKS5 commands
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+// SOCKS5 reply codes
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_FAILURE: u8 = 0x01;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_REFUSED: u8 = 0x05;
+const REP_TTL_EXPIRED: u8 = 0x06;
+const REP_COMMAND: u8 = 0x07;
+const REP_ADDRESS_TYPE: u8 = 0x08;
+
+// where a client wants to go, names are looked up on the far side of the tunnel
+#[derive(Debug, PartialEq)]
+enum Target {
+    Addr(SocketAddr),
+    Name(String, u16),
+}
+
+// SOCKS5 and HTTP CONNECT on one local listener, forwarding every connection through the tunnel
+pub struct Proxy {
+    stack: Arc<NetStack>,
+    dns: Vec<IpAddr>, // servers behind the peer that names are looked up with
+}
+
+impl Proxy {
+    pub fn new(stack: Arc<NetStack>, dns: Vec<IpAddr>) -> Self {
+        Self { stack, dns }
+    }
+
+    // serves whoever connects, a thread per client, until the listener fails
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        for client in listener.incoming() {
+            let client = client?;
+            let proxy = self.clone();
+            thread::spawn(move || {
+                let _ = proxy.handle(client);
+            });
+        }
+        Ok(())
+    }
+
+    // the first byte tells the protocols apart, SOCKS5 opens with its version
+    fn handle(&self, client: net::TcpStream) -> io::Result<()> {
+        client.set_nodelay(true)?;
+        let mut reader = BufReader::new(client.try_clone()?);
+        match reader.fill_buf()?.first() {
+            Some(0x05) => self.socks(reader, client),
+            Some(_) => self.http(reader, client),
+            None => Ok(()),
+        }
+    }
+
+    fn socks(
+        &self,
+        mut reader: BufReader<net::TcpStream>,
+        mut client: net::TcpStream,
+    ) -> io::Result<()> {
+        let [_, n] = read_array(&mut reader)?;
+        let mut methods = vec![0x00; n as usize];
+        reader.read_exact(&mut methods)?;
+        // no authentication is all there is, the listener is meant to stay local
+        if !methods.contains(&0x00) {
+            return client.write_all(&[0x05, 0xff]);
+        }
+        client.write_all(&[0x05, 0x00])?;
+
+        let [_, cmd, _] = read_array(&mut reader)?;
+        let target = match read_target(&mut reader) {
+            Ok(target) => target,
+            Err(_) => return socks_reply(&mut client, REP_ADDRESS_TYPE, None),
+        };
+        match cmd {
+            CMD_CONNECT => {
+                let stream = match self
+                    .resolve(&target)
+                    .and_then(|address| self.stack.connect_timeout(address, CONNECT_TIMEOUT))
+                {
+                    Ok(stream) => stream,
+                    Err(e) => return socks_reply(&mut client, socks_error(&e), None),
+                };
+                socks_reply(&mut client, REP_SUCCEEDED, stream.local_addr())?;
+                pipe(reader, client, stream);
+                Ok(())
+            }
+            CMD_UDP_ASSOCIATE => self.associate(reader, client),
+            _ => socks_reply(&mut client, REP_COMMAND, None),
+        }
+    }
+
+    // relays datagrams for as long as the client keeps the control connection open
+    fn associate(
+        &self,
+        mut reader: BufReader<net::TcpStream>,
+        mut client: net::TcpStream,
+    ) -> io::Result<()> {
+        let relay = net::UdpSocket::bind(SocketAddr::new(client.local_addr()?.ip(), 0x00))?;
+        let tunnel = match self.stack.bind_udp(0x00) {
+            Ok(tunnel) => tunnel,
+            Err(_) => return socks_reply(&mut client, REP_FAILURE, None),
+        };
+        socks_reply(&mut client, REP_SUCCEEDED, Some(relay.local_addr()?))?;
+        relay.set_read_timeout(Some(RELAY_TICK))?;
+        tunnel.set_read_timeout(Some(RELAY_TICK));
+
+        let owner = client.peer_addr()?.ip();
+        let source = Mutex::new(None); // where the client sends its datagrams from
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut buffer = vec![0x00; 0x10000];
+                while !done.load(Ordering::Relaxed) {
+                    let Ok((length, src)) = relay.recv_from(&mut buffer) else {
+                        continue;
+                    };
+                    // only the client that asked gets to use the relay
+                    if src.ip() != owner {
+                        continue;
+                    }
+                    *source.lock().unwrap() = Some(src);
+                    let mut datagram = &buffer[0x00..length];
+                    // no fragmentation support, so fragments are dropped like the RFC allows
+                    let Ok([_, _, 0x00]) = read_array(&mut datagram) else {
+                        continue;
+                    };
+                    if let Ok(address) =
+                        read_target(&mut datagram).and_then(|target| self.resolve(&target))
+                    {
+                        let _ = tunnel.send_to(datagram, address);
+                    }
+                }
+            });
+            scope.spawn(|| {
+                let mut buffer = vec![0x00; 0x10000];
+                while !done.load(Ordering::Relaxed) {
+                    let Ok((length, src)) = tunnel.recv_from(&mut buffer) else {
+                        continue;
+                    };
+                    if let Some(dst) = *source.lock().unwrap() {
+                        let mut datagram = vec![0x00, 0x00, 0x00];
+                        write_address(&mut datagram, src);
+                        datagram.extend_from_slice(&buffer[0x00..length]);
+                        let _ = relay.send_to(&datagram, dst);
+                    }
+                }
+            });
+            // anything on the control connection is ignored, its end is the end of the association
+            let _ = io::copy(&mut reader, &mut io::sink());
+            done.store(true, Ordering::Relaxed);
+        });
+        Ok(())
+    }
+
+    fn http(
+        &self,
+        mut reader: BufReader<net::TcpStream>,
+        mut client: net::TcpStream,
+    ) -> io::Result<()> {
+        // whatever follows the headers is the tunnelled stream
+        let head = match read_head(&mut reader) {
+            Ok(head) => head,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return http_reply(&mut client, "431 Request Header Fields Too Large");
+            }
+            Err(e) => Err(e)?,
+        };
+        let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+        let target = match (parts.next(), parts.next()) {
+            (Some("CONNECT"), Some(authority)) => parse_authority(authority),
+            _ => {
+                return http_reply(&mut client, "405 Method Not Allowed");
+            }
+        };
+        let Some(target) = target else {
+            return http_reply(&mut client, "400 Bad Request");
+        };
+        let stream = match self
+            .resolve(&target)
+            .and_then(|address| self.stack.connect_timeout(address, CONNECT_TIMEOUT))
+        {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                return http_reply(&mut client, "504 Gateway Timeout")
+            }
+            Err(_) => return http_reply(&mut client, "502 Bad Gateway"),
+        };
+        client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")?;
+        pipe(reader, client, stream);
+        Ok(())
+    }
+
+    fn resolve(&self, target: &Target) -> io::Result<SocketAddr> {
+        match target {
+            Target::Addr(address) => Ok(*address),
+            Target::Name(name, port) => match name.parse() {
+                Ok(address) => Ok(SocketAddr::new(address, *port)),
+                Err(_) => Ok(SocketAddr::new(self.lookup(name)?, *port)),
+            },
+        }
+    }
+
+    // asks the servers behind the peer, so names resolve the way they do on the far side
+    fn lookup(&self, name: &str) -> io::Result<IpAddr> {
+        let socket = self.stack.bind_udp(0x00)?;
+        socket.set_read_timeout(Some(DNS_TIMEOUT));
+        let mut buffer = vec![0x00; 0x0200];
+        for server in &self.dns {
+            // A first, AAAA only for names that have no IPv4 address
+            for qtype in [0x0001, 0x001c] {
+                // unpredictable, so an answer has to come from someone who saw the query
+                let id = OsRng.next_u32() as u16;
+                let server = SocketAddr::new(*server, 0x35);
+                socket.send_to(&dns_query(id, name, qtype)?, server)?;
+                // datagrams from anywhere else are not the answer
+                let length = loop {
+                    match socket.recv_from(&mut buffer) {
+                        Ok((length, src)) if src == server => break Some(length),
+                        Ok(_) => continue,
+                        Err(_) => break None,
+                    }
+                };
+                let Some(length) = length else {
+                    break;
+                };
+                if let Some(address) = dns_answer(id, &buffer[0x00..length]) {
+                    return Ok(address);
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("could not resolve {name}"),
+        ))
+    }
+}
+
+// copies both ways until each side has said all it has to say
+// returns how much went each way, into the tunnel first
+pub(crate) fn pipe(
+    mut reader: impl Read + Send,
+    mut client: net::TcpStream,
+    stream: TcpStream,
+) -> (u64, u64) {
+    thread::scope(|scope| {
+        let up = scope.spawn(|| {
+            let up = io::copy(&mut reader, &mut &stream).unwrap_or_default();
+            stream.shutdown();
+            up
+        });
+        let down = io::copy(&mut &stream, &mut client).unwrap_or_default();
+        let _ = client.shutdown(Shutdown::Write);
+        (up.join().unwrap_or_default(), down)
+    })
+}
+
+// the request line and headers up to the empty line that ends them, at most MAX_HEAD bytes of it
+fn read_head(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut head = String::new();
+    loop {
+        let left = MAX_HEAD.saturating_sub(head.len() as u64);
+        let start = head.len();
+        match reader.take(left).read_line(&mut head)? {
+            0x00 if left == 0x00 => Err(io::Error::from(io::ErrorKind::InvalidData))?,
+            0x00 => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+            // cut short by the limit, or by the client going away
+            _ if !head.ends_with('\n') && head.len() as u64 >= MAX_HEAD => {
+                Err(io::Error::from(io::ErrorKind::InvalidData))?
+            }
+            _ if !head.ends_with('\n') => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+            // an empty line, CRLF or a bare LF, ends the head
+            _ if matches!(&head[start..], "\r\n" | "\n") && start > 0x00 => return Ok(head),
+            _ => {}
+        }
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut array = [0x00; N];
+    reader.read_exact(&mut array)?;
+    Ok(array)
+}
+
+// ATYP, DST.ADDR and DST.PORT, as found in requests and UDP headers
+fn read_target(reader: &mut impl Read) -> io::Result<Target> {
+    let [atyp] = read_array(reader)?;
+    let target = match atyp {
+        ATYP_IPV4 => IpAddr::from(read_array::<0x04>(reader)?),
+        ATYP_IPV6 => IpAddr::from(read_array::<0x10>(reader)?),
+        ATYP_DOMAIN => {
+            let [n] = read_array(reader)?;
+            let mut name = vec![0x00; n as usize];
+            reader.read_exact(&mut name)?;
+            let name =
+                String::from_utf8(name).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+            return Ok(Target::Name(name, u16::from_be_bytes(read_array(reader)?)));
+        }
+        _ => Err(io::Error::from(io::ErrorKind::InvalidData))?,
+    };
+    Ok(Target::Addr(SocketAddr::new(
+        target,
+        u16::from_be_bytes(read_array(reader)?),
+    )))
+}
+
+fn write_address(out: &mut Vec<u8>, address: SocketAddr) {
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&address.port().to_be_bytes());
+}
+
+// BND.ADDR is left unspecified when there is nothing better to give
+fn socks_reply(client: &mut net::TcpStream, rep: u8, bound: Option<SocketAddr>) -> io::Result<()> {
+    let mut reply = vec![0x05, rep, 0x00];
+    write_address(
+        &mut reply,
+        bound.unwrap_or(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0x00)),
+    );
+    client.write_all(&reply)
+}
+
+fn socks_error(error: &io::Error) -> u8 {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => REP_REFUSED,
+        io::ErrorKind::TimedOut => REP_TTL_EXPIRED,
+        io::ErrorKind::NotFound | io::ErrorKind::InvalidInput => REP_HOST_UNREACHABLE,
+        _ => REP_FAILURE,
+    }
+}
+
+fn http_reply(client: &mut net::TcpStream, status: &str) -> io::Result<()> {
+    client.write_all(
+        format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").as_bytes(),
+    )
+}
+
+// host:port, with IPv6 hosts in brackets
+fn parse_authority(authority: &str) -> Option<Target> {
+    if let Ok(address) = authority.parse() {
+        return Some(Target::Addr(address));
+    }
+    let (host, port) = authority.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    match host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+    {
+        Some(host) => Some(Target::Addr(SocketAddr::new(
+            host.parse::<Ipv6Addr>().ok()?.into(),
+            port,
+        ))),
+        // an IPv6 address has to be in brackets, and names have no colons
+        None if host.is_empty() || host.contains(':') => None,
+        None => Some(Target::Name(host.to_owned(), port)),
+    }
+}
+
+// a recursive query for one record of name
+fn dns_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(0x0200);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 0x3f {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad name {name}"),
+            ))?
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0x00);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&0x0001u16.to_be_bytes());
+    Ok(query)
+}
+
+// the first A or AAAA record in a response to query id
+fn dns_answer(id: u16, msg: &[u8]) -> Option<IpAddr> {
+    let u16_at = |at: usize| Some(u16::from_be_bytes(msg.get(at..at + 0x02)?.try_into().ok()?));
+    if u16_at(0x00)? != id || msg.get(0x03)? & 0x0f != 0x00 {
+        return None;
+    }
+    let mut at = 0x0c;
+    for _ in 0x00..u16_at(0x04)? {
+        at = skip_name(msg, at)? + 0x04;
+    }
+    for _ in 0x00..u16_at(0x06)? {
+        at = skip_name(msg, at)?;
+        let (rtype, length) = (u16_at(at)?, u16_at(at + 0x08)? as usize);
+        let data = msg.get(at + 0x0a..at + 0x0a + length)?;
+        match (rtype, length) {
+            (0x0001, 0x04) => return Some(IpAddr::from(<[u8; 0x04]>::try_from(data).ok()?)),
+            (0x001c, 0x10) => return Some(IpAddr::from(<[u8; 0x10]>::try_from(data).ok()?)),
+            _ => at += 0x0a + length,
+        }
+    }
+    None
+}
+
+// the offset just past a possibly compressed name
+fn skip_name(msg: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        match *msg.get(at)? {
+            0x00 => return Some(at + 0x01),
+            length if length & 0xc0 == 0xc0 => return Some(at + 0x02),
+            length => at += 0x01 + length as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netstack::tests::{pair, Linked, B};
+
+    const ECHO: u16 = 0x1f90;
+
+    // a proxy on a's side of a linked pair, dns pointing at b
+    fn proxy() -> (Arc<Proxy>, Arc<NetStack>, Linked) {
+        let (a, b, linked) = pair();
+        (Arc::new(Proxy::new(a, vec![B])), b, linked)
+    }
+
+    // a local client whose connection the proxy handles on its own thread
+    fn client(proxy: &Arc<Proxy>) -> net::TcpStream {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0x00)).unwrap();
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(0x0a)))
+            .unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let proxy = proxy.clone();
+        thread::spawn(move || proxy.handle(accepted));
+        client
+    }
+
+    // answers each of count connections to ECHO with the four bytes they send first
+    fn echo(b: &Arc<NetStack>, count: usize) -> thread::JoinHandle<()> {
+        // all listening up front, so no SYN finds the port closed
+        let servers: Vec<_> = (0x00..count).map(|_| b.listen(ECHO).unwrap()).collect();
+        thread::spawn(move || {
+            for mut server in servers {
+                let mut buffer = [0x00; 0x04];
+                server.read_exact(&mut buffer).unwrap();
+                server.write_all(&buffer).unwrap();
+                server.flush().unwrap();
+            }
+        })
+    }
+
+    fn ping(client: &mut net::TcpStream) {
+        client.write_all(b"ping").unwrap();
+        let mut buffer = [0x00; 0x04];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+    }
+
+    // greets without authentication, asks to connect to address and returns the reply
+    fn socks_connect(client: &mut net::TcpStream, address: &[u8]) -> [u8; 0x0a] {
+        client.write_all(&[0x05, 0x01, 0x00]).unwrap();
+        assert_eq!(read_array(client).unwrap(), [0x05, 0x00]);
+        client.write_all(&[0x05, CMD_CONNECT, 0x00]).unwrap();
+        client.write_all(address).unwrap();
+        read_array(client).unwrap()
+    }
+
+    fn http_status(client: &mut net::TcpStream) -> String {
+        let mut status = String::new();
+        BufReader::new(client).read_line(&mut status).unwrap();
+        status
+    }
+
+    #[test]
+    fn targets() {
+        let parse = |bytes: &[u8]| read_target(&mut &bytes[..]);
+        assert_eq!(
+            parse(&[ATYP_IPV4, 0x0a, 0x00, 0x00, 0x02, 0x1f, 0x90]).unwrap(),
+            Target::Addr(SocketAddr::new(B, ECHO))
+        );
+        let mut ipv6 = vec![ATYP_IPV6];
+        ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend_from_slice(&[0x01, 0xbb]);
+        assert_eq!(
+            parse(&ipv6).unwrap(),
+            Target::Addr(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0x01bb))
+        );
+        assert_eq!(
+            parse(b"\x03\x09echo.test\x1f\x90").unwrap(),
+            Target::Name("echo.test".to_owned(), ECHO)
+        );
+        // 0x02 is not an address type
+        let err = parse(&[0x02, 0x0a, 0x00, 0x00, 0x02, 0x1f, 0x90]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = parse(&ipv6[0x00..0x08]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn authorities() {
+        assert_eq!(
+            parse_authority("10.0.0.2:8080"),
+            Some(Target::Addr(SocketAddr::new(B, ECHO)))
+        );
+        assert_eq!(
+            parse_authority("[::1]:443"),
+            Some(Target::Addr(SocketAddr::new(
+                Ipv6Addr::LOCALHOST.into(),
+                0x01bb
+            )))
+        );
+        assert_eq!(
+            parse_authority("[2001:db8::1]:80"),
+            Some(Target::Addr(SocketAddr::new(
+                Ipv6Addr::new(0x2001, 0x0db8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01).into(),
+                0x50
+            )))
+        );
+        assert_eq!(
+            parse_authority("echo.test:8080"),
+            Some(Target::Name("echo.test".to_owned(), ECHO))
+        );
+        for bad in [
+            "echo.test",
+            "echo.test:http",
+            "[::1]",
+            "[echo.test]:443",
+            "::1:443",
+        ] {
+            assert_eq!(parse_authority(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn answers() {
+        // the query with a CNAME and then an A record for it tacked on
+        let mut msg = dns_query(0x1234, "echo.test", 0x0001).unwrap();
+        msg[0x02..0x04].copy_from_slice(&[0x81, 0x80]);
+        msg[0x06..0x08].copy_from_slice(&[0x00, 0x02]);
+        msg.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c]);
+        msg.extend_from_slice(&[0x00, 0x02, 0xc0, 0x0c]);
+        msg.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c]);
+        msg.extend_from_slice(&[0x00, 0x04, 0x0a, 0x00, 0x00, 0x02]);
+        assert_eq!(dns_answer(0x1234, &msg), Some(B));
+        assert_eq!(dns_answer(0x4321, &msg), None);
+        // every cut short response is turned down without reading past it
+        for length in 0x00..msg.len() {
+            assert_eq!(dns_answer(0x1234, &msg[0x00..length]), None, "{length}");
+        }
+
+        let mut msg = dns_query(0x1234, "echo.test", 0x001c).unwrap();
+        msg[0x02..0x04].copy_from_slice(&[0x81, 0x80]);
+        msg[0x06..0x08].copy_from_slice(&[0x00, 0x01]);
+        msg.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x1c, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c]);
+        msg.extend_from_slice(&[0x00, 0x10]);
+        msg.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        assert_eq!(dns_answer(0x1234, &msg), Some(Ipv6Addr::LOCALHOST.into()));
+        // NXDOMAIN
+        msg[0x03] = 0x83;
+        assert_eq!(dns_answer(0x1234, &msg), None);
+    }
+
+    #[test]
+    fn heads() {
+        let read = |bytes: &[u8]| read_head(&mut &bytes[..]);
+        let head = "CONNECT echo.test:8080 HTTP/1.1\r\nHost: echo.test:8080\r\n\r\n";
+        assert_eq!(read(format!("{head}ping").as_bytes()).unwrap(), head);
+        assert_eq!(
+            read(b"CONNECT a:1 HTTP/1.0\n\nping").unwrap(),
+            "CONNECT a:1 HTTP/1.0\n\n"
+        );
+        let err = read(b"CONNECT a:1 HTTP/1.1\r\nHost: a:1\r\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        // a header that never ends, with and without line breaks
+        let long = format!("CONNECT a:1 HTTP/1.1\r\n{}", "X: y\r\n".repeat(0x0800));
+        assert_eq!(
+            read(long.as_bytes()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let long = format!("CONNECT a:1 HTTP/1.1\r\nX: {}", "y".repeat(0x4000));
+        assert_eq!(
+            read(long.as_bytes()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn socks() {
+        let (proxy, b, _linked) = proxy();
+        let echoed = echo(&b, 0x02);
+        let mut client = client(&proxy);
+        let reply = socks_connect(
+            &mut client,
+            &[ATYP_IPV4, 0x0a, 0x00, 0x00, 0x02, 0x1f, 0x90],
+        );
+        assert_eq!(
+            reply[0x00..0x08],
+            [0x05, REP_SUCCEEDED, 0x00, ATYP_IPV4, 0x0a, 0x00, 0x00, 0x01]
+        );
+        ping(&mut client);
+
+        // the tunnel is up now, so the lookup's one query is not lost to the handshake
+        let dns = b.bind_udp(0x35).unwrap();
+        dns.set_read_timeout(Some(Duration::from_secs(0x0a)));
+        let named = thread::spawn(move || {
+            let mut buffer = [0x00; 0x0200];
+            let (length, src) = dns.recv_from(&mut buffer).unwrap();
+            let mut msg = buffer[0x00..length].to_vec();
+            msg[0x02..0x04].copy_from_slice(&[0x81, 0x80]);
+            msg[0x06..0x08].copy_from_slice(&[0x00, 0x01]);
+            msg.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c]);
+            msg.extend_from_slice(&[0x00, 0x04, 0x0a, 0x00, 0x00, 0x02]);
+            dns.send_to(&msg, src).unwrap();
+            // the answer only leaves at the next poll, dropping the socket would take it along
+            dns
+        });
+        let mut client = self::client(&proxy);
+        let reply = socks_connect(&mut client, b"\x03\x09echo.test\x1f\x90");
+        assert_eq!(reply[0x00..0x02], [0x05, REP_SUCCEEDED]);
+        ping(&mut client);
+        named.join().unwrap();
+        echoed.join().unwrap();
+    }
+
+    #[test]
+    fn socks_unknown_address_type() {
+        let (proxy, _b, _linked) = proxy();
+        let mut client = client(&proxy);
+        let reply = socks_connect(&mut client, &[0x02]);
+        assert_eq!(
+            reply,
+            [
+                0x05,
+                REP_ADDRESS_TYPE,
+                0x00,
+                ATYP_IPV4,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn http_connect() {
+        let (proxy, b, _linked) = proxy();
+        let echoed = echo(&b, 0x01);
+        let mut client = client(&proxy);
+        // whatever comes right behind the headers belongs to the tunnelled stream
+        client
+            .write_all(b"CONNECT 10.0.0.2:8080 HTTP/1.1\r\nHost: 10.0.0.2:8080\r\n\r\nping")
+            .unwrap();
+        let mut reply = [0x00; 0x27];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        let mut buffer = [0x00; 0x04];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+        echoed.join().unwrap();
+    }
+
+    #[test]
+    fn http_errors() {
+        let (proxy, _b, _linked) = proxy();
+        let mut client = client(&proxy);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+        assert_eq!(
+            http_status(&mut client),
+            "HTTP/1.1 405 Method Not Allowed\r\n"
+        );
+
+        let mut client = self::client(&proxy);
+        client.write_all(b"CONNECT [::1] HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(http_status(&mut client), "HTTP/1.1 400 Bad Request\r\n");
+
+        // exactly MAX_HEAD bytes with no end in sight, so nothing is left unread when it hangs up
+        let mut client = self::client(&proxy);
+        let line = "CONNECT a:1 HTTP/1.1\r\n";
+        let filler = "y".repeat(MAX_HEAD as usize - line.len() - 0x05);
+        client
+            .write_all(format!("{line}X: {filler}\r\n").as_bytes())
+            .unwrap();
+        assert_eq!(
+            http_status(&mut client),
+            "HTTP/1.1 431 Request Header Fields Too Large\r\n"
+        );
+    }
+}
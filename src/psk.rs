@@ -0,0 +1,283 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use x25519::PublicKey;
+
+// something like Rosenpass that keeps rolling the preshared key, asked right before every handshake
+pub trait PskProvider: Send + Sync {
+    // the key to mix into a handshake with peer that starts now, none to fall back on the configured one
+    fn preshared_key(&self, peer: &PublicKey, now: Duration) -> Option<[u8; 0x20]>;
+}
+
+struct Entry {
+    p_k: [u8; 0x20],
+    from: Duration,  // valid from, duration since UNIX epoch
+    until: Duration, // valid until, duration since UNIX epoch
+}
+
+// keys pushed from outside, each valid for a window of time
+#[derive(Default)]
+pub struct PskStore {
+    peers: Mutex<HashMap<[u8; 0x20], Vec<Entry>>>,
+}
+
+impl PskStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // later pushes win where windows overlap, so a new key can be pushed before the old one runs out
+    pub fn push(
+        &self,
+        peer: &PublicKey,
+        p_k: [u8; 0x20],
+        from: Duration,  // valid from, duration since UNIX epoch
+        until: Duration, // valid until, duration since UNIX epoch
+        now: Duration,   // duration since UNIX epoch
+    ) {
+        let mut peers = self.peers.lock().unwrap();
+        let entries = peers.entry(peer.to_bytes()).or_default();
+        // keys still good until the new one takes over stay, only the ones already over go
+        entries.retain(|entry| entry.until > now);
+        entries.push(Entry { p_k, from, until });
+    }
+
+    pub fn remove(&self, peer: &PublicKey) {
+        self.peers.lock().unwrap().remove(peer.as_bytes());
+    }
+}
+
+impl PskProvider for PskStore {
+    fn preshared_key(&self, peer: &PublicKey, now: Duration) -> Option<[u8; 0x20]> {
+        let mut peers = self.peers.lock().unwrap();
+        let entries = peers.get_mut(peer.as_bytes())?;
+        entries.retain(|entry| entry.until > now);
+        entries
+            .iter()
+            .rev()
+            .find(|entry| entry.from <= now)
+            .map(|entry| entry.p_k)
+    }
+}
+
+// an ML-KEM-768 exchange over some side channel, like Rosenpass does it, the shared secret becomes the preshared key
+// one side offers an encapsulation key and the other answers with a ciphertext, each pushing the key it ends up with
+// the side channel needs no secrecy, whoever tampers with it only gets the two ends to disagree and handshakes to fail
+#[cfg(feature = "mlkem")]
+pub struct KemExchange {
+    store: PskStore,
+    lifetime: Duration, // how long an exchanged key is good for
+    offers: Mutex<HashMap<[u8; 0x20], crate::mlkem::DecapsulationKey>>, // offers waiting for an answer, by peer
+}
+
+#[cfg(feature = "mlkem")]
+impl KemExchange {
+    pub fn new(lifetime: Duration) -> Self {
+        Self {
+            store: PskStore::new(),
+            lifetime,
+            offers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // starts an exchange with peer, the encapsulation key goes to it over the side channel
+    pub fn offer(&self, peer: &PublicKey, rng: &mut impl rand_core::CryptoRngCore) -> Vec<u8> {
+        let (dk, ek) = crate::mlkem::generate(rng);
+        self.offers.lock().unwrap().insert(peer.to_bytes(), dk);
+        ek
+    }
+
+    // answers an offer from peer, the ciphertext goes back over the side channel
+    pub fn answer(
+        &self,
+        peer: &PublicKey,
+        ek: &[u8],     // encapsulation key from offer
+        now: Duration, // duration since UNIX epoch
+        rng: &mut impl rand_core::CryptoRngCore,
+    ) -> crate::error::Result<Vec<u8>> {
+        let (c, k) = crate::mlkem::encapsulate(ek, rng)?;
+        self.push(peer, k, now);
+        Ok(c)
+    }
+
+    // completes an exchange this side offered
+    pub fn finish(
+        &self,
+        peer: &PublicKey,
+        c: &[u8],      // ciphertext from answer
+        now: Duration, // duration since UNIX epoch
+    ) -> crate::error::Result<()> {
+        let dk = self
+            .offers
+            .lock()
+            .unwrap()
+            .remove(peer.as_bytes())
+            .ok_or(crate::error::Error::UnknownPeer)?;
+        self.push(peer, dk.decapsulate(c)?, now);
+        Ok(())
+    }
+
+    pub fn store(&self) -> &PskStore {
+        &self.store
+    }
+
+    // HASH(LABEL_PSK || shared secret), so the key is never the raw KEM output
+    fn push(&self, peer: &PublicKey, k: [u8; 0x20], now: Duration) {
+        let p_k = crate::crypto::hash("psk-kem-", k);
+        self.store.push(peer, p_k, now, now + self.lifetime, now);
+    }
+}
+
+#[cfg(feature = "mlkem")]
+impl PskProvider for KemExchange {
+    fn preshared_key(&self, peer: &PublicKey, now: Duration) -> Option<[u8; 0x20]> {
+        self.store.preshared_key(peer, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519::StaticSecret;
+
+    #[test]
+    fn rotation() {
+        let peer = PublicKey::from(&StaticSecret::from([0x01; 0x20]));
+        let store = PskStore::new();
+        let now = Duration::from_secs(0x6553f100);
+        let minute = Duration::from_secs(0x3c);
+        assert_eq!(store.preshared_key(&peer, now), None);
+        store.push(&peer, [0x01; 0x20], now, now + 0x02 * minute, now);
+        // the next key is pushed well before it takes over, the current one carries on until then
+        store.push(
+            &peer,
+            [0x02; 0x20],
+            now + 0x03 * minute,
+            now + 0x05 * minute,
+            now,
+        );
+        assert_eq!(store.preshared_key(&peer, now + minute), Some([0x01; 0x20]));
+        // a gap between the two falls back on whatever the tunnel has configured
+        assert_eq!(store.preshared_key(&peer, now + 0x02 * minute), None);
+        assert_eq!(
+            store.preshared_key(&peer, now + 0x03 * minute),
+            Some([0x02; 0x20])
+        );
+        // an overlapping push wins from the moment it starts
+        let later = now + 0x04 * minute;
+        store.push(&peer, [0x03; 0x20], later, later + minute, later);
+        assert_eq!(store.preshared_key(&peer, later), Some([0x03; 0x20]));
+        assert_eq!(store.peers.lock().unwrap()[peer.as_bytes()].len(), 0x02);
+        assert_eq!(store.preshared_key(&peer, later + minute), None);
+        store.remove(&peer);
+        assert!(store.peers.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "mlkem")]
+    #[test]
+    fn kem_exchange_keys_a_handshake() {
+        use crate::{
+            crypto::ephemeral_from,
+            device::{Device, LoadMonitor},
+            packet::{HandshakeInit, HandshakeResp},
+            ratelimiter::RateLimiter,
+            tunnel::Tunnel,
+        };
+        use rand_core::{CryptoRng, RngCore};
+        use std::sync::Arc;
+        use zerocopy::FromZeroes;
+
+        struct Counter(u8);
+
+        impl RngCore for Counter {
+            fn next_u32(&mut self) -> u32 {
+                self.next_u64() as u32
+            }
+
+            fn next_u64(&mut self) -> u64 {
+                let mut bytes = [0x00; 0x08];
+                self.fill_bytes(&mut bytes);
+                u64::from_le_bytes(bytes)
+            }
+
+            fn fill_bytes(&mut self, dest: &mut [u8]) {
+                for byte in dest {
+                    self.0 = self.0.wrapping_mul(0x05).wrapping_add(0x01);
+                    *byte = self.0;
+                }
+            }
+
+            fn try_fill_bytes(
+                &mut self,
+                dest: &mut [u8],
+            ) -> core::result::Result<(), rand_core::Error> {
+                self.fill_bytes(dest);
+                Ok(())
+            }
+        }
+
+        impl CryptoRng for Counter {}
+
+        let device = |secret| {
+            Arc::new(Device::new(
+                StaticSecret::from(secret),
+                LoadMonitor::default(),
+                RateLimiter::default(),
+            ))
+        };
+        let (a, b) = (device([0x01; 0x20]), device([0x02; 0x20]));
+        let (a_p, b_p) = (*a.public(), *b.public());
+        let (a_x, b_x) = (
+            Arc::new(KemExchange::new(Duration::from_secs(0x78))),
+            Arc::new(KemExchange::new(Duration::from_secs(0x78))),
+        );
+        a.set_psk_provider(Some(a_x.clone()));
+        b.set_psk_provider(Some(b_x.clone()));
+        let (a, b) = (
+            Tunnel::new(a, b_p, [0x00; 0x20]),
+            Tunnel::new(b, a_p, [0x00; 0x20]),
+        );
+
+        // the side channel is just a function call here
+        let (mut rng, now) = (Counter(0x2a), Duration::from_secs(0x6553f100));
+        let ek = a_x.offer(&b_p, &mut rng);
+        let c = b_x.answer(&a_p, &ek, now, &mut rng).unwrap();
+        a_x.finish(&b_p, &c, now).unwrap();
+        let p_k = a.current_preshared_key(now);
+        assert!(p_k.is_some());
+        assert_eq!(p_k, b.current_preshared_key(now));
+        assert!(a_x.finish(&b_p, &c, now).is_err());
+
+        let mut init = HandshakeInit::new_zeroed();
+        a.send_handshake_init(0x07, ephemeral_from([0x03; 0x20]), now, None, &mut init)
+            .unwrap();
+        let mut resp = HandshakeResp::new_zeroed();
+        b.recv_handshake_init(
+            &init,
+            0x09,
+            ephemeral_from([0x04; 0x20]),
+            now,
+            None,
+            &mut resp,
+        )
+        .unwrap();
+        a.recv_handshake_resp(&resp, now).unwrap();
+        let mut datagram = a.encrypt(&[]).unwrap();
+        b.decrypt(&mut datagram, now).unwrap();
+
+        // once only one side has a fresh key the two no longer agree
+        let later = now + Duration::from_secs(0x01);
+        let ek = b_x.offer(&a_p, &mut rng);
+        a_x.answer(&b_p, &ek, now, &mut rng).unwrap();
+        a.send_handshake_init(0x0b, ephemeral_from([0x05; 0x20]), later, None, &mut init)
+            .unwrap();
+        b.recv_handshake_init(
+            &init,
+            0x0d,
+            ephemeral_from([0x06; 0x20]),
+            now,
+            None,
+            &mut resp,
+        )
+        .unwrap();
+        assert!(a.recv_handshake_resp(&resp, later).is_err());
+    }
+}
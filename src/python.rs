@@ -0,0 +1,583 @@
+// PyO3 bindings for protocol testing: the handshake halves, the ciphers, the packet views and the sans-IO tunnel
+// keys, macs and indices go in and out as bytes and ints, times as nanoseconds since the UNIX epoch
+
+use pyo3::{
+    create_exception,
+    exceptions::{PyException, PyRuntimeError, PyValueError},
+    prelude::*,
+    types::PyBytes,
+};
+use rand_core::{OsRng, RngCore};
+use std::{
+    mem::size_of,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use x25519::{PublicKey, ReusableSecret, StaticSecret};
+use zerocopy::little_endian::{U32, U64};
+
+use crate::{
+    cipher::{self, Decrypted},
+    crypto::ephemeral_from,
+    device::{Device, LoadMonitor},
+    error::{Category, Error},
+    handshake, keylog,
+    packet::{self as wire},
+    ratelimiter::RateLimiter,
+    tunnel,
+};
+
+create_exception!(shyvana, WireGuardError, PyException);
+create_exception!(shyvana, MaliciousError, WireGuardError);
+create_exception!(shyvana, BenignDropError, WireGuardError);
+create_exception!(shyvana, MisconfigurationError, WireGuardError);
+create_exception!(shyvana, NeedsRekeyError, WireGuardError);
+
+#[pymodule]
+fn shyvana(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add("WireGuardError", py.get_type::<WireGuardError>())?;
+    m.add("MaliciousError", py.get_type::<MaliciousError>())?;
+    m.add("BenignDropError", py.get_type::<BenignDropError>())?;
+    m.add(
+        "MisconfigurationError",
+        py.get_type::<MisconfigurationError>(),
+    )?;
+    m.add("NeedsRekeyError", py.get_type::<NeedsRekeyError>())?;
+    m.add_function(wrap_pyfunction!(generate_keypair, m)?)?;
+    m.add_function(wrap_pyfunction!(public_key, m)?)?;
+    m.add_class::<HandshakeInit>()?;
+    m.add_class::<HandshakeResp>()?;
+    m.add_class::<CookieReply>()?;
+    m.add_class::<TransportData>()?;
+    m.add_class::<PeerKeys>()?;
+    m.add_class::<Initiator>()?;
+    m.add_class::<Responder>()?;
+    m.add_class::<Encryptor>()?;
+    m.add_class::<Decryptor>()?;
+    m.add_class::<Tunnel>()?;
+    Ok(())
+}
+
+// a fresh static key pair, (private, public)
+#[pyfunction]
+fn generate_keypair(py: Python<'_>) -> (Bound<'_, PyBytes>, Bound<'_, PyBytes>) {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    (
+        PyBytes::new(py, secret.as_bytes()),
+        PyBytes::new(py, public.as_bytes()),
+    )
+}
+
+#[pyfunction]
+fn public_key<'py>(py: Python<'py>, private_key: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+    let public = PublicKey::from(&StaticSecret::from(key(private_key)?));
+    Ok(PyBytes::new(py, public.as_bytes()))
+}
+
+// a message field as Python sees it, ints for indices and counters, bytes for everything else
+trait Field {
+    fn read(&self, py: Python<'_>) -> PyResult<PyObject>;
+    fn write(&mut self, value: &Bound<'_, PyAny>) -> PyResult<()>;
+}
+
+impl Field for u8 {
+    fn read(&self, py: Python<'_>) -> PyResult<PyObject> {
+        Ok(self.into_pyobject(py)?.into_any().unbind())
+    }
+
+    fn write(&mut self, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        *self = value.extract()?;
+        Ok(())
+    }
+}
+
+impl Field for U32 {
+    fn read(&self, py: Python<'_>) -> PyResult<PyObject> {
+        Ok(self.get().into_pyobject(py)?.into_any().unbind())
+    }
+
+    fn write(&mut self, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        U32::set(self, value.extract()?);
+        Ok(())
+    }
+}
+
+impl Field for U64 {
+    fn read(&self, py: Python<'_>) -> PyResult<PyObject> {
+        Ok(self.get().into_pyobject(py)?.into_any().unbind())
+    }
+
+    fn write(&mut self, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        U64::set(self, value.extract()?);
+        Ok(())
+    }
+}
+
+impl<const N: usize> Field for [u8; N] {
+    fn read(&self, py: Python<'_>) -> PyResult<PyObject> {
+        Ok(PyBytes::new(py, self).into_any().unbind())
+    }
+
+    fn write(&mut self, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        *self = value
+            .extract::<Vec<u8>>()?
+            .try_into()
+            .map_err(|_| PyValueError::new_err(format!("expected {N} bytes")))?;
+        Ok(())
+    }
+}
+
+// a Python class per message, over a copy of its bytes
+// fields can be read and overwritten freely, which is the point when writing conformance tests
+macro_rules! packet {
+    (
+        $(
+            $name:ident ($m_t:literal) {
+                $(
+                    $field:ident => $getter:ident, $setter:ident;
+                )+
+            }
+        )+
+    ) => {
+        $(
+            #[pyclass(module = "shyvana")]
+            pub struct $name {
+                bytes: Vec<u8>,
+            }
+
+            impl $name {
+                fn view(&self) -> &wire::$name {
+                    wire::$name::wrap_ref(&self.bytes).unwrap()
+                }
+
+                fn view_mut(&mut self) -> &mut wire::$name {
+                    wire::$name::wrap_mut(&mut self.bytes).unwrap()
+                }
+            }
+
+            #[pymethods]
+            impl $name {
+                // all zeroes but the message type, or a copy of data if given
+                #[new]
+                #[pyo3(signature = (data = None))]
+                fn new(data: Option<&[u8]>) -> PyResult<Self> {
+                    let bytes = match data {
+                        Some(data) => data.to_vec(),
+                        None => {
+                            let mut bytes = vec![0x00; size_of::<wire::$name>()];
+                            bytes[0x00] = $m_t;
+                            bytes
+                        }
+                    };
+                    wire::$name::wrap_ref(&bytes).map_err(raise)?;
+                    Ok(Self { bytes })
+                }
+
+                $(
+                    #[getter]
+                    fn $getter(&self, py: Python<'_>) -> PyResult<PyObject> {
+                        self.view().$field.read(py)
+                    }
+
+                    #[setter]
+                    fn $setter(&mut self, value: &Bound<'_, PyAny>) -> PyResult<()> {
+                        self.view_mut().$field.write(value)
+                    }
+                )+
+
+                fn __bytes__<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+                    PyBytes::new(py, &self.bytes)
+                }
+
+                fn __len__(&self) -> usize {
+                    self.bytes.len()
+                }
+            }
+        )+
+    };
+}
+
+packet! {
+    HandshakeInit (0x01) {
+        m_t => message_type, set_message_type;
+        r_0 => reserved, set_reserved;
+        s_i => sender_index, set_sender_index;
+        u_e => unencrypted_ephemeral, set_unencrypted_ephemeral;
+        e_s => encrypted_static, set_encrypted_static;
+        e_t => encrypted_timestamp, set_encrypted_timestamp;
+        m_1 => mac1, set_mac1;
+        m_2 => mac2, set_mac2;
+    }
+    HandshakeResp (0x02) {
+        m_t => message_type, set_message_type;
+        r_0 => reserved, set_reserved;
+        s_i => sender_index, set_sender_index;
+        r_i => receiver_index, set_receiver_index;
+        u_e => unencrypted_ephemeral, set_unencrypted_ephemeral;
+        e_n => encrypted_nothing, set_encrypted_nothing;
+        m_1 => mac1, set_mac1;
+        m_2 => mac2, set_mac2;
+    }
+    CookieReply (0x03) {
+        m_t => message_type, set_message_type;
+        r_0 => reserved, set_reserved;
+        r_i => receiver_index, set_receiver_index;
+        n_c => nonce, set_nonce;
+        e_c => encrypted_cookie, set_encrypted_cookie;
+    }
+    TransportData (0x04) {
+        m_t => message_type, set_message_type;
+        r_0 => reserved, set_reserved;
+        r_i => receiver_index, set_receiver_index;
+        cnt => counter, set_counter;
+    }
+}
+
+// the precomputed keys for one peer, from the local private key and the peer's public key
+#[pyclass(module = "shyvana")]
+pub struct PeerKeys(handshake::PeerKeys);
+
+#[pymethods]
+impl PeerKeys {
+    #[new]
+    fn new(local_private: &[u8], peer_public: &[u8]) -> PyResult<Self> {
+        Ok(Self(handshake::PeerKeys::new(
+            &StaticSecret::from(key(local_private)?),
+            &PublicKey::from(key(peer_public)?),
+        )))
+    }
+
+    #[getter]
+    fn public<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.0.public().as_bytes())
+    }
+
+    #[getter]
+    fn mac1_key<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.mac1_key())
+    }
+
+    #[getter]
+    fn cookie_key<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.cookie_key())
+    }
+}
+
+// the initiator's half of a handshake, good for one response
+#[pyclass(module = "shyvana")]
+pub struct Initiator(Option<handshake::Initiator>);
+
+#[pymethods]
+impl Initiator {
+    #[staticmethod]
+    #[pyo3(signature = (index, local_public, peer, ephemeral = None, now = None, cookie = None))]
+    fn send_handshake_init(
+        index: u32,
+        local_public: &[u8],
+        peer: &PeerKeys,
+        ephemeral: Option<&[u8]>,
+        now: Option<u64>,
+        cookie: Option<&[u8]>,
+    ) -> PyResult<(Self, HandshakeInit)> {
+        let mut msg = HandshakeInit::new(None)?;
+        let initiator = handshake::Initiator::send_handshake_init(
+            index,
+            &PublicKey::from(key(local_public)?),
+            &peer.0,
+            secret(ephemeral)?,
+            clock(now),
+            cookie.map(mac).transpose()?,
+            msg.view_mut(),
+        )
+        .map_err(raise)?;
+        Ok((Self(Some(initiator)), msg))
+    }
+
+    // (sending key, receiving key)
+    #[pyo3(signature = (local_private, resp, preshared_key = None))]
+    fn recv_handshake_resp<'py>(
+        &mut self,
+        py: Python<'py>,
+        local_private: &[u8],
+        resp: &HandshakeResp,
+        preshared_key: Option<&[u8]>,
+    ) -> PyResult<(Bound<'py, PyBytes>, Bound<'py, PyBytes>)> {
+        let initiator = self.0.as_ref().ok_or_else(used)?;
+        let (s_k, r_k) = initiator
+            .recv_handshake_resp(
+                &StaticSecret::from(key(local_private)?),
+                preshared_key.map(key).transpose()?,
+                resp.view(),
+            )
+            .map_err(raise)?;
+        // a response that fails to verify leaves the initiator waiting for the real one
+        self.0 = None;
+        Ok((PyBytes::new(py, &s_k), PyBytes::new(py, &r_k)))
+    }
+}
+
+// the responder's half of a handshake, good for one response
+#[pyclass(module = "shyvana")]
+pub struct Responder(Option<handshake::Responder>);
+
+#[pymethods]
+impl Responder {
+    #[staticmethod]
+    fn recv_handshake_init(
+        local_private: &[u8],
+        peer: &PeerKeys,
+        init: &HandshakeInit,
+    ) -> PyResult<Self> {
+        let secret = StaticSecret::from(key(local_private)?);
+        let responder = handshake::Responder::recv_handshake_init(
+            &secret,
+            &PublicKey::from(&secret),
+            &peer.0,
+            init.view(),
+        )
+        .map_err(raise)?;
+        Ok(Self(Some(responder)))
+    }
+
+    // (response, sending key, receiving key)
+    #[pyo3(signature = (initiator_index, peer, index, ephemeral = None, preshared_key = None, cookie = None))]
+    #[allow(clippy::too_many_arguments)]
+    fn send_handshake_resp<'py>(
+        &mut self,
+        py: Python<'py>,
+        initiator_index: u32,
+        peer: &PeerKeys,
+        index: u32,
+        ephemeral: Option<&[u8]>,
+        preshared_key: Option<&[u8]>,
+        cookie: Option<&[u8]>,
+    ) -> PyResult<(HandshakeResp, Bound<'py, PyBytes>, Bound<'py, PyBytes>)> {
+        let responder = self.0.take().ok_or_else(used)?;
+        let mut msg = HandshakeResp::new(None)?;
+        let (s_k, r_k) = responder
+            .send_handshake_resp(
+                initiator_index,
+                &peer.0,
+                index,
+                secret(ephemeral)?,
+                preshared_key.map(key).transpose()?,
+                cookie.map(mac).transpose()?,
+                msg.view_mut(),
+            )
+            .map_err(raise)?;
+        Ok((msg, PyBytes::new(py, &s_k), PyBytes::new(py, &r_k)))
+    }
+}
+
+#[pyclass(module = "shyvana")]
+pub struct Encryptor(cipher::Encryptor);
+
+#[pymethods]
+impl Encryptor {
+    #[new]
+    fn new(receiver_index: u32, key: &[u8]) -> PyResult<Self> {
+        Ok(Self(cipher::Encryptor::new(
+            receiver_index,
+            self::key(key)?,
+        )))
+    }
+
+    // the next counter to be sent
+    #[getter]
+    fn counter(&self) -> u64 {
+        self.0.s_c()
+    }
+
+    #[getter]
+    fn receiver_index(&self) -> u32 {
+        self.0.r_i()
+    }
+
+    // a whole transport data message, packet padded to a multiple of 16
+    fn encrypt<'py>(&mut self, py: Python<'py>, packet: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        let mut buffer = vec![0x00; 0x20 + packet.len().next_multiple_of(0x10)];
+        buffer[0x10..0x10 + packet.len()].copy_from_slice(packet);
+        let mut decrypted = Decrypted::new(&mut buffer).map_err(raise)?;
+        decrypted.resize(packet.len());
+        let length = self.0.encrypt(decrypted).map_err(raise)?.len();
+        Ok(PyBytes::new(py, &buffer[0x00..length]))
+    }
+}
+
+#[pyclass(module = "shyvana")]
+pub struct Decryptor(cipher::Decryptor);
+
+#[pymethods]
+impl Decryptor {
+    #[new]
+    fn new(receiver_index: u32, key: &[u8]) -> PyResult<Self> {
+        Ok(Self(cipher::Decryptor::new(
+            receiver_index,
+            self::key(key)?,
+        )))
+    }
+
+    #[getter]
+    fn receiver_index(&self) -> u32 {
+        self.0.r_i()
+    }
+
+    // the packet with its padding, there is no IP header to tell them apart at this level
+    fn decrypt<'py>(&self, py: Python<'py>, message: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        let mut buffer = message.to_vec();
+        self.0.decrypt(&mut buffer).map_err(raise)?;
+        Ok(PyBytes::new(py, &buffer[0x10..buffer.len() - 0x10]))
+    }
+}
+
+// a tunnel to one peer on a device of its own, no sockets and no timers
+#[pyclass(module = "shyvana")]
+pub struct Tunnel(Arc<tunnel::Tunnel>);
+
+#[pymethods]
+impl Tunnel {
+    #[new]
+    #[pyo3(signature = (private_key, peer_public, preshared_key = None))]
+    fn new(private_key: &[u8], peer_public: &[u8], preshared_key: Option<&[u8]>) -> PyResult<Self> {
+        let device = Arc::new(Device::new(
+            StaticSecret::from(key(private_key)?),
+            LoadMonitor::default(),
+            RateLimiter::default(),
+        ));
+        Ok(Self(Arc::new(tunnel::Tunnel::new(
+            device,
+            PublicKey::from(key(peer_public)?),
+            preshared_key.map(key).transpose()?.unwrap_or([0x00; 0x20]),
+        ))))
+    }
+
+    #[getter]
+    fn public_key<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.0.device().public().as_bytes())
+    }
+
+    #[getter]
+    fn peer_public<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.0.peer_public().as_bytes())
+    }
+
+    // random index and ephemeral unless given
+    #[pyo3(signature = (index = None, ephemeral = None, now = None, cookie = None))]
+    fn send_handshake_init(
+        &self,
+        index: Option<u32>,
+        ephemeral: Option<&[u8]>,
+        now: Option<u64>,
+        cookie: Option<&[u8]>,
+    ) -> PyResult<HandshakeInit> {
+        let mut msg = HandshakeInit::new(None)?;
+        self.0
+            .send_handshake_init(
+                index.unwrap_or_else(|| OsRng.next_u32()),
+                secret(ephemeral)?,
+                clock(now),
+                cookie.map(mac).transpose()?,
+                msg.view_mut(),
+            )
+            .map_err(raise)?;
+        Ok(msg)
+    }
+
+    // answers an initiation and installs the session it sets up
+    #[pyo3(signature = (init, index = None, ephemeral = None, now = None, cookie = None))]
+    fn recv_handshake_init(
+        &self,
+        init: &HandshakeInit,
+        index: Option<u32>,
+        ephemeral: Option<&[u8]>,
+        now: Option<u64>,
+        cookie: Option<&[u8]>,
+    ) -> PyResult<HandshakeResp> {
+        let mut msg = HandshakeResp::new(None)?;
+        self.0
+            .recv_handshake_init(
+                init.view(),
+                index.unwrap_or_else(|| OsRng.next_u32()),
+                secret(ephemeral)?,
+                clock(now),
+                cookie.map(mac).transpose()?,
+                msg.view_mut(),
+            )
+            .map_err(raise)?;
+        Ok(msg)
+    }
+
+    #[pyo3(signature = (resp, now = None))]
+    fn recv_handshake_resp(&self, resp: &HandshakeResp, now: Option<u64>) -> PyResult<()> {
+        self.0
+            .recv_handshake_resp(resp.view(), clock(now))
+            .map_err(raise)
+    }
+
+    fn encrypt<'py>(&self, py: Python<'py>, packet: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        let datagram = self.0.encrypt(packet).map_err(raise)?;
+        Ok(PyBytes::new(py, &datagram))
+    }
+
+    // the inner packet, empty for a keepalive
+    #[pyo3(signature = (message, now = None))]
+    fn decrypt<'py>(
+        &self,
+        py: Python<'py>,
+        message: &[u8],
+        now: Option<u64>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let mut datagram = message.to_vec();
+        let packet = self.0.decrypt(&mut datagram, clock(now)).map_err(raise)?;
+        Ok(PyBytes::new(py, packet))
+    }
+}
+
+// the exception class follows the category, the reason goes along as an attribute
+fn raise(error: Error) -> PyErr {
+    let message = error.to_string();
+    let exception = match error.category() {
+        Category::Malicious => MaliciousError::new_err(message),
+        Category::BenignDrop => BenignDropError::new_err(message),
+        Category::LocalMisconfiguration => MisconfigurationError::new_err(message),
+        Category::NeedsRekey => NeedsRekeyError::new_err(message),
+    };
+    Python::with_gil(|py| {
+        let _ = exception.value(py).setattr("reason", error.reason());
+    });
+    exception
+}
+
+fn used() -> PyErr {
+    PyRuntimeError::new_err("handshake state already used")
+}
+
+fn key(bytes: &[u8]) -> PyResult<[u8; 0x20]> {
+    bytes
+        .try_into()
+        .map_err(|_| PyValueError::new_err("keys are 32 bytes"))
+}
+
+fn mac(bytes: &[u8]) -> PyResult<[u8; 0x10]> {
+    bytes
+        .try_into()
+        .map_err(|_| PyValueError::new_err("cookies are 16 bytes"))
+}
+
+fn secret(ephemeral: Option<&[u8]>) -> PyResult<ReusableSecret> {
+    Ok(match ephemeral {
+        Some(ephemeral) => ephemeral_from(key(ephemeral)?),
+        None => keylog::ephemeral(&mut OsRng),
+    })
+}
+
+fn clock(now: Option<u64>) -> Duration {
+    match now {
+        Some(now) => Duration::from_nanos(now),
+        None => SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default(),
+    }
+}
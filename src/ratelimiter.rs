@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::Duration,
+};
+
+// how often buckets that have filled back up get dropped
+const GC_INTERVAL: Duration = Duration::from_secs(0x01);
+
+// one initiation per nanosecond, beyond that a token would cost nothing
+const MAX_RATE: u64 = 1_000_000_000;
+
+struct Bucket {
+    tokens: u64,    // nanoseconds worth of allowance left
+    last: Duration, // last time the bucket was touched
+}
+
+struct Table {
+    buckets: HashMap<IpAddr, Bucket>,
+    gc_at: Duration, // last time idle buckets were dropped
+}
+
+// token bucket limiter for handshake initiations, keyed by source address
+pub struct RateLimiter {
+    rate: u64,  // sustained initiations per second per source, at most MAX_RATE
+    burst: u64, // initiations a source may send back to back
+    table: Mutex<Table>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate: rate.clamp(0x01, MAX_RATE),
+            burst: burst.max(0x01),
+            table: Mutex::new(Table {
+                buckets: HashMap::new(),
+                gc_at: Duration::ZERO,
+            }),
+        }
+    }
+
+    pub fn allow(&self, ip: IpAddr, now: Duration) -> bool {
+        let (cost, max) = (self.cost(), self.max());
+        let mut table = self.table.lock().unwrap();
+        if now.saturating_sub(table.gc_at) >= GC_INTERVAL {
+            // a bucket is only as good as new once it has been idle long enough to fill back up
+            let full = Duration::from_nanos(max);
+            table
+                .buckets
+                .retain(|_, bucket| now.saturating_sub(bucket.last) < full);
+            table.gc_at = now;
+        }
+        let bucket = table.buckets.entry(key(ip)).or_insert(Bucket {
+            tokens: max,
+            last: now,
+        });
+        let elapsed = now.saturating_sub(bucket.last).as_nanos().min(max as u128) as u64;
+        bucket.tokens = bucket.tokens.saturating_add(elapsed).min(max);
+        bucket.last = now;
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+
+    // number of sources currently tracked
+    pub fn len(&self) -> usize {
+        self.table.lock().unwrap().buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0x00
+    }
+
+    // initiations the source of ip could send right now without being limited
+    pub fn available(&self, ip: IpAddr, now: Duration) -> u64 {
+        let (cost, max) = (self.cost(), self.max());
+        match self.table.lock().unwrap().buckets.get(&key(ip)) {
+            Some(bucket) => {
+                let elapsed = now.saturating_sub(bucket.last).as_nanos().min(max as u128) as u64;
+                bucket.tokens.saturating_add(elapsed).min(max) / cost
+            }
+            None => self.burst,
+        }
+    }
+
+    // nanoseconds worth of allowance one initiation takes, never zero since rate is clamped
+    fn cost(&self) -> u64 {
+        MAX_RATE / self.rate
+    }
+
+    // a full bucket
+    fn max(&self) -> u64 {
+        self.cost().saturating_mul(self.burst)
+    }
+}
+
+impl Default for RateLimiter {
+    // same numbers as the reference implementations
+    fn default() -> Self {
+        Self::new(0x14, 0x05)
+    }
+}
+
+// IPv6 sources are aggregated by /64, since a single host usually gets a whole one
+fn key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !0xffff_ffff_ffff_ffff)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(0xc0, 0x00, 0x02, 0x01));
+
+    #[test]
+    fn burst_then_refill() {
+        let limiter = RateLimiter::new(0x0a, 0x03);
+        let now = Duration::from_secs(0x64);
+        for _ in 0x00..0x03 {
+            assert!(limiter.allow(SRC, now));
+        }
+        assert!(!limiter.allow(SRC, now));
+        assert_eq!(limiter.available(SRC, now), 0x00);
+        // a tenth of a second buys one more
+        let now = now + Duration::from_millis(0x64);
+        assert_eq!(limiter.available(SRC, now), 0x01);
+        assert!(limiter.allow(SRC, now));
+        assert!(!limiter.allow(SRC, now));
+        // and it never holds more than the burst
+        let now = now + Duration::from_secs(0x3c);
+        assert_eq!(limiter.available(SRC, now), 0x03);
+    }
+
+    #[test]
+    fn gc_keeps_drained_buckets() {
+        // a full bucket takes ten seconds to refill, well past the sweep interval
+        let limiter = RateLimiter::new(0x01, 0x0a);
+        let mut now = Duration::from_secs(0x64);
+        while limiter.allow(SRC, now) {}
+        // another source touching the table after a couple of seconds sweeps it
+        now += Duration::from_secs(0x02);
+        assert!(limiter.allow(IpAddr::V4([0xc0, 0x00, 0x02, 0x02].into()), now));
+        assert_eq!(limiter.len(), 0x02);
+        // the drained bucket is still there, so only what refilled is allowed
+        assert_eq!(limiter.available(SRC, now), 0x02);
+        assert!(limiter.allow(SRC, now));
+        assert!(limiter.allow(SRC, now));
+        assert!(!limiter.allow(SRC, now));
+        // once it would be full again it goes
+        now += Duration::from_secs(0x0b);
+        assert!(limiter.allow(IpAddr::V4([0xc0, 0x00, 0x02, 0x03].into()), now));
+        assert_eq!(limiter.len(), 0x01);
+    }
+
+    #[test]
+    fn extreme_rates() {
+        let now = Duration::from_secs(0x64);
+        // faster than one per nanosecond is clamped rather than dividing by zero
+        let limiter = RateLimiter::new(u64::MAX, u64::MAX);
+        assert_eq!(limiter.rate(), MAX_RATE);
+        assert!(limiter.allow(SRC, now));
+        assert_eq!(limiter.available(SRC, now), u64::MAX - 0x01);
+        let limiter = RateLimiter::new(0x00, 0x00);
+        assert!(limiter.allow(SRC, now));
+        assert!(!limiter.allow(SRC, now));
+    }
+
+    #[test]
+    fn ipv6_by_prefix() {
+        let limiter = RateLimiter::new(0x01, 0x01);
+        let now = Duration::from_secs(0x64);
+        assert!(limiter.allow("2001:db8::1".parse().unwrap(), now));
+        assert!(!limiter.allow("2001:db8::ffff:2".parse().unwrap(), now));
+        assert!(limiter.allow("2001:db8:0:1::1".parse().unwrap(), now));
+        // mapped addresses share a bucket with the plain IPv4 one
+        assert!(limiter.allow(SRC, now));
+        assert!(!limiter.allow("::ffff:192.0.2.1".parse().unwrap(), now));
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use x25519::PublicKey;
+
+use crate::error::Error;
+
+// live counters for one peer, updated by whoever moves its packets and runs its handshakes
+pub struct PeerMetrics {
+    public: PublicKey,
+    rx_bytes: AtomicU64,
+    rx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_packets: AtomicU64,
+    initiated: AtomicU64,
+    completed: AtomicU64,
+    replays: AtomicU64,
+    session: AtomicU64, // nanoseconds since UNIX epoch the current session started, zero if none
+    handshake: AtomicU64, // nanoseconds since UNIX epoch of the last completed handshake, zero if none
+    failed: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl PeerMetrics {
+    pub fn new(public: PublicKey) -> Self {
+        Self {
+            public,
+            rx_bytes: AtomicU64::new(0x00),
+            rx_packets: AtomicU64::new(0x00),
+            tx_bytes: AtomicU64::new(0x00),
+            tx_packets: AtomicU64::new(0x00),
+            initiated: AtomicU64::new(0x00),
+            completed: AtomicU64::new(0x00),
+            replays: AtomicU64::new(0x00),
+            session: AtomicU64::new(0x00),
+            handshake: AtomicU64::new(0x00),
+            failed: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn rx(&self, bytes: usize) {
+        self.rx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.rx_packets.fetch_add(0x01, Ordering::Relaxed);
+    }
+
+    pub fn tx(&self, bytes: usize) {
+        self.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.tx_packets.fetch_add(0x01, Ordering::Relaxed);
+    }
+
+    pub fn handshake_initiated(&self) {
+        self.initiated.fetch_add(0x01, Ordering::Relaxed);
+    }
+
+    // a completed handshake installs a fresh session
+    pub fn handshake_completed(&self, now: Duration) {
+        self.completed.fetch_add(0x01, Ordering::Relaxed);
+        self.handshake.store(nanos(now), Ordering::Relaxed);
+        self.session.store(nanos(now), Ordering::Relaxed);
+    }
+
+    pub fn handshake_failed(&self, error: &Error) {
+        *self
+            .failed
+            .lock()
+            .unwrap()
+            .entry(error.reason())
+            .or_default() += 0x01;
+    }
+
+    pub fn replay(&self) {
+        self.replays.fetch_add(0x01, Ordering::Relaxed);
+    }
+
+    // the session is gone, without a handshake to replace it
+    pub fn session_expired(&self) {
+        self.session.store(0x00, Ordering::Relaxed);
+    }
+
+    pub fn public(&self) -> &PublicKey {
+        &self.public
+    }
+
+    pub fn snapshot(&self, now: Duration) -> PeerStats {
+        PeerStats {
+            public: self.public,
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            handshakes_initiated: self.initiated.load(Ordering::Relaxed),
+            handshakes_completed: self.completed.load(Ordering::Relaxed),
+            handshakes_failed: self.failed.lock().unwrap().clone(),
+            replay_drops: self.replays.load(Ordering::Relaxed),
+            session_age: since(self.session.load(Ordering::Relaxed))
+                .map(|born| now.saturating_sub(born)),
+            last_handshake: since(self.handshake.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+// counters that belong to the device rather than any one peer
+#[derive(Default)]
+pub struct DeviceMetrics {
+    cookies: AtomicU64,
+    failed: Mutex<BTreeMap<&'static str, u64>>,
+    dropped: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl DeviceMetrics {
+    pub fn cookie_reply(&self) {
+        self.cookies.fetch_add(0x01, Ordering::Relaxed);
+    }
+
+    // handshakes turned away before they could be tied to a peer
+    pub fn handshake_failed(&self, error: &Error) {
+        *self
+            .failed
+            .lock()
+            .unwrap()
+            .entry(error.reason())
+            .or_default() += 0x01;
+    }
+
+    // transport data that never made it to a session or did not open, replays are counted per peer
+    pub fn transport_dropped(&self, error: &Error) {
+        *self
+            .dropped
+            .lock()
+            .unwrap()
+            .entry(error.reason())
+            .or_default() += 0x01;
+    }
+
+    pub fn snapshot(&self, peers: Vec<PeerStats>) -> Stats {
+        Stats {
+            rx_bytes: peers.iter().map(|peer| peer.rx_bytes).sum(),
+            rx_packets: peers.iter().map(|peer| peer.rx_packets).sum(),
+            tx_bytes: peers.iter().map(|peer| peer.tx_bytes).sum(),
+            tx_packets: peers.iter().map(|peer| peer.tx_packets).sum(),
+            cookie_replies: self.cookies.load(Ordering::Relaxed),
+            handshakes_failed: self.failed.lock().unwrap().clone(),
+            transport_dropped: self.dropped.lock().unwrap().clone(),
+            peers,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub public: PublicKey,
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub handshakes_initiated: u64,
+    pub handshakes_completed: u64,
+    pub handshakes_failed: BTreeMap<&'static str, u64>, // keyed by Error::reason
+    pub replay_drops: u64,
+    pub session_age: Option<Duration>,    // none without a session
+    pub last_handshake: Option<Duration>, // duration since UNIX epoch, none if there never was one
+}
+
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub rx_bytes: u64,   // summed over peers
+    pub rx_packets: u64, // summed over peers
+    pub tx_bytes: u64,   // summed over peers
+    pub tx_packets: u64, // summed over peers
+    pub cookie_replies: u64,
+    pub handshakes_failed: BTreeMap<&'static str, u64>, // not attributed to a peer, keyed by Error::reason
+    pub transport_dropped: BTreeMap<&'static str, u64>, // unknown index or failed to open, keyed by Error::reason
+    pub peers: Vec<PeerStats>,
+}
+
+impl Stats {
+    // Prometheus text exposition format, version 0.0.4
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|peer| (hex(&peer.public), peer))
+            .collect();
+        let mut metric = |name: &str, kind: &str, help: &str, rows: Vec<(String, f64)>| {
+            writeln!(out, "# HELP shyvana_{name} {help}").unwrap();
+            writeln!(out, "# TYPE shyvana_{name} {kind}").unwrap();
+            for (labels, value) in rows {
+                writeln!(out, "shyvana_{name}{labels} {value}").unwrap();
+            }
+        };
+        let each = |f: &dyn Fn(&PeerStats) -> Option<f64>| {
+            peers
+                .iter()
+                .filter_map(|(key, peer)| Some((format!("{{peer=\"{key}\"}}"), f(peer)?)))
+                .collect()
+        };
+        metric(
+            "rx_bytes_total",
+            "counter",
+            "Bytes received from the peer.",
+            each(&|peer| Some(peer.rx_bytes as f64)),
+        );
+        metric(
+            "rx_packets_total",
+            "counter",
+            "Packets received from the peer.",
+            each(&|peer| Some(peer.rx_packets as f64)),
+        );
+        metric(
+            "tx_bytes_total",
+            "counter",
+            "Bytes sent to the peer.",
+            each(&|peer| Some(peer.tx_bytes as f64)),
+        );
+        metric(
+            "tx_packets_total",
+            "counter",
+            "Packets sent to the peer.",
+            each(&|peer| Some(peer.tx_packets as f64)),
+        );
+        metric(
+            "handshakes_initiated_total",
+            "counter",
+            "Handshake initiations sent to the peer.",
+            each(&|peer| Some(peer.handshakes_initiated as f64)),
+        );
+        metric(
+            "handshakes_completed_total",
+            "counter",
+            "Handshakes completed with the peer.",
+            each(&|peer| Some(peer.handshakes_completed as f64)),
+        );
+        metric(
+            "handshakes_failed_total",
+            "counter",
+            "Handshakes that failed, by reason. Failures without a peer label happened before one was known.",
+            self.handshakes_failed
+                .iter()
+                .map(|(reason, count)| (format!("{{reason=\"{reason}\"}}"), *count as f64))
+                .chain(peers.iter().flat_map(|(key, peer)| {
+                    peer.handshakes_failed.iter().map(move |(reason, count)| {
+                        (format!("{{peer=\"{key}\",reason=\"{reason}\"}}"), *count as f64)
+                    })
+                }))
+                .collect(),
+        );
+        metric(
+            "replay_drops_total",
+            "counter",
+            "Transport packets dropped as replays.",
+            each(&|peer| Some(peer.replay_drops as f64)),
+        );
+        metric(
+            "transport_dropped_total",
+            "counter",
+            "Transport packets dropped for an unknown index or because they did not open, by reason.",
+            self.transport_dropped
+                .iter()
+                .map(|(reason, count)| (format!("{{reason=\"{reason}\"}}"), *count as f64))
+                .collect(),
+        );
+        metric(
+            "cookie_replies_total",
+            "counter",
+            "Cookie replies sent while under load.",
+            vec![(String::new(), self.cookie_replies as f64)],
+        );
+        metric(
+            "session_age_seconds",
+            "gauge",
+            "Age of the current session with the peer.",
+            each(&|peer| Some(peer.session_age?.as_secs_f64())),
+        );
+        metric(
+            "last_handshake_seconds",
+            "gauge",
+            "UNIX time of the last completed handshake with the peer.",
+            each(&|peer| Some(peer.last_handshake?.as_secs_f64())),
+        );
+        out
+    }
+}
+
+// how long a scraper gets to send its request and take the answer, one at a time
+#[cfg(feature = "prometheus")]
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(0x02);
+
+// serves the device's stats to whoever connects, a connection that fails is dropped and the next one taken
+#[cfg(feature = "prometheus")]
+pub fn serve(
+    device: std::sync::Arc<crate::device::Device>,
+    listener: std::net::TcpListener,
+) -> std::io::Result<()> {
+    use crate::trace::debug;
+    use std::{
+        io::{BufRead, BufReader},
+        thread,
+        time::SystemTime,
+    };
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_error) => {
+                // out of descriptors and the like, which a moment may well fix
+                debug!(error = %_error, "failed to accept stats connection");
+                thread::sleep(Duration::from_millis(0x64));
+                continue;
+            }
+        };
+        // a client that goes quiet would otherwise hold up every scrape after it
+        if stream.set_read_timeout(Some(SCRAPE_TIMEOUT)).is_err()
+            || stream.set_write_timeout(Some(SCRAPE_TIMEOUT)).is_err()
+        {
+            continue;
+        }
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        if reader.read_line(&mut line).is_err() {
+            continue;
+        }
+        // drain the headers, closing with unread data would reset the connection
+        let mut header = String::new();
+        while reader.read_line(&mut header).is_ok_and(|n| n > 0x02) {
+            header.clear();
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let (status, body) = match line.split_whitespace().nth(0x01) {
+            Some("/metrics") => ("200 OK", device.stats(now).to_prometheus()),
+            _ => ("404 Not Found", String::new()),
+        };
+        let _ = std::io::Write::write_all(
+            &mut stream,
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        );
+    }
+    Ok(())
+}
+
+fn nanos(time: Duration) -> u64 {
+    time.as_nanos().clamp(0x01, u64::MAX as u128) as u64
+}
+
+fn since(nanos: u64) -> Option<Duration> {
+    (nanos != 0x00).then(|| Duration::from_nanos(nanos))
+}
+
+fn hex(public: &PublicKey) -> String {
+    public
+        .as_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(all(test, feature = "prometheus"))]
+mod tests {
+    use super::*;
+    use crate::{
+        device::{Device, LoadMonitor},
+        ratelimiter::RateLimiter,
+    };
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+        thread,
+    };
+    use x25519::StaticSecret;
+
+    fn scrape(address: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn local_scrape() {
+        let device = Arc::new(Device::new(
+            StaticSecret::from([0x01; 0x20]),
+            LoadMonitor::default(),
+            RateLimiter::default(),
+        ));
+        device.metrics().cookie_reply();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve(device, listener));
+
+        // a client that connects and says nothing only holds things up until it times out
+        let _quiet = TcpStream::connect(address).unwrap();
+        let response = scrape(address, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("cookie_replies_total 1"));
+        assert!(scrape(address, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use x25519::PublicKey;
+
+use crate::trace::debug;
+
+// what the responder remembers about a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub timestamp: [u8; 0x0c], // greatest TAI64N timestamp seen in an initiation
+    pub handshake: Duration,   // when that initiation was answered, duration since UNIX epoch
+}
+
+// somewhere records outlive the process, so initiations replayed after a restart are still refused
+pub trait TimestampStore: Send + Sync {
+    // every record there is, read once when the device picks the store up
+    fn load(&self) -> io::Result<HashMap<[u8; 0x20], Record>>;
+
+    // called with the new record every time the responder answers peer, with the device's records locked
+    // so saves come in the order timestamps were accepted, it should hand the writing off rather than block
+    fn save(&self, peer: &PublicKey, record: Record) -> io::Result<()>;
+}
+
+// one line per peer: public key, timestamp, and last handshake in nanoseconds, the first two in hex
+// saves are batched by a writer thread, at most one write every WRITE_INTERVAL, and a crash loses at most that much
+// every write goes through a synced temporary file renamed over the old one, so the file is always whole
+pub struct FileStore {
+    shared: Arc<Shared>,
+    writer: Option<JoinHandle<()>>,
+}
+
+// how long records can wait before they reach the disk
+pub const WRITE_INTERVAL: Duration = Duration::from_secs(0x01);
+
+struct Shared {
+    path: PathBuf,
+    state: Mutex<State>,
+    writing: Mutex<()>, // one write at a time, so an older snapshot never lands over a newer one
+    wake: Condvar,      // a save made the records dirty, or the store is going away
+}
+
+struct State {
+    records: HashMap<[u8; 0x20], Record>,
+    dirty: bool,  // records has changes the file does not
+    closed: bool, // the store was dropped, the writer flushes once more and stops
+}
+
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let shared = Arc::new(Shared {
+            path: path.into(),
+            state: Mutex::new(State {
+                records: HashMap::new(),
+                dirty: false,
+                closed: false,
+            }),
+            writing: Mutex::new(()),
+            wake: Condvar::new(),
+        });
+        let writer = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.write_behind())
+        };
+        Self {
+            shared,
+            writer: Some(writer),
+        }
+    }
+
+    // writes whatever has not been written yet, straight away
+    pub fn flush(&self) -> io::Result<()> {
+        self.shared.flush()
+    }
+}
+
+impl Shared {
+    fn write_behind(&self) {
+        loop {
+            let closed = {
+                let state = self.state.lock().unwrap();
+                let state = self
+                    .wake
+                    .wait_while(state, |state| !state.dirty && !state.closed)
+                    .unwrap();
+                state.closed
+            };
+            if let Err(_error) = self.flush() {
+                // still dirty, so the next round tries again
+                debug!(error = %_error, "failed to write timestamp records");
+            }
+            if closed {
+                return;
+            }
+            // saves in the meantime pile up for the next write, only dropping the store cuts the wait short
+            let state = self.state.lock().unwrap();
+            drop(
+                self.wake
+                    .wait_timeout_while(state, WRITE_INTERVAL, |state| !state.closed)
+                    .unwrap(),
+            );
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        let text = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty {
+                return Ok(());
+            }
+            state.dirty = false;
+            let mut text = String::new();
+            for (public, record) in state.records.iter() {
+                text.push_str(&format!(
+                    "{} {} {}\n",
+                    to_hex(public),
+                    to_hex(&record.timestamp),
+                    record.handshake.as_nanos() as u64
+                ));
+            }
+            text
+        };
+        self.write(&text).inspect_err(|_| {
+            self.state.lock().unwrap().dirty = true;
+        })
+    }
+
+    // the rename only counts once the directory entry is on disk too
+    fn write(&self, text: &str) -> io::Result<()> {
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        #[cfg(unix)]
+        {
+            let dir = self
+                .path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(".".as_ref());
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl TimestampStore for FileStore {
+    fn load(&self) -> io::Result<HashMap<[u8; 0x20], Record>> {
+        let text = match fs::read_to_string(&self.shared.path) {
+            Ok(text) => text,
+            // nothing saved yet
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => Err(error)?,
+        };
+        let mut records = HashMap::new();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let mut words = line.split_whitespace();
+            let (Some(public), Some(timestamp), Some(handshake), None) =
+                (words.next(), words.next(), words.next(), words.next())
+            else {
+                Err(invalid(line))?
+            };
+            let handshake = handshake.parse().map_err(|_| invalid(line))?;
+            records.insert(
+                from_hex(public).ok_or_else(|| invalid(line))?,
+                Record {
+                    timestamp: from_hex(timestamp).ok_or_else(|| invalid(line))?,
+                    handshake: Duration::from_nanos(handshake),
+                },
+            );
+        }
+        self.shared.state.lock().unwrap().records = records.clone();
+        Ok(records)
+    }
+
+    // only a newer timestamp replaces a record, whatever order saves come in
+    fn save(&self, peer: &PublicKey, record: Record) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        let last = state.records.get(peer.as_bytes());
+        if last.is_some_and(|last| record.timestamp <= last.timestamp) {
+            return Ok(());
+        }
+        state.records.insert(peer.to_bytes(), record);
+        state.dirty = true;
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+}
+
+// what was saved last still reaches the disk
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.wake.notify_one();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    let mut out = [0x00; N];
+    if text.len() != N * 0x02 || !text.is_ascii() {
+        return None;
+    }
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&text[i * 0x02..i * 0x02 + 0x02], 0x10).ok()?;
+    }
+    Some(out)
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad timestamp record: {line}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("shyvana-{name}-{}.timestamps", std::process::id()))
+    }
+
+    fn record(seconds: u8) -> Record {
+        let mut timestamp = [0x00; 0x0c];
+        timestamp[0x07] = seconds;
+        Record {
+            timestamp,
+            handshake: Duration::from_secs(seconds as u64),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = path("round-trip");
+        let (a, b) = (PublicKey::from([0x01; 0x20]), PublicKey::from([0x02; 0x20]));
+        let store = FileStore::new(&path);
+        assert!(store.load().unwrap().is_empty());
+        store.save(&a, record(0x05)).unwrap();
+        store.save(&b, record(0x07)).unwrap();
+        store.flush().unwrap();
+        let records = FileStore::new(&path).load().unwrap();
+        assert_eq!(records.len(), 0x02);
+        assert_eq!(records[a.as_bytes()], record(0x05));
+        assert_eq!(records[b.as_bytes()], record(0x07));
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        assert!(!PathBuf::from(temporary).exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn older_save_keeps_newer() {
+        let path = path("older");
+        let a = PublicKey::from([0x01; 0x20]);
+        let store = FileStore::new(&path);
+        store.save(&a, record(0x09)).unwrap();
+        store.save(&a, record(0x03)).unwrap();
+        store.flush().unwrap();
+        assert_eq!(
+            FileStore::new(&path).load().unwrap()[a.as_bytes()],
+            record(0x09)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drop_writes_the_rest() {
+        let path = path("drop");
+        let a = PublicKey::from([0x01; 0x20]);
+        let store = FileStore::new(&path);
+        // nothing flushed by hand, whatever the writer had not got to yet still goes out
+        store.save(&a, record(0x01)).unwrap();
+        store.save(&a, record(0x02)).unwrap();
+        drop(store);
+        assert_eq!(
+            FileStore::new(&path).load().unwrap()[a.as_bytes()],
+            record(0x02)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn malformed() {
+        let path = path("malformed");
+        for text in [
+            "not a record\n",
+            &format!("{} {} 5 extra\n", "01".repeat(0x20), "00".repeat(0x0c)),
+            &format!("{} {} 5\n", "01".repeat(0x1f), "00".repeat(0x0c)),
+            &format!("{} {} soon\n", "01".repeat(0x20), "00".repeat(0x0c)),
+        ] {
+            fs::write(&path, text).unwrap();
+            let error = FileStore::new(&path).load().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
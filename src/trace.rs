@@ -0,0 +1,57 @@
+// thin wrappers around tracing, so call sites compile to nothing when the feature is off
+// fields must only ever be indices, endpoints, fingerprints and reasons, never key material
+
+#[cfg(feature = "tracing")]
+use x25519::PublicKey;
+
+#[cfg(feature = "tracing")]
+macro_rules! debug {
+    ($($t:tt)*) => { tracing::debug!($($t)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug {
+    ($($t:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! trace {
+    ($($t:tt)*) => { tracing::trace!($($t)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace {
+    ($($t:tt)*) => {};
+}
+
+// only some features have anything to warn about
+#[cfg(feature = "tracing")]
+#[allow(unused_macros)]
+macro_rules! warning {
+    ($($t:tt)*) => { tracing::warn!($($t)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+#[allow(unused_macros)]
+macro_rules! warning {
+    ($($t:tt)*) => {};
+}
+
+pub(crate) use debug;
+pub(crate) use trace;
+// under another name, a bare warn clashes with the lint attribute
+#[allow(unused_imports)]
+pub(crate) use warning as warn;
+
+// first four bytes of a public key, enough to tell peers apart in logs
+#[cfg(feature = "tracing")]
+pub(crate) struct Fingerprint<'a>(pub &'a PublicKey);
+
+#[cfg(feature = "tracing")]
+impl std::fmt::Display for Fingerprint<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.as_bytes()[0x00..0x04]
+            .iter()
+            .try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::Mutex,
+    time::Duration,
+};
+
+// the largest message a stream transport carries, anything bigger is a broken stream
+pub const MAX_MESSAGE: usize = 0xffff;
+
+// whatever carries WireGuard messages between the peers
+// stream transports deliver whole messages, so callers see datagrams either way
+pub trait Transport: Send + Sync {
+    // dst is where the peer was last heard from, streams only have the one peer and ignore it
+    fn send_to(&self, msg: &[u8], dst: SocketAddr) -> io::Result<()>;
+
+    // none blocks until a message arrives, timing out is WouldBlock or TimedOut like std's sockets
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, SocketAddr)>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, msg: &[u8], dst: SocketAddr) -> io::Result<()> {
+        UdpSocket::send_to(self, msg, dst).map(|_| ())
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, SocketAddr)> {
+        self.set_read_timeout(timeout)?;
+        UdpSocket::recv_from(self, buf)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Transport for crate::udp::UdpSocket {
+    fn send_to(&self, msg: &[u8], dst: SocketAddr) -> io::Result<()> {
+        Transport::send_to(self.inner(), msg, dst)
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, SocketAddr)> {
+        Transport::recv_from(self.inner(), buf, timeout)
+    }
+}
+
+// a stream and whatever has been read from it but not handed out yet
+// reads time out between messages as well as halfway through one, so partial messages have to be kept
+pub(crate) struct Buffered {
+    stream: TcpStream,
+    peer: SocketAddr,
+    read: Mutex<Vec<u8>>,
+}
+
+impl Buffered {
+    pub(crate) fn new(stream: TcpStream, read: Vec<u8>) -> io::Result<Self> {
+        // messages are latency sensitive and already as big as they are going to get
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            peer: stream.peer_addr()?,
+            stream,
+            read: Mutex::new(read),
+        })
+    }
+
+    pub(crate) fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    // for when the stream cannot be trusted any more, whoever is reading it gets an error or the end
+    #[cfg(feature = "websocket")]
+    pub(crate) fn shutdown(&self) {
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+
+    pub(crate) fn write(&self, bytes: &[u8]) -> io::Result<()> {
+        (&self.stream).write_all(bytes)
+    }
+
+    // calls parse on what has been read so far, reading more until it finds a message
+    // parse returns the message and how many bytes it took up, or none if there is not enough yet
+    pub(crate) fn read<T>(
+        &self,
+        timeout: Option<Duration>,
+        mut parse: impl FnMut(&[u8]) -> io::Result<Option<(T, usize)>>,
+    ) -> io::Result<T> {
+        let mut read = self.read.lock().unwrap();
+        self.stream.set_read_timeout(timeout)?;
+        let mut chunk = [0x00; 0x1000];
+        loop {
+            if let Some((value, length)) = parse(&read)? {
+                read.drain(0x00..length);
+                return Ok(value);
+            }
+            match (&self.stream).read(&mut chunk)? {
+                0x00 => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+                length => read.extend_from_slice(&chunk[0x00..length]),
+            }
+        }
+    }
+}
+
+// each message goes out behind its length as a big endian u16, the framing TCP-only networks need
+pub struct TcpTransport {
+    stream: Buffered,
+}
+
+impl TcpTransport {
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        Self::new(TcpStream::connect(addr)?)
+    }
+
+    // waits for the peer to connect, one connection per tunnel
+    pub fn accept(listener: &TcpListener) -> io::Result<Self> {
+        Self::new(listener.accept()?.0)
+    }
+
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            stream: Buffered::new(stream, Vec::new())?,
+        })
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.stream.peer()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_to(&self, msg: &[u8], _: SocketAddr) -> io::Result<()> {
+        let length =
+            u16::try_from(msg.len()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mut frame = Vec::with_capacity(0x02 + msg.len());
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame.extend_from_slice(msg);
+        self.stream.write(&frame)
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, SocketAddr)> {
+        let length = self.stream.read(timeout, |read| {
+            let Some(length) = read.get(0x00..0x02) else {
+                return Ok(None);
+            };
+            let length = u16::from_be_bytes([length[0x00], length[0x01]]) as usize;
+            let Some(msg) = read.get(0x02..0x02 + length) else {
+                return Ok(None);
+            };
+            // too big for buf is cut short, like a UDP socket would
+            let copied = length.min(buf.len());
+            buf[0x00..copied].copy_from_slice(&msg[0x00..copied]);
+            Ok(Some((copied, 0x02 + length)))
+        })?;
+        Ok((length, self.stream.peer()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // a connected pair on loopback, the transport and the raw stream its peer writes to
+    fn pair() -> (TcpTransport, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (TcpTransport::accept(&listener).unwrap(), peer)
+    }
+
+    #[test]
+    fn message_split_across_reads() {
+        let (transport, mut peer) = pair();
+        let peer_addr = peer.local_addr().unwrap();
+        let msg = [0xa5; 0x94];
+        let mut frame = (msg.len() as u16).to_be_bytes().to_vec();
+        frame.extend_from_slice(&msg);
+        // half of the length, then half of the message, each read timing out in between
+        let mut buf = [0x00; 0x0100];
+        for piece in [&frame[0x00..0x01], &frame[0x01..0x40]] {
+            peer.write_all(piece).unwrap();
+            peer.flush().unwrap();
+            let err = transport
+                .recv_from(&mut buf, Some(Duration::from_millis(0x32)))
+                .unwrap_err();
+            assert!(matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ));
+        }
+        peer.write_all(&frame[0x40..]).unwrap();
+        let (length, src) = transport.recv_from(&mut buf, None).unwrap();
+        assert_eq!((&buf[0x00..length], src), (&msg[..], peer_addr));
+    }
+
+    #[test]
+    fn largest_message() {
+        let (transport, peer) = pair();
+        let echo = thread::spawn(move || {
+            let peer = TcpTransport::new(peer).unwrap();
+            let mut buf = vec![0x00; MAX_MESSAGE];
+            let (length, src) = peer.recv_from(&mut buf, None).unwrap();
+            peer.send_to(&buf[0x00..length], src).unwrap();
+        });
+        let dst = transport.peer();
+        // one byte too many does not fit in the length, so it is refused before anything is written
+        let err = transport
+            .send_to(&vec![0x5a; MAX_MESSAGE + 0x01], dst)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let msg = vec![0x5a; MAX_MESSAGE];
+        transport.send_to(&msg, dst).unwrap();
+        let mut buf = vec![0x00; MAX_MESSAGE + 0x01];
+        let (length, _) = transport.recv_from(&mut buf, None).unwrap();
+        assert_eq!(&buf[0x00..length], msg);
+        echo.join().unwrap();
+    }
+}
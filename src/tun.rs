@@ -0,0 +1,205 @@
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{self, IoSlice, Read, Write},
+    mem::size_of,
+    os::fd::{AsRawFd, RawFd},
+};
+use zerocopy::{byteorder::native_endian::U16, AsBytes, FromBytes, FromZeroes, Unaligned};
+
+use crate::checksum::{fold, sum};
+
+// virtio_net_hdr.flags
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 0x01;
+
+// virtio_net_hdr.gso_type
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0x00;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 0x01;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 0x04;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+// the largest packet the kernel hands out with TSO enabled
+pub const MAX_PACKET: usize = 0xffff + size_of::<VirtioNetHdr>();
+
+#[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: U16,
+    gso_size: U16,
+    csum_start: U16,
+    csum_offset: U16,
+}
+
+pub struct Tun {
+    file: File,
+    name: String,
+}
+
+impl Tun {
+    pub fn open(name: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+        // SAFETY: ifreq is plain old data, so all zeroes is a valid value
+        let mut ifreq: libc::ifreq = unsafe { core::mem::zeroed() };
+        if name.len() >= ifreq.ifr_name.len() {
+            Err(io::Error::from(io::ErrorKind::InvalidInput))?
+        }
+        for (dst, src) in ifreq.ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        ifreq.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI | libc::IFF_VNET_HDR) as _;
+        // SAFETY: the descriptor is open and ifreq outlives the call
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut ifreq) } < 0x00 {
+            Err(io::Error::last_os_error())?
+        }
+        let offload = libc::TUN_F_CSUM | libc::TUN_F_TSO4 | libc::TUN_F_TSO6;
+        // SAFETY: the descriptor is open and TUNSETOFFLOAD takes its argument by value
+        if unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                libc::TUNSETOFFLOAD,
+                offload as libc::c_ulong,
+            )
+        } < 0x00
+        {
+            Err(io::Error::last_os_error())?
+        }
+        // SAFETY: the kernel nul terminates the name it picked
+        let name = unsafe { CStr::from_ptr(ifreq.ifr_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        Ok(Self { file, name })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // reads one packet, which may be a TSO super segment, and appends the resulting IP packets to packets
+    // each one is laid out like Decrypted and paired with its length, ready for Encryptor or Lane::encrypt
+    pub fn recv(&self, buffer: &mut [u8], packets: &mut Vec<(Vec<u8>, usize)>) -> io::Result<()> {
+        let length = (&self.file).read(buffer)?;
+        split(&buffer[0x00..length], packets)
+    }
+
+    // writes one IP packet, with a header that asks for no offloads
+    pub fn send(&self, packet: &[u8]) -> io::Result<()> {
+        let hdr = VirtioNetHdr::new_zeroed();
+        let length =
+            (&self.file).write_vectored(&[IoSlice::new(hdr.as_bytes()), IoSlice::new(packet)])?;
+        if length != size_of::<VirtioNetHdr>() + packet.len() {
+            Err(io::Error::from(io::ErrorKind::WriteZero))?
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Tun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+// splits a packet read from a tun device with IFF_VNET_HDR into plain IP packets, finishing any offloaded checksums
+pub fn split(buffer: &[u8], packets: &mut Vec<(Vec<u8>, usize)>) -> io::Result<()> {
+    let (hdr, packet) = VirtioNetHdr::read_from_prefix(buffer)
+        .map(|hdr| (hdr, &buffer[size_of::<VirtioNetHdr>()..]))
+        .ok_or_else(invalid)?;
+    let csum_start = hdr.csum_start.get() as usize;
+    match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE => {
+            let mut out = decrypted(packet);
+            if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0x00 {
+                let csum_at = csum_start + hdr.csum_offset.get() as usize;
+                finish(&mut out[0x10..0x10 + packet.len()], csum_start, csum_at)?;
+            }
+            packets.push((out, packet.len()));
+        }
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => {
+            let tcp = packet.get(csum_start..).ok_or_else(invalid)?;
+            let hdr_len =
+                csum_start + (*tcp.get(0x0c).ok_or_else(invalid)? >> 0x04) as usize * 0x04;
+            let gso_size = hdr.gso_size.get() as usize;
+            let v4 = hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN == VIRTIO_NET_HDR_GSO_TCPV4;
+            if csum_start < if v4 { 0x14 } else { 0x28 }
+                || hdr_len < csum_start + 0x14
+                || hdr_len > packet.len()
+                || gso_size == 0x00
+            {
+                Err(invalid())?
+            }
+            let seq = u32::from_be_bytes(
+                packet[csum_start + 0x04..csum_start + 0x08]
+                    .try_into()
+                    .unwrap(),
+            );
+            let chunks = packet[hdr_len..].chunks(gso_size);
+            let count = chunks.len();
+            for (i, chunk) in chunks.enumerate() {
+                let length = hdr_len + chunk.len();
+                let mut out = vec![0x00; 0x20 + length.next_multiple_of(0x10)];
+                let seg = &mut out[0x10..0x10 + length];
+                seg[0x00..hdr_len].copy_from_slice(&packet[0x00..hdr_len]);
+                seg[hdr_len..].copy_from_slice(chunk);
+                if v4 {
+                    // total length, identification and header checksum
+                    seg[0x02..0x04].copy_from_slice(&(length as u16).to_be_bytes());
+                    let id = u16::from_be_bytes([seg[0x04], seg[0x05]]).wrapping_add(i as u16);
+                    seg[0x04..0x06].copy_from_slice(&id.to_be_bytes());
+                    seg[0x0a..0x0c].fill(0x00);
+                    let sum = !fold(sum(0x00, &seg[0x00..csum_start]));
+                    seg[0x0a..0x0c].copy_from_slice(&sum.to_be_bytes());
+                } else {
+                    // payload length
+                    seg[0x04..0x06].copy_from_slice(&((length - 0x28) as u16).to_be_bytes());
+                }
+                let seq = seq.wrapping_add((i * gso_size) as u32);
+                seg[csum_start + 0x04..csum_start + 0x08].copy_from_slice(&seq.to_be_bytes());
+                if i + 0x01 < count {
+                    // FIN and PSH only belong on the last segment
+                    seg[csum_start + 0x0d] &= !0x09;
+                }
+                finish(seg, csum_start, csum_start + 0x10)?;
+                packets.push((out, length));
+            }
+        }
+        _ => Err(invalid())?,
+    }
+    Ok(())
+}
+
+// computes the transport checksum from scratch, pseudo header included
+fn finish(packet: &mut [u8], csum_start: usize, csum_at: usize) -> io::Result<()> {
+    if csum_at + 0x02 > packet.len() || csum_start > csum_at {
+        Err(invalid())?
+    }
+    let length = (packet.len() - csum_start) as u32;
+    let pseudo = match packet[0x00] >> 0x04 {
+        0x04 if packet.len() >= 0x14 => sum(length + packet[0x09] as u32, &packet[0x0c..0x14]),
+        0x06 if packet.len() >= 0x28 => sum(length + packet[0x06] as u32, &packet[0x08..0x28]),
+        _ => Err(invalid())?,
+    };
+    packet[csum_at..csum_at + 0x02].fill(0x00);
+    let csum = match !fold(sum(pseudo, &packet[csum_start..])) {
+        // zero means no checksum for UDP
+        0x0000 => 0xffff,
+        csum => csum,
+    };
+    packet[csum_at..csum_at + 0x02].copy_from_slice(&csum.to_be_bytes());
+    Ok(())
+}
+
+// copies a packet into a buffer laid out like Decrypted
+fn decrypted(packet: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x00; 0x20 + packet.len().next_multiple_of(0x10)];
+    out[0x10..0x10 + packet.len()].copy_from_slice(packet);
+    out
+}
+
+fn invalid() -> io::Error {
+    io::Error::from(io::ErrorKind::InvalidData)
+}
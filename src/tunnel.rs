@@ -1,20 +1,412 @@
 use std::{
     collections::HashMap,
-    sync::{Mutex, RwLock},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
-use x25519::{PublicKey, StaticSecret};
+use x25519::{PublicKey, ReusableSecret};
 
 use crate::{
-    cipher::{Decryptor, Encryptor},
-    handshake::{Initiator, Responder},
+    cipher::{Decrypted, Decryptor, Encryptor, Window},
+    device::Device,
+    error::{Error, Peer, Result},
+    handshake::{Initiator, PeerKeys, Responder},
+    obfuscation::Obfuscation,
+    packet::{HandshakeInit, HandshakeResp, TransportData},
 };
 
+// sessions older than this get replaced by a fresh handshake
+pub const REKEY_AFTER_TIME: Duration = Duration::from_secs(0x78);
+
+// an initiation that has gone this long without a response gets sent again
+pub const REKEY_TIMEOUT: Duration = Duration::from_secs(0x05);
+
+// a receiving session this old opens nothing more
+pub const REJECT_AFTER_TIME: Duration = Duration::from_secs(0xb4);
+
+// receiving sessions kept at once: previous, current and next
+const SESSIONS: usize = 0x03;
+
+// a handshake this side started, with the preshared key it started with
+struct Pending {
+    initiator: Initiator,
+    p_k: Option<[u8; 0x20]>,
+}
+
 pub struct Tunnel {
-    self_secret: StaticSecret,
-    peer_public: PublicKey,
-    preshared_key: [u8; 0x20],
-    initiator_map: Mutex<HashMap<[u8; 0x04], Initiator>>,
-    encryptor: Mutex<Encryptor>,
-    decryptor_map: RwLock<HashMap<[u8; 0x04], Decryptor>>,
+    device: Arc<Device>,
+    peer_keys: PeerKeys,
+    preshared_key: RwLock<[u8; 0x20]>, // all zero means none, used when the device's provider has nothing
+    obfuscation: RwLock<Obfuscation>,
+    initiator_map: Mutex<HashMap<u32, Pending>>,
+    encryptor: Mutex<Option<Encryptor>>,
+    decryptor_map: RwLock<HashMap<u32, (Arc<Decryptor>, Duration)>>, // with when each was set up
+}
+
+impl Tunnel {
+    pub fn new(device: Arc<Device>, peer_public: PublicKey, preshared_key: [u8; 0x20]) -> Self {
+        Self {
+            peer_keys: PeerKeys::new(device.secret(), &peer_public),
+            device,
+            preshared_key: RwLock::new(preshared_key),
+            obfuscation: RwLock::new(Obfuscation::default()),
+            initiator_map: Mutex::new(HashMap::new()),
+            encryptor: Mutex::new(None),
+            decryptor_map: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // sessions already up keep their keys, the new one is mixed into the next handshake
+    pub fn set_preshared_key(&self, preshared_key: [u8; 0x20]) {
+        *self.preshared_key.write().unwrap() = preshared_key;
+    }
+
+    pub fn preshared_key(&self) -> Option<[u8; 0x20]> {
+        let p_k = *self.preshared_key.read().unwrap();
+        (p_k != [0x00; 0x20]).then_some(p_k)
+    }
+
+    // the peer has to be set up with the same values, or nothing gets through either way
+    pub fn set_obfuscation(&self, obfuscation: Obfuscation) -> Result<()> {
+        obfuscation.validate()?;
+        *self.obfuscation.write().unwrap() = obfuscation;
+        Ok(())
+    }
+
+    pub fn obfuscation(&self) -> Obfuscation {
+        *self.obfuscation.read().unwrap()
+    }
+
+    // the provider gets the first say, so keys it pushes take effect at the next handshake
+    pub fn current_preshared_key(&self, now: Duration) -> Option<[u8; 0x20]> {
+        self.device
+            .preshared_key(self.peer_keys.public(), now)
+            .or_else(|| self.preshared_key())
+    }
+
+    pub fn peer_public(&self) -> &PublicKey {
+        self.peer_keys.public()
+    }
+
+    pub fn peer_keys(&self) -> &PeerKeys {
+        &self.peer_keys
+    }
+
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    pub fn send_handshake_init(
+        &self,
+        i_i: u32,                // index the response will be addressed to
+        e_s: ReusableSecret,     // initiator ephemeral secret
+        now: Duration,           // duration since UNIX epoch
+        l_c: Option<[u8; 0x10]>, // latest cookie received
+        msg: &mut HandshakeInit, // destination buffer
+    ) -> Result<()> {
+        let initiator = Initiator::send_handshake_init(
+            i_i,
+            self.device.public(),
+            &self.peer_keys,
+            e_s,
+            now,
+            l_c,
+            msg,
+        )?;
+        // only the latest initiation is worth answering
+        let mut initiator_map = self.initiator_map.lock().unwrap();
+        initiator_map.clear();
+        initiator_map.insert(
+            i_i,
+            Pending {
+                initiator,
+                p_k: self.current_preshared_key(now),
+            },
+        );
+        Ok(())
+    }
+
+    // answers an initiation and installs the session it sets up
+    pub fn recv_handshake_init(
+        &self,
+        src: &HandshakeInit,     // initiation being answered
+        r_i: u32,                // index transport data will be addressed to
+        e_s: ReusableSecret,     // responder ephemeral secret
+        now: Duration,           // duration since UNIX epoch
+        l_c: Option<[u8; 0x10]>, // latest cookie received
+        msg: &mut HandshakeResp, // destination buffer
+    ) -> Result<()> {
+        let responder = Responder::recv_handshake_init(
+            self.device.secret(),
+            self.device.public(),
+            &self.peer_keys,
+            src,
+        )?;
+        // a replayed initiation decrypts just as well, only its timestamp gives it away
+        self.device
+            .accept_timestamp(self.peer_public(), responder.timestamp(), now)
+            .map_err(|e| e.with_peer(Peer::Key(*self.peer_public())))?;
+        let i_i = src.s_i.get();
+        let (s_k, r_k) = responder.send_handshake_resp(
+            i_i,
+            &self.peer_keys,
+            r_i,
+            e_s,
+            self.current_preshared_key(now),
+            l_c,
+            msg,
+        )?;
+        self.install(Encryptor::new(i_i, s_k), Decryptor::new(r_i, r_k), now);
+        Ok(())
+    }
+
+    // completes a handshake this side started and installs the session
+    pub fn recv_handshake_resp(
+        &self,
+        src: &HandshakeResp, // response to an initiation of ours
+        now: Duration,       // duration since UNIX epoch
+    ) -> Result<()> {
+        let i_i = src.r_i.get();
+        let mut initiator_map = self.initiator_map.lock().unwrap();
+        let Pending { initiator, p_k } = initiator_map
+            .get(&i_i)
+            .ok_or_else(|| Error::UnknownReceiver.with_peer(Peer::Index(i_i)))?;
+        let (s_k, r_k) = initiator.recv_handshake_resp(self.device.secret(), *p_k, src)?;
+        // a forged response leaves the initiation waiting for the real one
+        initiator_map.remove(&i_i);
+        drop(initiator_map);
+        self.install(
+            Encryptor::new(src.s_i.get(), s_k),
+            Decryptor::new(i_i, r_k),
+            now,
+        );
+        Ok(())
+    }
+
+    // seals an inner packet into a transport data message for the current session
+    pub fn encrypt(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        let mut buffer = vec![0x00; 0x20 + packet.len().next_multiple_of(0x10)];
+        buffer[0x10..0x10 + packet.len()].copy_from_slice(packet);
+        let mut decrypted = Decrypted::new(&mut buffer)?;
+        decrypted.resize(packet.len());
+        let length = match self.encryptor.lock().unwrap().as_mut() {
+            Some(encryptor) => encryptor.encrypt(decrypted)?.len(),
+            None => Err(Error::SessionExpired.with_peer(Peer::Key(*self.peer_public())))?,
+        };
+        buffer.truncate(length);
+        Ok(buffer)
+    }
+
+    // opens a transport data message in place and returns the inner packet, empty for a keepalive
+    pub fn decrypt<'a>(&self, datagram: &'a mut [u8], now: Duration) -> Result<&'a [u8]> {
+        let r_i = TransportData::wrap_ref(datagram)?.r_i.get();
+        self.decryptor(r_i, now)?.decrypt(datagram)?;
+        Ok(inner(datagram))
+    }
+
+    // the receiving session transport data addressed to r_i is opened with, for opening it somewhere else
+    pub fn decryptor(&self, r_i: u32, now: Duration) -> Result<Arc<Decryptor>> {
+        match self.decryptor_map.read().unwrap().get(&r_i) {
+            Some((decryptor, born)) if now.saturating_sub(*born) < REJECT_AFTER_TIME => {
+                Ok(decryptor.clone())
+            }
+            Some(_) => Err(Error::SessionExpired.with_peer(Peer::Index(r_i))),
+            None => Err(Error::UnknownReceiver.with_peer(Peer::Index(r_i))),
+        }
+    }
+
+    // the next amount counters of the current session, for sealing packets somewhere else
+    pub fn reserve(&self, amount: u64) -> Result<Encryptor> {
+        match self.encryptor.lock().unwrap().as_mut() {
+            Some(encryptor) if encryptor.s_c() < encryptor.s_b() => Ok(encryptor.reserve(amount)),
+            Some(_) => Err(Error::CounterExhausted.with_peer(Peer::Key(*self.peer_public()))),
+            None => Err(Error::SessionExpired.with_peer(Peer::Key(*self.peer_public()))),
+        }
+    }
+
+    // takes the rest of the sending counter range for another process, nothing more goes out from here
+    pub fn hand_over(&self) -> Option<Encryptor> {
+        self.encryptor.lock().unwrap().as_mut().map(|encryptor| {
+            let amount = encryptor.s_b() - encryptor.s_c();
+            encryptor.reserve(amount)
+        })
+    }
+
+    // index, key, replay window and set up time of every receiving session
+    pub fn receiving(&self) -> Vec<(u32, [u8; 0x20], Window, Duration)> {
+        self.decryptor_map
+            .read()
+            .unwrap()
+            .values()
+            .map(|(decryptor, born)| (decryptor.r_i(), decryptor.key(), decryptor.window(), *born))
+            .collect()
+    }
+
+    // like receiving, but the sessions are gone from here afterwards, so nothing opens here that was not in the snapshot
+    pub fn stop_receiving(&self) -> Vec<(u32, [u8; 0x20], Window, Duration)> {
+        std::mem::take(&mut *self.decryptor_map.write().unwrap())
+            .into_values()
+            .map(|(decryptor, born)| (decryptor.r_i(), decryptor.key(), decryptor.window(), born))
+            .collect()
+    }
+
+    // installs sessions handed over by another process in place of whatever is here
+    pub fn resume(&self, encryptor: Option<Encryptor>, decryptors: Vec<(Decryptor, Duration)>) {
+        *self.decryptor_map.write().unwrap() = decryptors
+            .into_iter()
+            .map(|(decryptor, born)| (decryptor.r_i(), (Arc::new(decryptor), born)))
+            .collect();
+        *self.encryptor.lock().unwrap() = encryptor;
+    }
+
+    fn install(&self, encryptor: Encryptor, decryptor: Decryptor, now: Duration) {
+        let mut decryptor_map = self.decryptor_map.write().unwrap();
+        decryptor_map.retain(|_, (_, born)| now.saturating_sub(*born) < REJECT_AFTER_TIME);
+        // the peer may still be sending on the one before, anything older is gone for good
+        while decryptor_map.len() >= SESSIONS {
+            let oldest = decryptor_map
+                .iter()
+                .min_by_key(|(_, (_, born))| *born)
+                .map(|(r_i, _)| *r_i)
+                .unwrap();
+            decryptor_map.remove(&oldest);
+        }
+        decryptor_map.insert(decryptor.r_i(), (Arc::new(decryptor), now));
+        drop(decryptor_map);
+        *self.encryptor.lock().unwrap() = Some(encryptor);
+    }
+}
+
+// the inner packet of a transport data message Decryptor::decrypt opened in place, empty for a keepalive
+pub fn inner(msg: &[u8]) -> &[u8] {
+    let length = msg.len();
+    let packet = &msg[0x10..length - 0x10];
+    // the padding is only told apart from the packet by the length in its IP header
+    let length = match packet.first().map(|b| b >> 0x04) {
+        Some(0x04) if packet.len() >= 0x14 => {
+            u16::from_be_bytes([packet[0x02], packet[0x03]]) as usize
+        }
+        Some(0x06) if packet.len() >= 0x28 => {
+            0x28 + u16::from_be_bytes([packet[0x04], packet[0x05]]) as usize
+        }
+        _ => 0x00,
+    };
+    &packet[0x00..length.min(packet.len())]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::ephemeral_from, device::LoadMonitor, ratelimiter::RateLimiter};
+    use x25519::StaticSecret;
+    use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+    const NOW: Duration = Duration::from_secs(0x6553f100);
+
+    fn pair() -> (Tunnel, Tunnel) {
+        let device = |secret| {
+            Arc::new(Device::new(
+                StaticSecret::from(secret),
+                LoadMonitor::default(),
+                RateLimiter::default(),
+            ))
+        };
+        let (a, b) = (device([0x01; 0x20]), device([0x02; 0x20]));
+        let (a_p, b_p) = (*a.public(), *b.public());
+        (
+            Tunnel::new(a, b_p, [0x00; 0x20]),
+            Tunnel::new(b, a_p, [0x00; 0x20]),
+        )
+    }
+
+    // a full handshake started by a, the session is set up at now
+    fn handshake(a: &Tunnel, b: &Tunnel, i_i: u32, r_i: u32, now: Duration) -> HandshakeResp {
+        let mut init = HandshakeInit::new_zeroed();
+        a.send_handshake_init(i_i, ephemeral_from([0x03; 0x20]), now, None, &mut init)
+            .unwrap();
+        let mut resp = HandshakeResp::new_zeroed();
+        b.recv_handshake_init(
+            &init,
+            r_i,
+            ephemeral_from([0x04; 0x20]),
+            now,
+            None,
+            &mut resp,
+        )
+        .unwrap();
+        resp
+    }
+
+    #[test]
+    fn forged_response_keeps_initiation() {
+        let (a, b) = pair();
+        let resp = handshake(&a, &b, 0x07, 0x09, NOW);
+        let mut forged = HandshakeResp::read_from(resp.as_bytes()).unwrap();
+        forged.e_n[0x00] ^= 0x01;
+        assert!(a.recv_handshake_resp(&forged, NOW).is_err());
+        // the real one still gets through, and only once
+        a.recv_handshake_resp(&resp, NOW).unwrap();
+        let err = a.recv_handshake_resp(&resp, NOW).unwrap_err();
+        assert!(matches!(err.kind(), Error::UnknownReceiver));
+        let mut datagram = a.encrypt(&[]).unwrap();
+        b.decrypt(&mut datagram, NOW).unwrap();
+    }
+
+    #[test]
+    fn other_peer_is_unknown() {
+        let (_, b) = pair();
+        // c has a tunnel to b, but b only has one to a
+        let c = Arc::new(Device::new(
+            StaticSecret::from([0x05; 0x20]),
+            LoadMonitor::default(),
+            RateLimiter::default(),
+        ));
+        let c = Tunnel::new(c, *b.device().public(), [0x00; 0x20]);
+        let mut init = HandshakeInit::new_zeroed();
+        c.send_handshake_init(0x07, ephemeral_from([0x03; 0x20]), NOW, None, &mut init)
+            .unwrap();
+        let mut resp = HandshakeResp::new_zeroed();
+        let err = b
+            .recv_handshake_init(
+                &init,
+                0x09,
+                ephemeral_from([0x04; 0x20]),
+                NOW,
+                None,
+                &mut resp,
+            )
+            .unwrap_err();
+        assert!(matches!(err.kind(), Error::UnknownPeer));
+        assert!(b.receiving().is_empty());
+    }
+
+    #[test]
+    fn keeps_three_sessions() {
+        let (a, b) = pair();
+        let mut now = NOW;
+        for i in 0x00..0x05 {
+            let resp = handshake(&a, &b, 0x10 + i, 0x20 + i, now);
+            a.recv_handshake_resp(&resp, now).unwrap();
+            now += REKEY_AFTER_TIME / 0x04;
+        }
+        let mut kept: Vec<_> = b.receiving().iter().map(|(r_i, ..)| *r_i).collect();
+        kept.sort();
+        assert_eq!(kept, [0x22, 0x23, 0x24]);
+        assert!(matches!(
+            b.decryptor(0x21, now).map(|_| ()).unwrap_err().kind(),
+            Error::UnknownReceiver
+        ));
+        // the newest still opens, until it is too old to
+        let mut datagram = a.encrypt(&[]).unwrap();
+        b.decrypt(&mut datagram.clone(), now).unwrap();
+        let err = b
+            .decrypt(&mut datagram, now + REJECT_AFTER_TIME)
+            .unwrap_err();
+        assert!(matches!(err.kind(), Error::SessionExpired));
+        // and stale ones are swept out when the next one comes along
+        let later = now + REJECT_AFTER_TIME;
+        let resp = handshake(&a, &b, 0x30, 0x40, later);
+        a.recv_handshake_resp(&resp, later).unwrap();
+        assert_eq!(b.receiving().len(), 0x01);
+    }
 }
@@ -0,0 +1,238 @@
+use nix::{
+    errno::Errno,
+    sys::socket::{
+        recvmmsg, sendmmsg, setsockopt,
+        sockopt::{UdpGroSegment, UdpGsoSegment},
+        ControlMessage, ControlMessageOwned, MsgFlags, MultiHeaders, SockaddrStorage,
+    },
+};
+use std::{
+    io::{self, IoSlice, IoSliceMut},
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs, UdpSocket as StdUdpSocket},
+    os::fd::AsRawFd,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// the kernel refuses to coalesce more than this many segments into one send
+const MAX_SEGMENTS: usize = 0x40;
+
+// the largest UDP payload that can be coalesced into one message
+pub const MAX_PAYLOAD: usize = 0xffff - 0x08 - 0x28;
+
+pub struct UdpSocket {
+    inner: StdUdpSocket,
+    gso: AtomicBool, // whether sends are coalesced with UDP_SEGMENT
+    gro: bool,       // whether receives are coalesced with UDP_GRO
+}
+
+impl UdpSocket {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let inner = StdUdpSocket::bind(addr)?;
+        // both are best effort, older kernels simply do not know about them
+        let gso = AtomicBool::new(setsockopt(&inner, UdpGsoSegment, &0x00).is_ok());
+        let gro = setsockopt(&inner, UdpGroSegment, &true).is_ok();
+        Ok(Self { inner, gso, gro })
+    }
+
+    pub fn inner(&self) -> &StdUdpSocket {
+        &self.inner
+    }
+
+    pub fn gso(&self) -> bool {
+        self.gso.load(Ordering::Relaxed)
+    }
+
+    pub fn gro(&self) -> bool {
+        self.gro
+    }
+
+    // the buffers should be MAX_PAYLOAD long when gro is on, since the kernel may coalesce into them
+    pub fn recv_batch<'a>(
+        &self,
+        buffers: &'a mut [Vec<u8>],
+    ) -> io::Result<Vec<(&'a mut [u8], SocketAddr)>> {
+        let mut received = Vec::with_capacity(buffers.len());
+        {
+            let mut slices: Vec<_> = buffers
+                .iter_mut()
+                .map(|buffer| [IoSliceMut::new(buffer)])
+                .collect();
+            let cmsg = self.gro.then(|| nix::cmsg_space!(i32));
+            let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(slices.len(), cmsg);
+            for msg in recvmmsg(
+                self.inner.as_raw_fd(),
+                &mut headers,
+                slices.iter_mut(),
+                // return as soon as anything is there instead of waiting for every buffer to fill
+                MsgFlags::MSG_WAITFORONE,
+                None,
+            )? {
+                let mut segment = msg.bytes;
+                for cmsg in msg.cmsgs()? {
+                    if let ControlMessageOwned::UdpGroSegments(size) = cmsg {
+                        segment = size as usize;
+                    }
+                }
+                let source = msg.address.as_ref().and_then(socket_addr);
+                received.push((msg.bytes, segment, source));
+            }
+        }
+        let mut datagrams = Vec::with_capacity(received.len());
+        for (buffer, (length, segment, source)) in buffers.iter_mut().zip(received) {
+            let Some(source) = source else {
+                continue;
+            };
+            if length == 0x00 {
+                datagrams.push((&mut buffer[0x00..0x00], source));
+                continue;
+            }
+            for datagram in buffer[0x00..length].chunks_mut(segment.max(0x01)) {
+                datagrams.push((datagram, source));
+            }
+        }
+        Ok(datagrams)
+    }
+
+    pub fn send_batch(&self, dst: SocketAddr, datagrams: &[&[u8]]) -> io::Result<()> {
+        let mut datagrams = datagrams;
+        if self.gso() {
+            let mut sent = 0x00;
+            match self.send_segments(dst, datagrams, &mut sent) {
+                // the egress device cannot checksum offload, so segmentation is off the table
+                // whatever went out before that stays out, the rest goes one datagram at a time
+                Err(error) if error.raw_os_error() == Some(Errno::EIO as i32) => {
+                    self.gso.store(false, Ordering::Relaxed);
+                    datagrams = &datagrams[sent..];
+                }
+                result => return result,
+            }
+        }
+        let addrs = vec![Some(SockaddrStorage::from(dst)); datagrams.len()];
+        let slices: Vec<_> = datagrams
+            .iter()
+            .map(|datagram| [IoSlice::new(datagram)])
+            .collect();
+        let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(slices.len(), None);
+        let mut sent = 0x00;
+        while sent < slices.len() {
+            sent += sendmmsg(
+                self.inner.as_raw_fd(),
+                &mut headers,
+                &slices[sent..],
+                &addrs[sent..],
+                [],
+                MsgFlags::empty(),
+            )?
+            .count();
+        }
+        Ok(())
+    }
+
+    // coalesces runs of equally sized datagrams, where the last one may be shorter, into one message each
+    // sent counts the datagrams that made it out, so a failure part way through can be picked up from there
+    fn send_segments(
+        &self,
+        dst: SocketAddr,
+        datagrams: &[&[u8]],
+        sent: &mut usize,
+    ) -> io::Result<()> {
+        let mut runs: Vec<(usize, Vec<IoSlice>)> = Vec::new();
+        for datagram in datagrams {
+            match runs.last_mut() {
+                Some((segment, slices))
+                    if slices.len() < MAX_SEGMENTS
+                        && slices.iter().map(|slice| slice.len()).sum::<usize>()
+                            + datagram.len()
+                            <= MAX_PAYLOAD
+                        && slices.last().unwrap().len() == *segment
+                        && datagram.len() <= *segment =>
+                {
+                    slices.push(IoSlice::new(datagram))
+                }
+                _ => runs.push((datagram.len(), vec![IoSlice::new(datagram)])),
+            }
+        }
+        // control messages are shared by every message in a sendmmsg call, so each segment size gets its own call
+        let mut start = 0x00;
+        while start < runs.len() {
+            let segment = runs[start].0;
+            let end = start
+                + runs[start..]
+                    .iter()
+                    .take_while(|(size, _)| *size == segment)
+                    .count();
+            let size = segment as u16;
+            let cmsgs = [ControlMessage::UdpGsoSegments(&size)];
+            let addrs = vec![Some(SockaddrStorage::from(dst)); end - start];
+            let slices: Vec<_> = runs[start..end]
+                .iter()
+                .map(|(_, slices)| slices.as_slice())
+                .collect();
+            let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(
+                slices.len(),
+                Some(nix::cmsg_space!(u16)),
+            );
+            let mut done = 0x00;
+            while done < slices.len() {
+                let count = sendmmsg(
+                    self.inner.as_raw_fd(),
+                    &mut headers,
+                    &slices[done..],
+                    &addrs[done..],
+                    cmsgs,
+                    MsgFlags::empty(),
+                )?
+                .count();
+                *sent += slices[done..done + count]
+                    .iter()
+                    .map(|slices| slices.len())
+                    .sum::<usize>();
+                done += count;
+            }
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+fn socket_addr(addr: &SockaddrStorage) -> Option<SocketAddr> {
+    if let Some(addr) = addr.as_sockaddr_in() {
+        Some(SocketAddrV4::from(*addr).into())
+    } else {
+        addr.as_sockaddr_in6()
+            .map(|addr| SocketAddrV6::from(*addr).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_round_trip() {
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dst = rx.inner().local_addr().unwrap();
+        // runs of equal sizes get coalesced, the odd ones out start runs of their own
+        let sizes = [
+            0x0500, 0x0500, 0x0500, 0x0100, 0x0500, 0x0500, 0x40, 0x40, 0x0500,
+        ];
+        let datagrams: Vec<Vec<u8>> = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| vec![i as u8; size])
+            .collect();
+        let slices: Vec<&[u8]> = datagrams.iter().map(Vec::as_slice).collect();
+        tx.send_batch(dst, &slices).unwrap();
+
+        let mut buffers = vec![vec![0x00; MAX_PAYLOAD]; 0x10];
+        let mut received = Vec::new();
+        while received.len() < datagrams.len() {
+            for (datagram, src) in rx.recv_batch(&mut buffers).unwrap() {
+                assert_eq!(src, tx.inner().local_addr().unwrap());
+                received.push(datagram.to_vec());
+            }
+        }
+        assert_eq!(received, datagrams);
+    }
+}
@@ -0,0 +1,247 @@
+// wasm-bindgen wrappers for browsers and edge workers, where the caller owns the WebSocket or WebTransport
+// wasm32-unknown-unknown has no clock or randomness to reach for, so both come in as JS functions:
+// clock() returns milliseconds since the UNIX epoch, random(buffer) fills a Uint8Array in place
+
+use js_sys::{Function, Reflect, Uint8Array};
+use rand_core::{CryptoRng, RngCore};
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+use wasm_bindgen::prelude::*;
+use x25519::{PublicKey, StaticSecret};
+
+use crate::{
+    device::{Device, LoadMonitor},
+    driver::{Action, Driver},
+    error::Error,
+    ratelimiter::RateLimiter,
+    tunnel,
+};
+
+// the one peer on the other end of the caller's connection, which has no address worth knowing
+const PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0x00));
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Idle,           // nothing to do
+    WriteToNetwork, // send data to the peer
+    WriteToTunnel,  // data is a decrypted IP packet for the local stack
+}
+
+#[wasm_bindgen]
+pub struct Output {
+    op: Op,
+    data: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl Output {
+    #[wasm_bindgen(getter)]
+    pub fn op(&self) -> Op {
+        self.op
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+}
+
+#[wasm_bindgen]
+pub struct Keypair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl Keypair {
+    #[wasm_bindgen(getter)]
+    pub fn private(&self) -> Vec<u8> {
+        self.private.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn public(&self) -> Vec<u8> {
+        self.public.clone()
+    }
+}
+
+#[wasm_bindgen(js_name = generateKeypair)]
+pub fn generate_keypair(random: &Function) -> Keypair {
+    let secret = StaticSecret::random_from_rng(Random(random));
+    Keypair {
+        private: secret.to_bytes().to_vec(),
+        public: PublicKey::from(&secret).to_bytes().to_vec(),
+    }
+}
+
+#[wasm_bindgen(js_name = publicKey)]
+pub fn public_key(private_key: &[u8]) -> Result<Vec<u8>, JsValue> {
+    let secret = StaticSecret::from(key(private_key)?);
+    Ok(PublicKey::from(&secret).to_bytes().to_vec())
+}
+
+// a tunnel to one peer over whatever the caller carries datagrams with, handshakes and timers included
+#[wasm_bindgen]
+pub struct Tunnel {
+    driver: Driver,
+    clock: Function,
+    random: Function,
+    buffer: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl Tunnel {
+    // initiate is whether this side starts handshakes, keepalive is in seconds and 0 turns it off
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        private_key: &[u8],
+        peer_public: &[u8],
+        preshared_key: Option<Vec<u8>>,
+        initiate: bool,
+        keepalive: u16,
+        clock: Function,
+        random: Function,
+    ) -> Result<Tunnel, JsValue> {
+        let device = Arc::new(Device::new(
+            StaticSecret::from(key(private_key)?),
+            LoadMonitor::default(),
+            RateLimiter::default(),
+        ));
+        let preshared_key = match preshared_key {
+            Some(preshared_key) => key(&preshared_key)?,
+            None => [0x00; 0x20],
+        };
+        let tunnel = Arc::new(tunnel::Tunnel::new(
+            device,
+            PublicKey::from(key(peer_public)?),
+            preshared_key,
+        ));
+        let keepalive = (keepalive != 0x00).then(|| Duration::from_secs(keepalive as u64));
+        Ok(Self {
+            driver: Driver::new(tunnel, initiate.then_some(PEER), keepalive),
+            clock,
+            random,
+            buffer: vec![0x00; 0x10000],
+        })
+    }
+
+    // seals an IP packet for the peer, throws until tick has set up a session
+    pub fn encapsulate(&mut self, packet: &[u8]) -> Result<Vec<u8>, JsValue> {
+        let now = self.now()?;
+        let Self {
+            driver,
+            random,
+            buffer,
+            ..
+        } = self;
+        match driver
+            .encapsulate(packet, now, &mut Random(random), buffer)
+            .map_err(raise)?
+        {
+            Action::Network(length) => Ok(buffer[0x00..length].to_vec()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    // handles one datagram from the peer
+    pub fn decapsulate(&mut self, datagram: &[u8]) -> Result<Output, JsValue> {
+        let now = self.now()?;
+        let Self {
+            driver,
+            random,
+            buffer,
+            ..
+        } = self;
+        let mut datagram = datagram.to_vec();
+        let (op, length) = match driver
+            .decapsulate(&mut datagram, PEER, now, &mut Random(random), buffer)
+            .map_err(raise)?
+        {
+            Action::Idle => (Op::Idle, 0x00),
+            Action::Network(length) => (Op::WriteToNetwork, length),
+            Action::Inner(length) => (Op::WriteToTunnel, length),
+        };
+        Ok(Output {
+            op,
+            data: buffer[0x00..length].to_vec(),
+        })
+    }
+
+    // a datagram for the peer or undefined, call it every 100ms or so and again until it is undefined
+    pub fn tick(&mut self) -> Result<Option<Vec<u8>>, JsValue> {
+        let now = self.now()?;
+        let Self {
+            driver,
+            random,
+            buffer,
+            ..
+        } = self;
+        match driver
+            .tick(now, &mut Random(random), buffer)
+            .map_err(raise)?
+        {
+            Action::Network(length) => Ok(Some(buffer[0x00..length].to_vec())),
+            _ => Ok(None),
+        }
+    }
+
+    fn now(&self) -> Result<Duration, JsValue> {
+        let millis = self.clock.call0(&JsValue::NULL)?.as_f64();
+        millis
+            .and_then(|millis| Duration::try_from_secs_f64(millis / 1000.0).ok())
+            .ok_or_else(|| {
+                js_sys::Error::new("clock must return milliseconds since the UNIX epoch").into()
+            })
+    }
+}
+
+// randomness from the caller, e.g. b => crypto.getRandomValues(b)
+struct Random<'a>(&'a Function);
+
+impl RngCore for Random<'_> {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    // there is no carrying on without randomness, so a failing random throws straight through
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let array = Uint8Array::new_with_length(dest.len() as u32);
+        self.0
+            .call1(&JsValue::NULL, &array)
+            .expect_throw("random must fill the Uint8Array it is given");
+        array.copy_to(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for Random<'_> {}
+
+// an Error with the reason and category alongside the message
+fn raise(error: Error) -> JsValue {
+    let exception = js_sys::Error::new(&error.to_string());
+    let _ = Reflect::set(&exception, &"reason".into(), &error.reason().into());
+    let _ = Reflect::set(
+        &exception,
+        &"category".into(),
+        &error.category().to_string().into(),
+    );
+    exception.into()
+}
+
+fn key(bytes: &[u8]) -> Result<[u8; 0x20], JsValue> {
+    bytes
+        .try_into()
+        .map_err(|_| js_sys::Error::new("keys are 32 bytes").into())
+}
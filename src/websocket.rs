@@ -0,0 +1,467 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand_core::CryptoRngCore;
+use sha1::{Digest, Sha1};
+use std::{
+    io::{self, Read},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::transport::{Buffered, Transport, MAX_MESSAGE};
+
+// RFC 6455 section 1.3, appended to the key before hashing it into the accept
+const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// opcodes
+const CONTINUATION: u8 = 0x00;
+const BINARY: u8 = 0x02;
+const CLOSE: u8 = 0x08;
+const PING: u8 = 0x09;
+const PONG: u8 = 0x0a;
+
+// close status for a frame that breaks the protocol
+const PROTOCOL_ERROR: u16 = 0x03ea;
+
+// a handshake request or response bigger than this is not one
+const MAX_HEADERS: usize = 0x2000;
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    masked: bool,
+    payload: Vec<u8>,
+}
+
+// every message is one binary frame, for networks that only let HTTP(S) ports through
+// the client masks what it sends as RFC 6455 requires, the server does not
+pub struct WsTransport {
+    stream: Buffered,
+    mask: Option<Mutex<Box<dyn CryptoRngCore + Send>>>, // client side, every frame gets a fresh key from it
+    fragments: Mutex<Vec<u8>>, // a message still missing frames, kept across timeouts
+}
+
+impl WsTransport {
+    // upgrades a fresh connection to addr, host and path go in the request line and Host header
+    // rng makes the handshake key and then the masking keys, so it has to be one an intermediary cannot predict
+    pub fn connect(
+        addr: SocketAddr,
+        host: &str,
+        path: &str,
+        mut rng: impl CryptoRngCore + Send + 'static,
+    ) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        let mut key = [0x00; 0x10];
+        rng.fill_bytes(&mut key);
+        let key = STANDARD.encode(key);
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        );
+        io::Write::write_all(&mut &stream, request.as_bytes())?;
+        let (head, rest) = read_head(&stream)?;
+        let accepted = head
+            .lines()
+            .next()
+            .is_some_and(|line| line.split_whitespace().nth(0x01) == Some("101"))
+            && header(&head, "sec-websocket-accept") == Some(accept(&key).as_str());
+        if !accepted {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "server did not upgrade to a WebSocket",
+            ))?
+        }
+        Ok(Self {
+            stream: Buffered::new(stream, rest)?,
+            mask: Some(Mutex::new(Box::new(rng))),
+            fragments: Mutex::default(),
+        })
+    }
+
+    // waits for the peer to connect and upgrades it, whatever the path
+    pub fn accept(listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        let (head, rest) = read_head(&stream)?;
+        let upgrade =
+            header(&head, "upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+        let response = match header(&head, "sec-websocket-key") {
+            Some(key) if upgrade => format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept(key)
+            ),
+            _ => {
+                let _ = io::Write::write_all(
+                    &mut &stream,
+                    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+                Err(io::Error::new(io::ErrorKind::InvalidData, "not a WebSocket upgrade"))?
+            }
+        };
+        io::Write::write_all(&mut &stream, response.as_bytes())?;
+        Ok(Self {
+            stream: Buffered::new(stream, rest)?,
+            mask: None,
+            fragments: Mutex::default(),
+        })
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.stream.peer()
+    }
+
+    fn send(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        // RFC 6455 section 5.3, keys an intermediary cannot predict
+        let key = self.mask.as_ref().map(|rng| {
+            let mut key = [0x00; 0x04];
+            rng.lock().unwrap().fill_bytes(&mut key);
+            key
+        });
+        let masked = if key.is_some() { 0x80 } else { 0x00 };
+        let mut frame = Vec::with_capacity(0x0e + payload.len());
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            length @ 0x00..=0x7d => frame.push(masked | length as u8),
+            length @ 0x7e..=0xffff => {
+                frame.push(masked | 0x7e);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(masked | 0x7f);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        let start = frame.len() + key.map_or(0x00, |key| key.len());
+        frame.extend(key.into_iter().flatten());
+        frame.extend_from_slice(payload);
+        if let Some(key) = key {
+            for (i, b) in frame[start..].iter_mut().enumerate() {
+                *b ^= key[i % 0x04];
+            }
+        }
+        self.stream.write(&frame)
+    }
+
+    fn recv_frame(&self, timeout: Option<Duration>) -> io::Result<Frame> {
+        let frame = self.read_frame(timeout)?;
+        // RFC 6455 section 5.1, clients mask every frame and servers none, anything else fails the connection
+        if frame.masked != self.mask.is_none() {
+            let _ = self.send(CLOSE, &PROTOCOL_ERROR.to_be_bytes());
+            self.stream.shutdown();
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "WebSocket frame masked the wrong way",
+            ))?
+        }
+        Ok(frame)
+    }
+
+    fn read_frame(&self, timeout: Option<Duration>) -> io::Result<Frame> {
+        self.stream.read(timeout, |read| {
+            let Some(&[first, second]) = read.get(0x00..0x02) else {
+                return Ok(None);
+            };
+            let mut at = 0x02;
+            let length = match second & 0x7f {
+                0x7e => {
+                    let Some(length) = read.get(at..at + 0x02) else {
+                        return Ok(None);
+                    };
+                    at += 0x02;
+                    u16::from_be_bytes(length.try_into().unwrap()) as u64
+                }
+                0x7f => {
+                    let Some(length) = read.get(at..at + 0x08) else {
+                        return Ok(None);
+                    };
+                    at += 0x08;
+                    u64::from_be_bytes(length.try_into().unwrap())
+                }
+                length => length as u64,
+            };
+            if length > MAX_MESSAGE as u64 {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "WebSocket frame too big",
+                ))?
+            }
+            let key = match second & 0x80 {
+                0x00 => None,
+                _ => {
+                    let Some(key) = read.get(at..at + 0x04) else {
+                        return Ok(None);
+                    };
+                    at += 0x04;
+                    Some(<[u8; 0x04]>::try_from(key).unwrap())
+                }
+            };
+            let Some(payload) = read.get(at..at + length as usize) else {
+                return Ok(None);
+            };
+            let mut payload = payload.to_vec();
+            if let Some(key) = key {
+                for (i, b) in payload.iter_mut().enumerate() {
+                    *b ^= key[i % 0x04];
+                }
+            }
+            let frame = Frame {
+                fin: first & 0x80 != 0x00,
+                opcode: first & 0x0f,
+                masked: key.is_some(),
+                payload,
+            };
+            Ok(Some((frame, at + length as usize)))
+        })
+    }
+}
+
+impl Transport for WsTransport {
+    fn send_to(&self, msg: &[u8], _: SocketAddr) -> io::Result<()> {
+        self.send(BINARY, msg)
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, SocketAddr)> {
+        let mut msg = self.fragments.lock().unwrap();
+        loop {
+            let frame = self.recv_frame(timeout)?;
+            match frame.opcode {
+                BINARY | CONTINUATION => {
+                    msg.extend_from_slice(&frame.payload);
+                    if msg.len() > MAX_MESSAGE {
+                        msg.clear();
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "WebSocket message too big",
+                        ))?
+                    }
+                    if frame.fin {
+                        break;
+                    }
+                }
+                PING => self.send(PONG, &frame.payload)?,
+                CLOSE => {
+                    let _ = self.send(CLOSE, &frame.payload);
+                    Err(io::Error::from(io::ErrorKind::UnexpectedEof))?
+                }
+                // pongs, and text frames nobody should be sending
+                _ => {}
+            }
+        }
+        // too big for buf is cut short, like a UDP socket would
+        let copied = msg.len().min(buf.len());
+        buf[0x00..copied].copy_from_slice(&msg[0x00..copied]);
+        msg.clear();
+        Ok((copied, self.stream.peer()))
+    }
+}
+
+fn accept(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(GUID);
+    STANDARD.encode(hasher.finalize())
+}
+
+// reads up to the blank line that ends the headers, and whatever came in after it
+fn read_head(mut stream: &TcpStream) -> io::Result<(String, Vec<u8>)> {
+    let mut read = Vec::new();
+    let mut chunk = [0x00; 0x0400];
+    loop {
+        if let Some(end) = read.windows(0x04).position(|window| window == b"\r\n\r\n") {
+            let rest = read.split_off(end + 0x04);
+            let head =
+                String::from_utf8(read).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+            return Ok((head, rest));
+        }
+        if read.len() > MAX_HEADERS {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "headers too long",
+            ))?
+        }
+        match stream.read(&mut chunk)? {
+            0x00 => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+            length => read.extend_from_slice(&chunk[0x00..length]),
+        }
+    }
+}
+
+// the value of the first header called name, names compared without case
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(0x01).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::{CryptoRng, RngCore};
+    use std::{io::Write, thread};
+
+    // predictable, which is all a test needs
+    struct Counter(u64);
+
+    impl RngCore for Counter {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_mul(0x5851f42d4c957f2d).wrapping_add(0x01);
+            self.0 >> 0x10
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = self.next_u64() as u8;
+            }
+        }
+
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> core::result::Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for Counter {}
+
+    // a client that upgrades by hand, so the test decides what goes over the wire
+    fn raw_client(addr: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let (head, rest) = read_head(&stream).unwrap();
+        assert_eq!(
+            header(&head, "sec-websocket-accept"),
+            Some(accept(key).as_str())
+        );
+        assert!(rest.is_empty());
+        stream
+    }
+
+    // a short frame, masked with key if there is one
+    fn frame(first: u8, payload: &[u8], key: Option<[u8; 0x04]>) -> Vec<u8> {
+        let mut frame = vec![first, payload.len() as u8];
+        match key {
+            Some(key) => {
+                frame[0x01] |= 0x80;
+                frame.extend_from_slice(&key);
+                frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 0x04]));
+            }
+            None => frame.extend_from_slice(payload),
+        }
+        frame
+    }
+
+    #[test]
+    fn round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let server = WsTransport::accept(&listener).unwrap();
+            let mut buf = [0x00; 0x1000];
+            let (length, _) = server.recv_from(&mut buf, None).unwrap();
+            server.send_to(&buf[0x00..length], addr).unwrap();
+        });
+        let client = WsTransport::connect(addr, "localhost", "/tunnel", Counter(0x01)).unwrap();
+        let msg = vec![0xa5; 0x200];
+        client.send_to(&msg, addr).unwrap();
+        let mut buf = [0x00; 0x1000];
+        let (length, _) = client.recv_from(&mut buf, None).unwrap();
+        assert_eq!(&buf[0x00..length], msg);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn fresh_key_per_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let client = WsTransport::connect(addr, "localhost", "/", Counter(0x01)).unwrap();
+            for _ in 0x00..0x08 {
+                client.send_to(&[0x00; 0x10], addr).unwrap();
+            }
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let (head, mut rest) = read_head(&stream).unwrap();
+        let key = header(&head, "sec-websocket-key").unwrap();
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept(key)
+        );
+        stream.write_all(response.as_bytes()).unwrap();
+        client.join().unwrap();
+        stream.read_to_end(&mut rest).unwrap();
+        // 0x02 header bytes, the key, and zeros masked into copies of the key
+        let keys: Vec<&[u8]> = rest.chunks(0x16).map(|frame| &frame[0x02..0x06]).collect();
+        assert_eq!(keys.len(), 0x08);
+        for (i, frame) in rest.chunks(0x16).enumerate() {
+            assert_eq!(frame[0x01], 0x80 | 0x10);
+            assert!(frame[0x06..].chunks(0x04).all(|masked| masked == keys[i]));
+        }
+        for (i, key) in keys.iter().enumerate() {
+            assert!(!keys[i + 0x01..].contains(key));
+        }
+    }
+
+    #[test]
+    fn unmasked_client_frame_fails_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let server = WsTransport::accept(&listener).unwrap();
+            let mut buf = [0x00; 0x10];
+            server.recv_from(&mut buf, None).map(|_| ()).unwrap_err()
+        });
+        let mut client = raw_client(addr);
+        client
+            .write_all(&frame(0x80 | BINARY, b"ping", None))
+            .unwrap();
+        assert_eq!(server.join().unwrap().kind(), io::ErrorKind::InvalidData);
+        // a close with 1002 and then nothing more
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, [0x80 | CLOSE, 0x02, 0x03, 0xea]);
+    }
+
+    #[test]
+    fn fragments_survive_a_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (go, next) = std::sync::mpsc::channel();
+        let client = thread::spawn(move || {
+            let mut client = raw_client(addr);
+            let key = Some([0x12, 0x34, 0x56, 0x78]);
+            client.write_all(&frame(BINARY, b"pi", key)).unwrap();
+            next.recv().unwrap();
+            client
+                .write_all(&frame(0x80 | CONTINUATION, b"ng", key))
+                .unwrap();
+            client
+        });
+        let server = WsTransport::accept(&listener).unwrap();
+        let mut buf = [0x00; 0x10];
+        // the first fragment is in, the rest is not
+        let err = server
+            .recv_from(&mut buf, Some(Duration::from_millis(0x32)))
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+        go.send(()).unwrap();
+        let (length, _) = server.recv_from(&mut buf, None).unwrap();
+        assert_eq!(&buf[0x00..length], b"ping");
+        client.join().unwrap();
+    }
+}
@@ -0,0 +1,41 @@
+// builds examples/ffi/handshake.c against the static library and runs it
+// with the python feature the library needs libpython to link, so that combination is left out
+#![cfg(all(feature = "ffi", not(feature = "python"), target_os = "linux"))]
+
+use std::{env, fs, path::Path, process::Command};
+
+#[test]
+fn header_is_current() {
+    let generated = Path::new(env!("OUT_DIR")).join("shyvana.h");
+    let checked_in = Path::new(env!("CARGO_MANIFEST_DIR")).join("include/shyvana.h");
+    assert!(
+        fs::read(&generated).unwrap() == fs::read(&checked_in).unwrap(),
+        "src/ffi.rs changed, copy {} over include/shyvana.h",
+        generated.display()
+    );
+}
+
+#[test]
+fn handshake_example() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // the library built for this test run sits next to the test binary, target/<profile> only gets it on cargo build
+    let exe = env::current_exe().unwrap();
+    let deps = exe.parent().unwrap();
+    let binary = Path::new(env!("CARGO_TARGET_TMPDIR")).join("handshake");
+    let status = Command::new(env::var("CC").unwrap_or("cc".to_owned()))
+        .arg(root.join("examples/ffi/handshake.c"))
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg(deps.join("libshyvana.a"))
+        .args(["-lpthread", "-ldl", "-lm", "-o"])
+        .arg(&binary)
+        .status()
+        .expect("a C compiler should be on the path");
+    assert!(status.success());
+    let output = Command::new(&binary).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
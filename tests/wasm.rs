@@ -0,0 +1,134 @@
+// run with: wasm-pack test --node --features wasm
+#![cfg(all(feature = "wasm", target_arch = "wasm32"))]
+
+use js_sys::{Function, Reflect};
+use shyvana::wasm::{generate_keypair, public_key, Op, Tunnel};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+// a clock the test moves by hand, in milliseconds
+fn clock(name: &str) -> Function {
+    Function::new_no_args(&format!("return globalThis.{name}"))
+}
+
+fn set(name: &str, millis: f64) {
+    Reflect::set(&js_sys::global(), &name.into(), &millis.into()).unwrap();
+}
+
+fn random() -> Function {
+    Function::new_with_args("buffer", "globalThis.crypto.getRandomValues(buffer)")
+}
+
+fn reason(error: JsValue) -> String {
+    Reflect::get(&error, &"reason".into())
+        .unwrap()
+        .as_string()
+        .unwrap()
+}
+
+fn pair(name: &str) -> (Tunnel, Tunnel) {
+    let alice = generate_keypair(&random());
+    let bob = generate_keypair(&random());
+    let initiator = Tunnel::new(
+        &alice.private(),
+        &bob.public(),
+        None,
+        true,
+        25,
+        clock(name),
+        random(),
+    )
+    .unwrap();
+    let responder = Tunnel::new(
+        &bob.private(),
+        &alice.public(),
+        None,
+        false,
+        0x00,
+        clock(name),
+        random(),
+    )
+    .unwrap();
+    (initiator, responder)
+}
+
+// runs one handshake and returns the initiation
+fn handshake(alice: &mut Tunnel, bob: &mut Tunnel) -> Vec<u8> {
+    let init = alice.tick().unwrap().unwrap();
+    assert_eq!(init.len(), 148);
+    let resp = bob.decapsulate(&init).unwrap();
+    assert_eq!(resp.op(), Op::WriteToNetwork);
+    assert_eq!(resp.data().len(), 92);
+    assert_eq!(alice.decapsulate(&resp.data()).unwrap().op(), Op::Idle);
+    init
+}
+
+#[wasm_bindgen_test]
+fn keys() {
+    let keypair = generate_keypair(&random());
+    assert_eq!(keypair.private().len(), 0x20);
+    assert_eq!(public_key(&keypair.private()).unwrap(), keypair.public());
+    assert!(public_key(&[0x00; 0x10]).is_err());
+}
+
+#[wasm_bindgen_test]
+fn tunnel() {
+    set("now_tunnel", 1.7e12);
+    let (mut alice, mut bob) = pair("now_tunnel");
+    // nothing to seal with until the handshake is done, and the responder never starts one
+    assert_eq!(
+        reason(alice.encapsulate(&[0x45; 0x20]).unwrap_err()),
+        "session_expired"
+    );
+    assert!(bob.tick().unwrap().is_none());
+    handshake(&mut alice, &mut bob);
+    assert!(alice.tick().unwrap().is_none());
+
+    let packet = [0x45; 0x40];
+    let datagram = alice.encapsulate(&packet).unwrap();
+    let inner = bob.decapsulate(&datagram).unwrap();
+    assert_eq!(inner.op(), Op::WriteToTunnel);
+    assert_eq!(&inner.data()[0x00..packet.len()], &packet);
+    let datagram = bob.encapsulate(&packet).unwrap();
+    assert_eq!(
+        alice.decapsulate(&datagram).unwrap().op(),
+        Op::WriteToTunnel
+    );
+
+    let mut tampered = datagram.clone();
+    *tampered.last_mut().unwrap() ^= 0x01;
+    assert!(alice.decapsulate(&tampered).is_err());
+}
+
+#[wasm_bindgen_test]
+fn timers() {
+    set("now_timers", 1.7e12);
+    let (mut alice, mut bob) = pair("now_timers");
+    handshake(&mut alice, &mut bob);
+
+    // a keepalive once 25s go by without sending
+    set("now_timers", 1.7e12 + 25_000.0);
+    let keepalive = alice.tick().unwrap().unwrap();
+    assert_eq!(bob.decapsulate(&keepalive).unwrap().op(), Op::Idle);
+    assert!(alice.tick().unwrap().is_none());
+
+    // and a fresh handshake once the session is two minutes old
+    set("now_timers", 1.7e12 + 120_000.0);
+    handshake(&mut alice, &mut bob);
+}
+
+#[wasm_bindgen_test]
+fn clock_errors() {
+    let keypair = generate_keypair(&random());
+    let mut tunnel = Tunnel::new(
+        &keypair.private(),
+        &keypair.public(),
+        None,
+        true,
+        0x00,
+        Function::new_no_args("return 'soon'"),
+        random(),
+    )
+    .unwrap();
+    assert!(tunnel.tick().is_err());
+}